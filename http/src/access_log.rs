@@ -0,0 +1,344 @@
+use crate::{date::UtcDateTime, HTTPRequestHeader, RawHTTPResponse};
+use serde_json::json;
+use std::{
+    ffi::OsString,
+    fmt::Write as _,
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    net::IpAddr,
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::{Duration, SystemTime},
+};
+use tracing::error;
+
+/// How each line of an [`AccessLog`] is laid out
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AccessLogFormat {
+    /// The Common Log Format, `host ident user [time] "request" status bytes`, followed by the request ID and the duration in milliseconds
+    Common,
+    /// The Combined Log Format, which is the Common one plus the quoted `Referer` and `User-Agent`, followed by the request ID and the duration in milliseconds
+    Combined,
+    /// One JSON object per line
+    Json,
+}
+
+/// One request served, as written to an [`AccessLog`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AccessLogEntry {
+    /// The client's address, looking through trusted proxies
+    pub remote: Option<IpAddr>,
+    /// When the request started to arrive
+    pub time: SystemTime,
+    /// Empty for requests that couldn't be parsed, as are the path and HTTP version
+    pub method: String,
+    pub path: String,
+    pub http_version: String,
+    pub status: u16,
+    /// The size of the response body
+    pub size: usize,
+    /// How long it took from the request starting to arrive to the response being sent
+    pub duration: Duration,
+    pub referer: Option<String>,
+    pub user_agent: Option<String>,
+    pub request_id: Option<String>,
+}
+
+impl AccessLogEntry {
+    /// An entry for the request, to be given its status, size and duration once it has been answered.
+    /// [`HTTPRequestHeader::client`] has to be filled in already, see [`crate::Router::client`].
+    pub fn new(header: &HTTPRequestHeader, time: SystemTime) -> Self {
+        Self {
+            remote: header.client.ip,
+            time,
+            method: header.method.clone(),
+            path: header.path.clone(),
+            http_version: header.http_version.clone(),
+            status: 0,
+            size: 0,
+            duration: Duration::ZERO,
+            referer: header.referer.clone(),
+            user_agent: header.user_agent.clone(),
+            request_id: header.request_id.clone(),
+        }
+    }
+
+    /// Records the status and body size of the response the request was answered with
+    pub fn set_response(&mut self, response: &RawHTTPResponse) {
+        self.status = response.status_code as u16;
+        self.size = if response.allows_body() {
+            response.body.len()
+        } else {
+            0
+        };
+    }
+
+    /// Formats the entry as one line, without the line break
+    pub fn format(&self, format: AccessLogFormat) -> String {
+        let date = UtcDateTime::from(self.time);
+        let millis = self.duration.as_secs_f64() * 1000.0;
+        if format == AccessLogFormat::Json {
+            let request_line = !self.method.is_empty();
+            return json!({
+                "time": format!(
+                    "{}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+                    date.year, date.month, date.day, date.hour, date.minute, date.second, date.millisecond
+                ),
+                "remote": self.remote.map(|ip| ip.to_string()),
+                "method": request_line.then_some(&self.method),
+                "path": request_line.then_some(&self.path),
+                "protocol": request_line.then(|| format!("HTTP/{}", self.http_version)),
+                "status": self.status,
+                "size": self.size,
+                "duration_ms": (millis * 1000.0).round() / 1000.0,
+                "referer": self.referer,
+                "user_agent": self.user_agent,
+                "request_id": self.request_id,
+            })
+            .to_string();
+        }
+
+        let mut line = format!(
+            "{} - - [{:02}/{}/{}:{:02}:{:02}:{:02} +0000] \"{}\" {} {}",
+            self.remote
+                .map_or_else(|| "-".to_owned(), |ip| ip.to_string()),
+            date.day,
+            date.month_name(),
+            date.year,
+            date.hour,
+            date.minute,
+            date.second,
+            if self.method.is_empty() {
+                "-".to_owned()
+            } else {
+                escape(&format!(
+                    "{} {} HTTP/{}",
+                    self.method, self.path, self.http_version
+                ))
+            },
+            self.status,
+            match self.size {
+                0 => "-".to_owned(),
+                size => size.to_string(),
+            }
+        );
+        let quoted =
+            |value: &Option<String>| value.as_deref().map_or_else(|| "-".to_owned(), escape);
+        if format == AccessLogFormat::Combined {
+            let _ = write!(
+                line,
+                " \"{}\" \"{}\"",
+                quoted(&self.referer),
+                quoted(&self.user_agent)
+            );
+        }
+        let _ = write!(line, " \"{}\" {millis:.3}", quoted(&self.request_id));
+        line
+    }
+}
+
+/// Escapes a value for a quoted field of the Common and Combined formats, the way Apache does, so a client can't break a line up or forge another
+fn escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            c if c.is_control() => {
+                for byte in c.to_string().bytes() {
+                    let _ = write!(escaped, "\\x{byte:02x}");
+                }
+            }
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// A log file that is rotated once it grows past a size, `access.log` becoming `access.log.1`, `access.log.1` becoming `access.log.2` and so on
+#[derive(Debug)]
+struct RotatingFile {
+    path: PathBuf,
+    file: File,
+    size: u64,
+    max_size: u64,
+    /// How many rotated files are kept, the oldest being removed
+    keep: usize,
+}
+
+impl RotatingFile {
+    fn open(path: &Path, max_size: u64, keep: usize) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Self {
+            path: path.to_owned(),
+            size: file.metadata()?.len(),
+            file,
+            max_size,
+            keep,
+        })
+    }
+
+    fn rotated(&self, n: usize) -> PathBuf {
+        let mut path = OsString::from(&self.path);
+        path.push(format!(".{n}"));
+        path.into()
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        let ignore_missing = |result: io::Result<()>| match result {
+            Err(err) if err.kind() != io::ErrorKind::NotFound => Err(err),
+            _ => Ok(()),
+        };
+        if self.keep == 0 {
+            ignore_missing(fs::remove_file(&self.path))?;
+        } else {
+            for n in (1..self.keep).rev() {
+                ignore_missing(fs::rename(self.rotated(n), self.rotated(n + 1)))?;
+            }
+            fs::rename(&self.path, self.rotated(1))?;
+        }
+        *self = Self::open(&self.path, self.max_size, self.keep)?;
+        Ok(())
+    }
+
+    fn write_line(&mut self, line: &[u8]) -> io::Result<()> {
+        if self.size > 0 && self.size + line.len() as u64 > self.max_size {
+            self.rotate()?;
+        }
+        self.file.write_all(line)?;
+        self.size += line.len() as u64;
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+enum Sink {
+    Stdout,
+    File(RotatingFile),
+}
+
+/// Writes a line for every request served, attached to a router with [`crate::Router::with_access_log`] for the server to write to. For example:
+/// ```rust
+/// # use http::{AccessLog, AccessLogFormat, Router};
+/// let router = Router::new().with_access_log(AccessLog::stdout(AccessLogFormat::Combined));
+/// ```
+/// Errors writing the log are reported on stderr, they never fail the request.
+#[derive(Debug)]
+pub struct AccessLog {
+    format: AccessLogFormat,
+    sink: Mutex<Sink>,
+}
+
+impl AccessLog {
+    /// Writes the log to stdout
+    pub fn stdout(format: AccessLogFormat) -> Self {
+        Self {
+            format,
+            sink: Mutex::new(Sink::Stdout),
+        }
+    }
+
+    /// Appends the log to the file at `path`, starting a new one whenever it would grow past `max_size` bytes and keeping the last `keep` files rotated out of the way
+    pub fn file(
+        path: &Path,
+        format: AccessLogFormat,
+        max_size: u64,
+        keep: usize,
+    ) -> io::Result<Self> {
+        Ok(Self {
+            format,
+            sink: Mutex::new(Sink::File(RotatingFile::open(path, max_size, keep)?)),
+        })
+    }
+
+    pub fn log(&self, entry: &AccessLogEntry) {
+        let mut line = entry.format(self.format);
+        line.push('\n');
+        let mut sink = self.sink.lock().unwrap_or_else(|err| err.into_inner());
+        let written = match &mut *sink {
+            Sink::Stdout => io::stdout().lock().write_all(line.as_bytes()),
+            Sink::File(file) => file.write_line(line.as_bytes()),
+        };
+        if let Err(err) = written {
+            error!("Could not write to the access log => {err}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::UNIX_EPOCH;
+
+    fn entry() -> AccessLogEntry {
+        AccessLogEntry {
+            remote: Some("203.0.113.7".parse().unwrap()),
+            time: UNIX_EPOCH + Duration::from_millis(971_186_136_250),
+            method: "GET".to_owned(),
+            path: "/apache_pb.gif".to_owned(),
+            http_version: "1.1".to_owned(),
+            status: 200,
+            size: 2326,
+            duration: Duration::from_micros(1500),
+            referer: Some("http://www.example.com/start.html".to_owned()),
+            user_agent: Some("Mozilla/4.08 \"quoted\"".to_owned()),
+            request_id: None,
+        }
+    }
+
+    #[test]
+    fn formats_entries() {
+        assert_eq!(
+            entry().format(AccessLogFormat::Common),
+            "203.0.113.7 - - [10/Oct/2000:13:55:36 +0000] \"GET /apache_pb.gif HTTP/1.1\" 200 2326 \"-\" 1.500"
+        );
+        assert_eq!(
+            entry().format(AccessLogFormat::Combined),
+            "203.0.113.7 - - [10/Oct/2000:13:55:36 +0000] \"GET /apache_pb.gif HTTP/1.1\" 200 2326 \
+            \"http://www.example.com/start.html\" \"Mozilla/4.08 \\\"quoted\\\"\" \"-\" 1.500"
+        );
+        let unparsed = AccessLogEntry {
+            method: String::new(),
+            size: 0,
+            status: 400,
+            user_agent: Some("evil\r\n".to_owned()),
+            ..entry()
+        };
+        assert_eq!(
+            unparsed.format(AccessLogFormat::Combined),
+            "203.0.113.7 - - [10/Oct/2000:13:55:36 +0000] \"-\" 400 - \
+            \"http://www.example.com/start.html\" \"evil\\x0d\\x0a\" \"-\" 1.500"
+        );
+
+        let json: serde_json::Value =
+            serde_json::from_str(&unparsed.format(AccessLogFormat::Json)).unwrap();
+        assert_eq!(json["time"], "2000-10-10T13:55:36.250Z");
+        assert_eq!(json["method"], serde_json::Value::Null);
+        assert_eq!(json["status"], 400);
+        assert_eq!(json["duration_ms"], 1.5);
+        assert_eq!(json["user_agent"], "evil\r\n");
+    }
+
+    #[test]
+    fn rotates_files() {
+        let dir = std::env::temp_dir().join(format!("access-log-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("access.log");
+        let log = AccessLog::file(&path, AccessLogFormat::Common, 200, 2).unwrap();
+        let line_len = entry().format(AccessLogFormat::Common).len() as u64 + 1;
+
+        for _ in 0..4 {
+            log.log(&entry());
+        }
+        // each file takes two lines
+        let len = |path: PathBuf| fs::metadata(path).map(|metadata| metadata.len()).ok();
+        assert_eq!(len(path.clone()), Some(2 * line_len));
+        assert_eq!(len(dir.join("access.log.1")), Some(2 * line_len));
+        for _ in 0..4 {
+            log.log(&entry());
+        }
+        assert_eq!(len(dir.join("access.log.2")), Some(2 * line_len));
+        assert_eq!(len(dir.join("access.log.3")), None);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
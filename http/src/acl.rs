@@ -0,0 +1,212 @@
+use crate::Cidr;
+use std::{
+    fmt, fs, io,
+    net::IpAddr,
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+};
+
+/// What an [`IpAcl`] rule does with the addresses it matches
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AclAction {
+    Allow,
+    Deny,
+}
+
+/// Which IP addresses may connect, or call a route with [`crate::RouteOptions::acl`] or [`crate::Router::with_acl`]. For example:
+/// ```rust
+/// # use http::IpAcl;
+/// // admin endpoints only from the internal network, except the guest subnet
+/// let admin = IpAcl::new()
+///     .deny("10.9.0.0/16".parse().unwrap())
+///     .allow("10.0.0.0/8".parse().unwrap())
+///     .allow("fd00::/8".parse().unwrap());
+/// assert!(admin.allows("10.1.2.3".parse().unwrap()));
+/// assert!(!admin.allows("10.9.0.1".parse().unwrap()));
+/// assert!(!admin.allows("203.0.113.7".parse().unwrap()));
+/// ```
+/// Rules are checked in order and the first one that matches decides. Addresses no rule matches are denied if there are any allow rules, so a list of allows is an allowlist, and allowed otherwise, so a list of denies is a denylist.
+/// Clones share their rules, so reloading one with [`IpAcl::reload`] changes them everywhere it is used.
+#[derive(Clone)]
+pub struct IpAcl {
+    path: Option<PathBuf>,
+    rules: Arc<RwLock<Vec<(AclAction, Cidr)>>>,
+}
+
+impl Default for IpAcl {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl IpAcl {
+    /// An ACL without rules, which allows everyone
+    pub fn new() -> Self {
+        Self {
+            path: None,
+            rules: Arc::new(RwLock::new(Vec::new())),
+        }
+    }
+
+    /// Loads the rules from a file with one `allow CIDR` or `deny CIDR` per line, e.g. `allow 10.0.0.0/8`. `all` stands for every IPv4 and IPv6 address, e.g. `deny all`.
+    /// Empty lines and lines starting with `#` are skipped. The file is remembered for [`IpAcl::reload`].
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let acl = Self {
+            path: Some(path.to_owned()),
+            ..Self::new()
+        };
+        acl.reload()?;
+        Ok(acl)
+    }
+
+    /// Loads the rules from the file given to [`IpAcl::load`] again and swaps them in. If the file can't be read or parsed the current rules are kept.
+    /// Does nothing for an ACL built in code.
+    pub fn reload(&self) -> io::Result<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        let rules = parse(&fs::read_to_string(path)?)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+        *self.rules.write().unwrap_or_else(|err| err.into_inner()) = rules;
+        Ok(())
+    }
+
+    /// Consumes self and adds a rule letting `cidr` in, after the existing rules
+    pub fn allow(self, cidr: Cidr) -> Self {
+        self.push(AclAction::Allow, cidr)
+    }
+
+    /// Consumes self and adds a rule keeping `cidr` out, after the existing rules
+    pub fn deny(self, cidr: Cidr) -> Self {
+        self.push(AclAction::Deny, cidr)
+    }
+
+    fn push(self, action: AclAction, cidr: Cidr) -> Self {
+        self.rules
+            .write()
+            .unwrap_or_else(|err| err.into_inner())
+            .push((action, cidr));
+        self
+    }
+
+    /// Whether `ip` is let in
+    pub fn allows(&self, ip: IpAddr) -> bool {
+        let rules = self.rules.read().unwrap_or_else(|err| err.into_inner());
+        let action = rules
+            .iter()
+            .find(|(_, cidr)| cidr.contains(ip))
+            .map(|(action, _)| *action)
+            .unwrap_or(
+                if rules.iter().any(|(action, _)| *action == AclAction::Allow) {
+                    AclAction::Deny
+                } else {
+                    AclAction::Allow
+                },
+            );
+        action == AclAction::Allow
+    }
+}
+
+/// Parses the rules of an ACL file, see [`IpAcl::load`]
+fn parse(rules: &str) -> Result<Vec<(AclAction, Cidr)>, String> {
+    let mut parsed = Vec::new();
+    for line in rules
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+    {
+        let (action, address) = line
+            .split_once(char::is_whitespace)
+            .ok_or_else(|| format!("Expected \"allow CIDR\" or \"deny CIDR\", got {line:?}"))?;
+        let action = match action {
+            "allow" => AclAction::Allow,
+            "deny" => AclAction::Deny,
+            _ => return Err(format!("Unknown ACL action {action:?}")),
+        };
+        match address.trim() {
+            "all" => {
+                parsed.push((action, "0.0.0.0/0".parse()?));
+                parsed.push((action, "::/0".parse()?));
+            }
+            address => parsed.push((action, address.parse()?)),
+        }
+    }
+    Ok(parsed)
+}
+
+impl fmt::Debug for IpAcl {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("IpAcl")
+            .field("path", &self.path)
+            .field(
+                "rules",
+                &*self.rules.read().unwrap_or_else(|err| err.into_inner()),
+            )
+            .finish()
+    }
+}
+
+/// ACLs are equal if they share their rules
+impl PartialEq for IpAcl {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.rules, &other.rules)
+    }
+}
+
+impl Eq for IpAcl {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn first_matching_rule_decides() {
+        let denylist = IpAcl::new().deny("192.0.2.0/24".parse().unwrap());
+        assert!(!denylist.allows(ip("192.0.2.9")));
+        assert!(denylist.allows(ip("2001:db8::1")));
+
+        let rules = parse(
+            "# internal only\n\
+             deny 10.9.0.0/16\n\
+             allow 10.0.0.0/8\n\
+             allow 2001:db8::/32\n",
+        )
+        .unwrap();
+        let allowlist = IpAcl::new();
+        *allowlist.rules.write().unwrap() = rules;
+        assert!(allowlist.allows(ip("10.1.2.3")));
+        assert!(allowlist.allows(ip("::ffff:10.1.2.3")));
+        assert!(allowlist.allows(ip("2001:db8::1")));
+        assert!(!allowlist.allows(ip("10.9.0.1")));
+        assert!(!allowlist.allows(ip("203.0.113.7")));
+
+        assert!(parse("permit 10.0.0.0/8").is_err());
+        assert!(parse("allow 10.0.0.0/40").is_err());
+        assert_eq!(parse("deny all").unwrap().len(), 2);
+    }
+
+    #[test]
+    fn reloads_from_file() {
+        let path = std::env::temp_dir().join(format!("acl-test-{}", std::process::id()));
+        fs::write(&path, "allow 127.0.0.1\n").unwrap();
+        let acl = IpAcl::load(&path).unwrap();
+        let shared = acl.clone();
+        assert!(shared.allows(ip("127.0.0.1")));
+        assert!(!shared.allows(ip("127.0.0.2")));
+
+        fs::write(&path, "deny 127.0.0.1\n").unwrap();
+        acl.reload().unwrap();
+        assert!(!shared.allows(ip("127.0.0.1")));
+        assert!(shared.allows(ip("127.0.0.2")));
+
+        // a broken file keeps the rules there were
+        fs::write(&path, "allow nowhere\n").unwrap();
+        assert!(acl.reload().is_err());
+        assert!(!shared.allows(ip("127.0.0.1")));
+        fs::remove_file(&path).unwrap();
+    }
+}
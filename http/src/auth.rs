@@ -0,0 +1,311 @@
+use crate::{Claims, HTTPRequestHeader, HTTPResponses};
+use argon2::{Argon2, PasswordHash, PasswordVerifier};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use std::{collections::HashMap, fmt, fs, io, path::Path, sync::Arc};
+
+/// Who a request was authenticated as, at [`HTTPRequestHeader::principal`]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Principal {
+    /// The user name, or whatever the token identifies
+    pub name: String,
+    /// The token's claims, when authenticated with a [`crate::JwtValidator`]
+    pub claims: Option<Claims>,
+}
+
+impl Principal {
+    /// A principal with just a name
+    pub fn new(name: &str) -> Self {
+        Self {
+            name: name.to_owned(),
+            claims: None,
+        }
+    }
+}
+
+/// The value of a request's `Authorization` header. Only the scheme is shown when debug printed, so credentials don't end up in logs.
+#[derive(Clone, Default, PartialEq, Eq)]
+pub struct Authorization(pub String);
+
+impl Authorization {
+    /// The credentials, if the header uses `scheme`. Schemes are case insensitive.
+    pub fn credentials(&self, scheme: &str) -> Option<&str> {
+        let (request_scheme, credentials) = self.0.split_once(' ')?;
+        request_scheme
+            .eq_ignore_ascii_case(scheme)
+            .then(|| credentials.trim())
+    }
+}
+
+impl fmt::Debug for Authorization {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let scheme = self.0.split(' ').next().unwrap_or_default();
+        write!(f, "Authorization({scheme} ...)")
+    }
+}
+
+/// Reasons a request was not let through by an [`AuthGuard`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AuthError {
+    /// The request has no credentials for the guard's scheme. Gets an `HTTP 401`
+    Missing,
+    /// The credentials are wrong, expired or malformed. Gets an `HTTP 401`
+    Invalid(String),
+    /// The credentials are fine, but don't allow access to this route. Gets an `HTTP 403`
+    Forbidden(String),
+}
+
+/// Checks the credentials of a request. Runs on tokio's blocking thread pool, so it may take its time, e.g. to verify a password hash.
+pub trait Authenticator: Send + Sync {
+    /// The `WWW-Authenticate` challenge sent when the request is rejected with `err`
+    fn challenge(&self, err: &AuthError) -> String;
+    fn authenticate(&self, header: &HTTPRequestHeader) -> Result<Principal, AuthError>;
+}
+
+/// An [`Authenticator`] attached to routes with [`crate::RouteOptions::auth`] or [`crate::Router::with_auth`]. Cheap to clone, clones share the authenticator.
+#[derive(Clone)]
+pub struct AuthGuard(Arc<dyn Authenticator>);
+
+impl AuthGuard {
+    pub fn new(authenticator: impl Authenticator + 'static) -> Self {
+        Self(Arc::new(authenticator))
+    }
+
+    /// Authenticates the request, returning the response to send back if it isn't let through
+    pub(crate) fn check(
+        &self,
+        header: &HTTPRequestHeader,
+    ) -> Result<Principal, Box<HTTPResponses>> {
+        self.0.authenticate(header).map_err(|err| {
+            let response = match &err {
+                AuthError::Forbidden(_) => HTTPResponses::forbidden(),
+                AuthError::Missing | AuthError::Invalid(_) => HTTPResponses::unauthorized(),
+            };
+            Box::new((*response).with_header("WWW-Authenticate", &self.0.challenge(&err)))
+        })
+    }
+}
+
+impl fmt::Debug for AuthGuard {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "AuthGuard")
+    }
+}
+
+/// Guards are equal if they share the same authenticator
+impl PartialEq for AuthGuard {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+impl Eq for AuthGuard {}
+
+fn credentials<'a>(header: &'a HTTPRequestHeader, scheme: &str) -> Option<&'a str> {
+    header.authorization.as_ref()?.credentials(scheme)
+}
+
+/// Basic authentication against an htpasswd file, with bcrypt (`htpasswd -B`) or argon2 hashes. The principal is the user name.
+pub struct BasicAuth {
+    realm: String,
+    users: HashMap<String, String>,
+}
+
+impl BasicAuth {
+    /// Loads the users from an htpasswd file, with a `user:hash` pair per line. Empty lines and lines starting with `#` are skipped.
+    /// Fails if a user's hash isn't bcrypt or argon2, as the other htpasswd formats are too weak to be worth supporting.
+    pub fn load(path: &Path, realm: &str) -> io::Result<Self> {
+        let invalid = |err| io::Error::new(io::ErrorKind::InvalidData, err);
+        let users = fs::read_to_string(path)?
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .map(|line| {
+                let (user, hash) = line
+                    .split_once(':')
+                    .ok_or_else(|| invalid(format!("Missing ':' in line {line:?}")))?;
+                if !(hash.starts_with("$2") || hash.starts_with("$argon2")) {
+                    return Err(invalid(format!(
+                        "Unsupported hash for {user}, only bcrypt and argon2 are accepted"
+                    )));
+                }
+                Ok((user.to_owned(), hash.to_owned()))
+            })
+            .collect::<io::Result<_>>()?;
+        Ok(Self {
+            realm: realm.to_owned(),
+            users,
+        })
+    }
+}
+
+fn verify_password(password: &str, hash: &str) -> bool {
+    if hash.starts_with("$argon2") {
+        PasswordHash::new(hash).is_ok_and(|hash| {
+            Argon2::default()
+                .verify_password(password.as_bytes(), &hash)
+                .is_ok()
+        })
+    } else {
+        bcrypt::verify(password, hash).unwrap_or(false)
+    }
+}
+
+impl Authenticator for BasicAuth {
+    fn challenge(&self, _: &AuthError) -> String {
+        format!("Basic realm=\"{}\", charset=\"UTF-8\"", self.realm)
+    }
+
+    fn authenticate(&self, header: &HTTPRequestHeader) -> Result<Principal, AuthError> {
+        let credentials = credentials(header, "Basic").ok_or(AuthError::Missing)?;
+        let credentials = STANDARD
+            .decode(credentials)
+            .ok()
+            .and_then(|credentials| String::from_utf8(credentials).ok())
+            .ok_or_else(|| AuthError::Invalid("Malformed credentials".to_owned()))?;
+        let (user, password) = credentials
+            .split_once(':')
+            .ok_or_else(|| AuthError::Invalid("Malformed credentials".to_owned()))?;
+
+        match self.users.get(user) {
+            Some(hash) if verify_password(password, hash) => Ok(Principal::new(user)),
+            Some(_) => Err(AuthError::Invalid("Wrong password".to_owned())),
+            None => {
+                // check against some hash anyway, so unknown users take as long as wrong passwords and can't be told apart
+                if let Some(hash) = self.users.values().next() {
+                    verify_password(password, hash);
+                }
+                Err(AuthError::Invalid("Unknown user".to_owned()))
+            }
+        }
+    }
+}
+
+/// Checks bearer tokens for [`BearerAuth`]. Implemented for closures taking the token, so a simple lookup can be written inline.
+pub trait TokenValidator: Send + Sync {
+    fn validate(&self, token: &str) -> Result<Principal, AuthError>;
+}
+
+impl<F: Fn(&str) -> Result<Principal, AuthError> + Send + Sync> TokenValidator for F {
+    fn validate(&self, token: &str) -> Result<Principal, AuthError> {
+        self(token)
+    }
+}
+
+/// Bearer token authentication (RFC 6750), with the tokens checked by a [`TokenValidator`]
+pub struct BearerAuth {
+    realm: String,
+    validator: Box<dyn TokenValidator>,
+}
+
+impl BearerAuth {
+    pub fn new(realm: &str, validator: impl TokenValidator + 'static) -> Self {
+        Self {
+            realm: realm.to_owned(),
+            validator: Box::new(validator),
+        }
+    }
+}
+
+impl Authenticator for BearerAuth {
+    fn challenge(&self, err: &AuthError) -> String {
+        let quote = |s: &str| s.replace(['\\', '"'], "");
+        match err {
+            AuthError::Missing => format!("Bearer realm=\"{}\"", self.realm),
+            AuthError::Invalid(reason) => format!(
+                "Bearer realm=\"{}\", error=\"invalid_token\", error_description=\"{}\"",
+                self.realm,
+                quote(reason)
+            ),
+            AuthError::Forbidden(reason) => format!(
+                "Bearer realm=\"{}\", error=\"insufficient_scope\", error_description=\"{}\"",
+                self.realm,
+                quote(reason)
+            ),
+        }
+    }
+
+    fn authenticate(&self, header: &HTTPRequestHeader) -> Result<Principal, AuthError> {
+        let token = credentials(header, "Bearer").ok_or(AuthError::Missing)?;
+        self.validator.validate(token)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use argon2::{password_hash::SaltString, PasswordHasher};
+
+    fn with_authorization(authorization: &str) -> HTTPRequestHeader {
+        HTTPRequestHeader {
+            authorization: Some(Authorization(authorization.to_owned())),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn checks_htpasswd_users() {
+        let path = std::env::temp_dir().join(format!("htpasswd-{}", std::process::id()));
+        fs::write(
+            &path,
+            format!(
+                "# users\nalice:{}\nbob:{}\n",
+                bcrypt::hash("wonderland", 4).unwrap(),
+                Argon2::default()
+                    .hash_password(
+                        b"password",
+                        &SaltString::from_b64("c29tZXNhbHRzb21lc2FsdA").unwrap()
+                    )
+                    .unwrap()
+            ),
+        )
+        .unwrap();
+        let auth = BasicAuth::load(&path, "test").unwrap();
+        fs::remove_file(&path).unwrap();
+
+        let basic = |credentials: &str| {
+            auth.authenticate(&with_authorization(&format!(
+                "Basic {}",
+                STANDARD.encode(credentials)
+            )))
+        };
+        assert_eq!(basic("alice:wonderland").unwrap().name, "alice");
+        assert_eq!(basic("bob:password").unwrap().name, "bob");
+        assert!(matches!(basic("alice:guess"), Err(AuthError::Invalid(_))));
+        assert!(matches!(basic("carol:guess"), Err(AuthError::Invalid(_))));
+        assert_eq!(
+            auth.authenticate(&HTTPRequestHeader::default()),
+            Err(AuthError::Missing)
+        );
+    }
+
+    #[test]
+    fn challenges_rejected_bearer_tokens() {
+        let guard = AuthGuard::new(BearerAuth::new("api", |token: &str| match token {
+            "good" => Ok(Principal::new("service")),
+            "limited" => Err(AuthError::Forbidden("Needs the admin scope".to_owned())),
+            _ => Err(AuthError::Invalid("Unknown token".to_owned())),
+        }));
+
+        assert_eq!(
+            guard
+                .check(&with_authorization("bearer good"))
+                .unwrap()
+                .name,
+            "service"
+        );
+        assert_eq!(
+            guard.check(&HTTPRequestHeader::default()).unwrap_err(),
+            Box::new(
+                (*HTTPResponses::unauthorized())
+                    .with_header("WWW-Authenticate", "Bearer realm=\"api\"")
+            )
+        );
+        assert_eq!(
+            guard.check(&with_authorization("Bearer limited")).unwrap_err(),
+            Box::new((*HTTPResponses::forbidden()).with_header(
+                "WWW-Authenticate",
+                "Bearer realm=\"api\", error=\"insufficient_scope\", error_description=\"Needs the admin scope\""
+            ))
+        );
+    }
+}
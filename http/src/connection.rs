@@ -0,0 +1,46 @@
+use std::net::SocketAddr;
+
+/// What the server knows about the connection a request arrived on, as opposed to what the request itself says
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ConnectionInfo {
+    /// The address of the other end of the TCP connection. `None` for requests that didn't come from a socket, e.g. in tests
+    pub peer: Option<SocketAddr>,
+    /// Whether the connection is over TLS
+    pub tls: bool,
+    /// The certificate the client authenticated with, if the connection is TLS and the client sent one that verified against the configured CA bundle
+    pub client_cert: Option<ClientCertificate>,
+    /// The addresses a load balancer said the connection was made with, through the PROXY protocol. `None` if the server isn't behind one, or for the load balancer's own connections
+    pub proxy: Option<ProxyAddresses>,
+}
+
+/// The original ends of a connection passed on by a load balancer, read from its PROXY protocol header
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ProxyAddresses {
+    /// The client's address
+    pub source: SocketAddr,
+    /// The address the client connected to, on the load balancer
+    pub destination: SocketAddr,
+}
+
+/// The verified identity from a TLS client certificate
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClientCertificate {
+    /// The subject distinguished name, e.g `CN=billing, O=Example`
+    pub subject: String,
+    /// The subject alternative names, each prefixed with its type, e.g `DNS:billing.internal` or `URI:spiffe://example/billing`
+    pub sans: Vec<String>,
+    /// Lowercase hex SHA-256 digest of the DER encoded certificate
+    pub fingerprint: String,
+}
+
+/// Whether a route needs the client to have authenticated with a TLS client certificate
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ClientCertPolicy {
+    /// Requests without a verified client certificate get a `403 Forbidden`
+    Required,
+    /// The client certificate is passed to the callback if there is one
+    #[default]
+    Optional,
+    /// The client certificate is never passed to the callback, even if the client sent one
+    Ignored,
+}
@@ -0,0 +1,244 @@
+use crate::date::UtcDateTime;
+use std::{
+    collections::HashMap,
+    fmt,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+/// The cookies a client sent with a request, from its `Cookie` headers
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CookieJar(HashMap<String, String>);
+
+impl CookieJar {
+    /// Parses the value of a `Cookie` header, e.g. `session=abc; theme=dark`, adding its cookies to the jar.
+    /// Pairs without a `=` are skipped. If a name is sent more than once, the first one is kept as browsers send the most specific cookie first.
+    pub fn add_header(&mut self, header: &str) {
+        for (name, value) in header
+            .split(';')
+            .filter_map(|pair| pair.split_once('='))
+            .map(|(name, value)| (name.trim(), value.trim()))
+            .filter(|(name, _)| !name.is_empty())
+        {
+            let value = value
+                .strip_prefix('"')
+                .and_then(|value| value.strip_suffix('"'))
+                .unwrap_or(value);
+            self.0
+                .entry(name.to_owned())
+                .or_insert_with(|| value.to_owned());
+        }
+    }
+
+    /// The value of the cookie called `name`
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.0.get(name).map(String::as_str)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.0
+            .iter()
+            .map(|(name, value)| (name.as_str(), value.as_str()))
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+/// The `SameSite` attribute of a [`Cookie`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SameSite {
+    Strict,
+    Lax,
+    /// Browsers only accept this on cookies that are also [`Cookie::secure`]
+    None,
+}
+
+/// A cookie to set on the client with a `Set-Cookie` header. Built up with chained calls and attached to a response with [`crate::HTTPResponses::with_cookie`]. For example:
+/// ```rust
+/// # use http::{Cookie, SameSite};
+/// # use std::time::Duration;
+/// let cookie = Cookie::new("theme", "dark")
+///     .path("/")
+///     .max_age(Duration::from_secs(3600))
+///     .http_only(true)
+///     .same_site(SameSite::Lax);
+/// assert_eq!(cookie.to_string(), "theme=dark; Path=/; Max-Age=3600; HttpOnly; SameSite=Lax");
+/// ```
+/// Names and values are sent as they are, so anything outside of the characters allowed in a cookie should be encoded by the caller.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Cookie {
+    name: String,
+    value: String,
+    path: Option<String>,
+    domain: Option<String>,
+    expires: Option<SystemTime>,
+    max_age: Option<Duration>,
+    secure: bool,
+    http_only: bool,
+    same_site: Option<SameSite>,
+}
+
+impl Cookie {
+    /// A session cookie, which the client drops when it is closed, with no other attributes
+    pub fn new(name: &str, value: &str) -> Self {
+        Self {
+            name: name.to_owned(),
+            value: value.to_owned(),
+            path: None,
+            domain: None,
+            expires: None,
+            max_age: None,
+            secure: false,
+            http_only: false,
+            same_site: None,
+        }
+    }
+
+    /// A cookie that makes the client delete its cookie called `name`. The path and domain have to match the ones the cookie was set with.
+    pub fn removal(name: &str) -> Self {
+        Self::new(name, "")
+            .expires(UNIX_EPOCH)
+            .max_age(Duration::ZERO)
+    }
+
+    /// The cookie's name
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The cookie's value
+    pub fn value(&self) -> &str {
+        &self.value
+    }
+
+    /// Swaps the value, keeping the other attributes. Used by [`crate::CookieKeys`] to sign and encrypt values
+    pub(crate) fn replace_value(mut self, value: String) -> Self {
+        self.value = value;
+        self
+    }
+
+    pub fn path(mut self, path: &str) -> Self {
+        self.path = Some(path.to_owned());
+        self
+    }
+
+    pub fn domain(mut self, domain: &str) -> Self {
+        self.domain = Some(domain.to_owned());
+        self
+    }
+
+    /// When the client should delete the cookie. Sent with a precision of one second.
+    pub fn expires(mut self, expires: SystemTime) -> Self {
+        self.expires = Some(expires);
+        self
+    }
+
+    /// How long the client should keep the cookie for. Takes precedence over [`Cookie::expires`] for clients that understand it. Sent with a precision of one second.
+    pub fn max_age(mut self, max_age: Duration) -> Self {
+        self.max_age = Some(max_age);
+        self
+    }
+
+    /// Whether the cookie is only sent over HTTPS
+    pub fn secure(mut self, secure: bool) -> Self {
+        self.secure = secure;
+        self
+    }
+
+    /// Whether the cookie is hidden from JavaScript
+    pub fn http_only(mut self, http_only: bool) -> Self {
+        self.http_only = http_only;
+        self
+    }
+
+    pub fn same_site(mut self, same_site: SameSite) -> Self {
+        self.same_site = Some(same_site);
+        self
+    }
+}
+
+/// Formats the cookie as the value of a `Set-Cookie` header
+impl fmt::Display for Cookie {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}={}", self.name, self.value)?;
+        if let Some(path) = &self.path {
+            write!(f, "; Path={path}")?;
+        }
+        if let Some(domain) = &self.domain {
+            write!(f, "; Domain={domain}")?;
+        }
+        if let Some(expires) = self.expires {
+            write!(f, "; Expires={}", http_date(expires))?;
+        }
+        if let Some(max_age) = self.max_age {
+            write!(f, "; Max-Age={}", max_age.as_secs())?;
+        }
+        if self.secure {
+            write!(f, "; Secure")?;
+        }
+        if self.http_only {
+            write!(f, "; HttpOnly")?;
+        }
+        match self.same_site {
+            Some(SameSite::Strict) => write!(f, "; SameSite=Strict"),
+            Some(SameSite::Lax) => write!(f, "; SameSite=Lax"),
+            Some(SameSite::None) => write!(f, "; SameSite=None"),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Formats a time the way HTTP headers expect it, e.g. `Sun, 06 Nov 1994 08:49:37 GMT`. Times before 1970 are sent as the epoch.
+fn http_date(time: SystemTime) -> String {
+    let date = UtcDateTime::from(time);
+    format!(
+        "{}, {:02} {} {} {:02}:{:02}:{:02} GMT",
+        date.weekday_name(),
+        date.day,
+        date.month_name(),
+        date.year,
+        date.hour,
+        date.minute,
+        date.second
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_cookie_headers() {
+        let mut jar = CookieJar::default();
+        jar.add_header("session=abc123; theme=\"dark\";flag; =empty");
+        jar.add_header("session=other; lang=en");
+
+        assert_eq!(jar.get("session"), Some("abc123"));
+        assert_eq!(jar.get("theme"), Some("dark"));
+        assert_eq!(jar.get("lang"), Some("en"));
+        assert_eq!(jar.get("flag"), None);
+        assert_eq!(jar.len(), 3);
+    }
+
+    #[test]
+    fn formats_set_cookie() {
+        let cookie = Cookie::new("id", "42")
+            .domain("example.test")
+            .expires(UNIX_EPOCH + Duration::from_secs(784111777))
+            .secure(true)
+            .same_site(SameSite::None);
+        assert_eq!(
+            cookie.to_string(),
+            "id=42; Domain=example.test; Expires=Sun, 06 Nov 1994 08:49:37 GMT; Secure; SameSite=None"
+        );
+        assert_eq!(
+            Cookie::removal("id").path("/").to_string(),
+            "id=; Path=/; Expires=Thu, 01 Jan 1970 00:00:00 GMT; Max-Age=0"
+        );
+    }
+}
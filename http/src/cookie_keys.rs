@@ -0,0 +1,217 @@
+use crate::{Cookie, CookieJar};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use ring::{
+    aead::{self, Aad, LessSafeKey, Nonce, UnboundKey, NONCE_LEN},
+    hkdf, hmac,
+    rand::{SecureRandom, SystemRandom},
+};
+use std::fmt;
+
+/// Shortest secret a [`CookieKeys`] key may be made from
+pub const MIN_SECRET_LEN: usize = 32;
+
+/// Reasons a secret can not be used as a cookie key
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CookieKeyError {
+    /// The secret is shorter than [`MIN_SECRET_LEN`] bytes
+    TooShort,
+}
+
+/// The keys derived from one secret. Signing and encryption use separate keys so neither can be used to attack the other.
+struct Key {
+    signing: hmac::Key,
+    encryption: LessSafeKey,
+}
+
+impl Key {
+    fn derive(secret: &[u8]) -> Result<Self, CookieKeyError> {
+        if secret.len() < MIN_SECRET_LEN {
+            return Err(CookieKeyError::TooShort);
+        }
+        let prk = hkdf::Salt::new(hkdf::HKDF_SHA256, b"").extract(secret);
+        let signing = prk
+            .expand(&[b"cookie signing"], hmac::HMAC_SHA256)
+            .map(hmac::Key::from)
+            .expect("HMAC key length is within HKDF's output limit");
+        let encryption = prk
+            .expand(&[b"cookie encryption"], &aead::AES_256_GCM)
+            .map(UnboundKey::from)
+            .map(LessSafeKey::new)
+            .expect("AES key length is within HKDF's output limit");
+        Ok(Self {
+            signing,
+            encryption,
+        })
+    }
+}
+
+/// Keys for cookies the client can read but not change (signed) or can neither read nor change (private).
+/// New cookies are always issued with the current key. Cookies issued with previous keys are still accepted, so keys can be rotated without logging everyone out: add the new key as current, keep the old one as previous until its cookies have expired, then drop it. For example:
+/// ```rust
+/// # use http::{Cookie, CookieJar, CookieKeys};
+/// let old = CookieKeys::new(&[1; 32]).unwrap();
+/// let cookie = old.sign(Cookie::new("user", "42"));
+///
+/// let mut jar = CookieJar::default();
+/// jar.add_header(&format!("{}={}", cookie.name(), cookie.value()));
+///
+/// let rotated = CookieKeys::new(&[2; 32]).unwrap().with_previous(&[1; 32]).unwrap();
+/// assert_eq!(rotated.get_signed(&jar, "user"), Some("42".to_owned()));
+/// ```
+pub struct CookieKeys {
+    /// The current key comes first
+    keys: Vec<Key>,
+    rng: SystemRandom,
+}
+
+/// Keeps the keys out of logs
+impl fmt::Debug for CookieKeys {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "CookieKeys({} key(s))", self.keys.len())
+    }
+}
+
+impl CookieKeys {
+    /// Keys derived from `secret`, which should be at least [`MIN_SECRET_LEN`] random bytes kept out of source control
+    pub fn new(secret: &[u8]) -> Result<Self, CookieKeyError> {
+        Ok(Self {
+            keys: vec![Key::derive(secret)?],
+            rng: SystemRandom::new(),
+        })
+    }
+
+    /// Consumes self and also accepts cookies issued with `secret`, without issuing new ones with it
+    pub fn with_previous(mut self, secret: &[u8]) -> Result<Self, CookieKeyError> {
+        self.keys.push(Key::derive(secret)?);
+        Ok(self)
+    }
+
+    /// Signs the cookie's value, appending the signature after a `.`. The name is part of what is signed, so the value can't be moved over to another cookie.
+    pub fn sign(&self, cookie: Cookie) -> Cookie {
+        let tag = hmac::sign(
+            &self.keys[0].signing,
+            signed_message(cookie.name(), cookie.value()).as_bytes(),
+        );
+        let value = format!("{}.{}", cookie.value(), URL_SAFE_NO_PAD.encode(tag));
+        cookie.replace_value(value)
+    }
+
+    /// The value of the signed cookie called `name`, if it has a valid signature from any of the keys
+    pub fn get_signed(&self, jar: &CookieJar, name: &str) -> Option<String> {
+        let (value, tag) = jar.get(name)?.rsplit_once('.')?;
+        let tag = URL_SAFE_NO_PAD.decode(tag).ok()?;
+        let message = signed_message(name, value);
+        self.keys
+            .iter()
+            .any(|key| hmac::verify(&key.signing, message.as_bytes(), &tag).is_ok())
+            .then(|| value.to_owned())
+    }
+
+    /// Encrypts the cookie's value so the client can't read or change it. Like [`CookieKeys::sign`], the name is bound to the value.
+    pub fn encrypt(&self, cookie: Cookie) -> Cookie {
+        let mut nonce = [0; NONCE_LEN];
+        self.rng
+            .fill(&mut nonce)
+            .expect("The system's random number generator failed");
+        let mut sealed = cookie.value().as_bytes().to_vec();
+        self.keys[0]
+            .encryption
+            .seal_in_place_append_tag(
+                Nonce::assume_unique_for_key(nonce),
+                Aad::from(cookie.name()),
+                &mut sealed,
+            )
+            .expect("Cookie values are within AES-GCM's length limit");
+        let value = URL_SAFE_NO_PAD.encode([nonce.as_slice(), &sealed].concat());
+        cookie.replace_value(value)
+    }
+
+    /// The value of the private cookie called `name`, if any of the keys can decrypt it
+    pub fn get_private(&self, jar: &CookieJar, name: &str) -> Option<String> {
+        let sealed = URL_SAFE_NO_PAD.decode(jar.get(name)?).ok()?;
+        if sealed.len() < NONCE_LEN {
+            return None;
+        }
+        let (nonce, sealed) = sealed.split_at(NONCE_LEN);
+        self.keys.iter().find_map(|key| {
+            let nonce = Nonce::try_assume_unique_for_key(nonce).ok()?;
+            let mut sealed = sealed.to_vec();
+            let value = key
+                .encryption
+                .open_in_place(nonce, Aad::from(name), &mut sealed)
+                .ok()?;
+            String::from_utf8(value.to_vec()).ok()
+        })
+    }
+}
+
+fn signed_message(name: &str, value: &str) -> String {
+    format!("{name}={value}")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn jar(cookie: &Cookie) -> CookieJar {
+        let mut jar = CookieJar::default();
+        jar.add_header(&format!("{}={}", cookie.name(), cookie.value()));
+        jar
+    }
+
+    #[test]
+    fn rejects_tampered_cookies() {
+        let keys = CookieKeys::new(&[7; 32]).unwrap();
+
+        let signed = keys.sign(Cookie::new("role", "user.admin"));
+        assert_eq!(
+            keys.get_signed(&jar(&signed), "role"),
+            Some("user.admin".to_owned())
+        );
+        let forged = signed
+            .clone()
+            .replace_value(signed.value().replacen("user", "root", 1));
+        assert_eq!(keys.get_signed(&jar(&forged), "role"), None);
+        // a signature is only valid for the cookie it was issued for
+        let moved = Cookie::new("other", signed.value());
+        assert_eq!(keys.get_signed(&jar(&moved), "other"), None);
+
+        let private = keys.encrypt(Cookie::new("secret", "hunter2"));
+        assert!(!private.value().contains("hunter2"));
+        assert_eq!(
+            keys.get_private(&jar(&private), "secret"),
+            Some("hunter2".to_owned())
+        );
+        let mut tampered = URL_SAFE_NO_PAD.decode(private.value()).unwrap();
+        *tampered.last_mut().unwrap() ^= 1;
+        let tampered = private.replace_value(URL_SAFE_NO_PAD.encode(tampered));
+        assert_eq!(keys.get_private(&jar(&tampered), "secret"), None);
+    }
+
+    #[test]
+    fn rotates_keys() {
+        let old = CookieKeys::new(&[1; 32]).unwrap();
+        let rotated = CookieKeys::new(&[2; 32])
+            .unwrap()
+            .with_previous(&[1; 32])
+            .unwrap();
+        let new = CookieKeys::new(&[2; 32]).unwrap();
+
+        let cookie = old.encrypt(Cookie::new("id", "42"));
+        assert_eq!(
+            rotated.get_private(&jar(&cookie), "id"),
+            Some("42".to_owned())
+        );
+        assert_eq!(new.get_private(&jar(&cookie), "id"), None);
+
+        // new cookies are issued with the current key only
+        let cookie = rotated.sign(Cookie::new("id", "42"));
+        assert_eq!(new.get_signed(&jar(&cookie), "id"), Some("42".to_owned()));
+        assert_eq!(old.get_signed(&jar(&cookie), "id"), None);
+
+        assert_eq!(
+            CookieKeys::new(&[1; 16]).unwrap_err(),
+            CookieKeyError::TooShort
+        );
+    }
+}
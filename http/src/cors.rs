@@ -0,0 +1,286 @@
+use crate::{HTTPRequestHeader, HTTPResponses, RawHTTPResponse};
+use regex::Regex;
+use std::time::Duration;
+
+/// An origin a [`Cors`] policy lets in
+#[derive(Debug, Clone)]
+enum AllowedOrigin {
+    Exact(String),
+    Pattern(Regex),
+}
+
+impl AllowedOrigin {
+    fn matches(&self, origin: &str) -> bool {
+        match self {
+            Self::Exact(exact) => exact == origin,
+            Self::Pattern(pattern) => pattern.is_match(origin),
+        }
+    }
+}
+
+/// Patterns are equal if they were built from the same regex
+impl PartialEq for AllowedOrigin {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Self::Exact(a), Self::Exact(b)) => a == b,
+            (Self::Pattern(a), Self::Pattern(b)) => a.as_str() == b.as_str(),
+            _ => false,
+        }
+    }
+}
+
+impl Eq for AllowedOrigin {}
+
+/// A Cross-Origin Resource Sharing policy, letting browser apps on other origins call routes it is attached to with [`crate::RouteOptions::cors`] or [`crate::Router::with_cors`]. For example:
+/// ```rust
+/// # use http::Cors;
+/// # use std::time::Duration;
+/// let cors = Cors::new()
+///     .allow_origin("https://app.example.test")
+///     .allow_origin_regex(r"https://[a-z0-9-]+\.preview\.example\.test")
+///     .unwrap()
+///     .allow_methods(&["GET", "POST"])
+///     .allow_headers(&["Content-Type"])
+///     .allow_credentials(true)
+///     .max_age(Duration::from_secs(600));
+/// ```
+/// `OPTIONS` preflight requests to these routes are answered by the router with an `HTTP 204`, without the route's callback, guard or sessions running, unless a route for `OPTIONS` itself matches.
+/// Preflights from origins, or asking for methods or headers, that aren't allowed get an `HTTP 403`.
+/// Responses to other requests from allowed origins get `Access-Control-Allow-Origin` and friends added, whether they come from the callback or are errors like an `HTTP 401`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Cors {
+    origins: Vec<AllowedOrigin>,
+    any_origin: bool,
+    methods: Vec<String>,
+    headers: Vec<String>,
+    exposed_headers: Vec<String>,
+    credentials: bool,
+    max_age: Option<Duration>,
+}
+
+impl Cors {
+    /// A policy that lets no origins in until some are allowed, for any method the route accepts, with no extra request headers or credentials
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Consumes self and lets in `origin`, e.g. `https://app.example.test`, compared as is against the request's `Origin`
+    pub fn allow_origin(mut self, origin: &str) -> Self {
+        self.origins.push(AllowedOrigin::Exact(origin.to_owned()));
+        self
+    }
+
+    /// Consumes self and lets in origins matching `pattern`. The pattern has to match the whole origin, so `https://.*\.example\.test` doesn't let in `https://evil.example.test.attacker.test`
+    pub fn allow_origin_regex(mut self, pattern: &str) -> Result<Self, regex::Error> {
+        let pattern = Regex::new(&format!("^(?:{pattern})$"))?;
+        self.origins.push(AllowedOrigin::Pattern(pattern));
+        Ok(self)
+    }
+
+    /// Consumes self and lets in every origin. Answered with `*`, unless credentials are allowed, in which case the request's origin is echoed back as browsers reject `*` with credentials.
+    pub fn allow_any_origin(mut self) -> Self {
+        self.any_origin = true;
+        self
+    }
+
+    /// Consumes self and limits the methods preflights may ask for. By default any method the route accepts is allowed.
+    pub fn allow_methods(mut self, methods: &[&str]) -> Self {
+        self.methods
+            .extend(methods.iter().map(|method| method.to_ascii_uppercase()));
+        self
+    }
+
+    /// Consumes self and allows requests to send `headers`, on top of the ones browsers always allow. Compared case insensitively
+    pub fn allow_headers(mut self, headers: &[&str]) -> Self {
+        self.headers
+            .extend(headers.iter().map(|header| header.to_ascii_lowercase()));
+        self
+    }
+
+    /// Consumes self and lets scripts read `headers` of the response, on top of the ones browsers always expose
+    pub fn expose_headers(mut self, headers: &[&str]) -> Self {
+        self.exposed_headers
+            .extend(headers.iter().map(|header| (*header).to_owned()));
+        self
+    }
+
+    /// Consumes self and sets whether browsers may send cookies and credentials along, and let scripts read the response to them
+    pub fn allow_credentials(mut self, credentials: bool) -> Self {
+        self.credentials = credentials;
+        self
+    }
+
+    /// Consumes self and sets how long browsers may cache a preflight's answer. By default browsers decide, which is a few seconds
+    pub fn max_age(mut self, max_age: Duration) -> Self {
+        self.max_age = Some(max_age);
+        self
+    }
+
+    /// The `Access-Control-Allow-Origin` to answer `origin` with, if it is let in
+    fn allow_origin_header(&self, origin: &str) -> Option<String> {
+        if self.any_origin && !self.credentials {
+            Some("*".to_owned())
+        } else if self.any_origin || self.origins.iter().any(|allowed| allowed.matches(origin)) {
+            Some(origin.to_owned())
+        } else {
+            None
+        }
+    }
+
+    /// The headers shared by preflights and actual responses. Responses that depend on the origin say so, so caches don't serve them to other origins
+    fn origin_headers(&self, origin: &str) -> Option<Vec<(String, String)>> {
+        let allow_origin = self.allow_origin_header(origin)?;
+        let mut headers = Vec::new();
+        if allow_origin != "*" {
+            headers.push(("Vary".to_owned(), "Origin".to_owned()));
+        }
+        headers.push(("Access-Control-Allow-Origin".to_owned(), allow_origin));
+        if self.credentials {
+            headers.push((
+                "Access-Control-Allow-Credentials".to_owned(),
+                "true".to_owned(),
+            ));
+        }
+        Some(headers)
+    }
+
+    /// Answers a preflight for a route that accepts the requested method when `route_accepts` says so
+    pub(crate) fn preflight(
+        &self,
+        header: &HTTPRequestHeader,
+        route_accepts: impl Fn(&str) -> bool,
+    ) -> RawHTTPResponse {
+        let method = header
+            .access_control_request_method
+            .as_deref()
+            .unwrap_or_default();
+        let requested_headers: Vec<String> = header
+            .access_control_request_headers
+            .iter()
+            .flat_map(|headers| headers.split(','))
+            .map(|name| name.trim().to_ascii_lowercase())
+            .filter(|name| !name.is_empty())
+            .collect();
+        let allowed = route_accepts(method)
+            && (self.methods.is_empty() || self.methods.iter().any(|allowed| allowed == method))
+            && requested_headers
+                .iter()
+                .all(|name| self.headers.contains(name));
+        let origin_headers = header
+            .origin
+            .as_deref()
+            .and_then(|origin| self.origin_headers(origin));
+        let Some(mut headers) = origin_headers.filter(|_| allowed) else {
+            return HTTPResponses::forbidden().into();
+        };
+
+        headers.push((
+            "Vary".to_owned(),
+            "Access-Control-Request-Method, Access-Control-Request-Headers".to_owned(),
+        ));
+        headers.push(("Access-Control-Allow-Methods".to_owned(), method.to_owned()));
+        if !requested_headers.is_empty() {
+            headers.push((
+                "Access-Control-Allow-Headers".to_owned(),
+                requested_headers.join(", "),
+            ));
+        }
+        if let Some(max_age) = self.max_age {
+            headers.push((
+                "Access-Control-Max-Age".to_owned(),
+                max_age.as_secs().to_string(),
+            ));
+        }
+        RawHTTPResponse {
+            status_code: 204,
+            message: "No Content".to_owned(),
+            headers,
+            body: Vec::new(),
+        }
+    }
+
+    /// Adds the CORS headers to the response to an actual request from `origin`
+    pub(crate) fn apply(&self, origin: Option<&str>, response: &mut RawHTTPResponse) {
+        let Some(origin) = origin else {
+            return;
+        };
+        match self.origin_headers(origin) {
+            Some(headers) => {
+                response.headers.extend(headers);
+                if !self.exposed_headers.is_empty() {
+                    response.headers.push((
+                        "Access-Control-Expose-Headers".to_owned(),
+                        self.exposed_headers.join(", "),
+                    ));
+                }
+            }
+            // the answer still depends on the origin, even though this one isn't let in
+            None => response
+                .headers
+                .push(("Vary".to_owned(), "Origin".to_owned())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hi() -> RawHTTPResponse {
+        HTTPResponses::from("Hi").into()
+    }
+
+    fn header<'a>(response: &'a RawHTTPResponse, name: &str) -> Option<&'a str> {
+        response
+            .headers
+            .iter()
+            .find(|(header, _)| header == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    #[test]
+    fn matches_allowed_origins() {
+        let cors = Cors::new()
+            .allow_origin_regex(r"https://[a-z]+\.example\.test")
+            .unwrap()
+            .expose_headers(&["X-Total"]);
+        for (origin, allowed) in [
+            ("https://app.example.test", true),
+            ("https://app.example.test.attacker.test", false),
+            ("http://app.example.test", false),
+        ] {
+            let mut response = hi();
+            cors.apply(Some(origin), &mut response);
+            let expected = allowed.then_some(origin);
+            assert_eq!(
+                header(&response, "Access-Control-Allow-Origin"),
+                expected,
+                "{origin}"
+            );
+            assert_eq!(header(&response, "Vary"), Some("Origin"));
+        }
+        let mut response = hi();
+        cors.apply(Some("https://app.example.test"), &mut response);
+        assert_eq!(
+            header(&response, "Access-Control-Expose-Headers"),
+            Some("X-Total")
+        );
+
+        // browsers reject a wildcard with credentials, so the origin is echoed back instead
+        let mut response = hi();
+        Cors::new()
+            .allow_any_origin()
+            .apply(Some("https://a.test"), &mut response);
+        assert_eq!(header(&response, "Access-Control-Allow-Origin"), Some("*"));
+        assert_eq!(header(&response, "Vary"), None);
+        let mut response = hi();
+        Cors::new()
+            .allow_any_origin()
+            .allow_credentials(true)
+            .apply(Some("https://a.test"), &mut response);
+        assert_eq!(
+            header(&response, "Access-Control-Allow-Origin"),
+            Some("https://a.test")
+        );
+    }
+}
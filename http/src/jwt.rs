@@ -0,0 +1,600 @@
+use crate::{AuthError, Principal, TokenValidator};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use ring::{
+    hmac,
+    signature::{self, RsaPublicKeyComponents, UnparsedPublicKey},
+};
+use serde::{de::DeserializeOwned, Deserialize};
+use serde_json::{Map, Value};
+use std::{
+    fmt, fs, io,
+    path::Path,
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+/// The signature algorithms tokens may be signed with
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+pub enum JwtAlgorithm {
+    HS256,
+    RS256,
+    ES256,
+}
+
+enum KeyMaterial {
+    Hmac(hmac::Key),
+    Rsa(RsaPublicKeyComponents<Vec<u8>>),
+    /// An uncompressed P-256 point
+    Ec(Vec<u8>),
+}
+
+struct Jwk {
+    kid: Option<String>,
+    alg: JwtAlgorithm,
+    key: KeyMaterial,
+}
+
+impl Jwk {
+    fn verify(&self, message: &[u8], signature: &[u8]) -> bool {
+        match &self.key {
+            KeyMaterial::Hmac(key) => hmac::verify(key, message, signature).is_ok(),
+            KeyMaterial::Rsa(key) => key
+                .verify(&signature::RSA_PKCS1_2048_8192_SHA256, message, signature)
+                .is_ok(),
+            KeyMaterial::Ec(point) => {
+                UnparsedPublicKey::new(&signature::ECDSA_P256_SHA256_FIXED, point)
+                    .verify(message, signature)
+                    .is_ok()
+            }
+        }
+    }
+}
+
+/// A key as written in a JWKS file. Only the fields needed for the supported algorithms are read
+#[derive(Deserialize)]
+struct RawJwk {
+    kty: String,
+    kid: Option<String>,
+    alg: Option<JwtAlgorithm>,
+    k: Option<String>,
+    n: Option<String>,
+    e: Option<String>,
+    crv: Option<String>,
+    x: Option<String>,
+    y: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct RawJwks {
+    keys: Vec<RawJwk>,
+}
+
+/// The keys tokens are verified against, loaded from a JSON Web Key Set file (RFC 7517).
+/// Supports `oct` keys for HS256, `RSA` keys for RS256 and `EC` keys on P-256 for ES256.
+pub struct Jwks(Vec<Jwk>);
+
+impl fmt::Debug for Jwks {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_list()
+            .entries(self.0.iter().map(|key| (&key.kid, key.alg)))
+            .finish()
+    }
+}
+
+impl Jwks {
+    pub fn load(path: &Path) -> io::Result<Self> {
+        Self::parse(&fs::read_to_string(path)?)
+    }
+
+    /// Parses a key set from its JSON. Fails on keys of unsupported types, as silently skipping them would reject every token signed with them
+    pub fn parse(json: &str) -> io::Result<Self> {
+        let invalid = |err: String| io::Error::new(io::ErrorKind::InvalidData, err);
+        let raw: RawJwks =
+            serde_json::from_str(json).map_err(|err| invalid(format!("Invalid JWKS => {err}")))?;
+        raw.keys
+            .into_iter()
+            .map(|raw| {
+                let field = |value: Option<String>, name: &str| {
+                    value
+                        .ok_or_else(|| format!("Missing {name}"))
+                        .and_then(|value| {
+                            URL_SAFE_NO_PAD
+                                .decode(value)
+                                .map_err(|err| format!("Invalid {name} => {err}"))
+                        })
+                };
+                let (alg, key) = match raw.kty.as_str() {
+                    "oct" => (
+                        JwtAlgorithm::HS256,
+                        KeyMaterial::Hmac(hmac::Key::new(hmac::HMAC_SHA256, &field(raw.k, "k")?)),
+                    ),
+                    "RSA" => (
+                        JwtAlgorithm::RS256,
+                        KeyMaterial::Rsa(RsaPublicKeyComponents {
+                            n: field(raw.n, "n")?,
+                            e: field(raw.e, "e")?,
+                        }),
+                    ),
+                    "EC" if raw.crv.as_deref() == Some("P-256") => (
+                        JwtAlgorithm::ES256,
+                        KeyMaterial::Ec([vec![4], field(raw.x, "x")?, field(raw.y, "y")?].concat()),
+                    ),
+                    kty => return Err(format!("Unsupported key type {kty}")),
+                };
+                if raw.alg.is_some_and(|raw_alg| raw_alg != alg) {
+                    return Err(format!(
+                        "Key type {} can't be used with {:?}",
+                        raw.kty, raw.alg
+                    ));
+                }
+                Ok(Jwk {
+                    kid: raw.kid,
+                    alg,
+                    key,
+                })
+            })
+            .collect::<Result<_, _>>()
+            .map(Self)
+            .map_err(invalid)
+    }
+}
+
+/// The claims of a verified token. The registered claims are parsed, everything else can be read with [`Claims::get`] or [`Claims::deserialize`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Claims {
+    pub sub: Option<String>,
+    pub iss: Option<String>,
+    /// The audiences, whether the token has one as a string or several as an array
+    pub aud: Vec<String>,
+    pub exp: Option<u64>,
+    pub nbf: Option<u64>,
+    /// When the token was issued. It is only parsed for routes that care about a token's age, [`JwtValidator`] doesn't check it
+    pub iat: Option<u64>,
+    /// From the space separated `scope` claim, or the `scp` array some issuers use instead
+    pub scopes: Vec<String>,
+    all: Map<String, Value>,
+}
+
+impl Claims {
+    /// Fails with the name of the first of `exp`, `nbf` or `iat` that is present but isn't a number
+    fn new(all: Map<String, Value>) -> Result<Self, &'static str> {
+        let string = |name: &str| all.get(name).and_then(Value::as_str).map(str::to_owned);
+        // NumericDates may have a fraction of a second, which is dropped. Dates before 1970 become 0
+        let number = |name: &'static str| match all.get(name) {
+            None => Ok(None),
+            Some(value) => value.as_f64().map(|secs| Some(secs as u64)).ok_or(name),
+        };
+        let strings = |value: Option<&Value>| match value {
+            Some(Value::String(value)) => vec![value.clone()],
+            Some(Value::Array(values)) => values
+                .iter()
+                .filter_map(Value::as_str)
+                .map(str::to_owned)
+                .collect(),
+            _ => Vec::new(),
+        };
+        Ok(Self {
+            sub: string("sub"),
+            iss: string("iss"),
+            aud: strings(all.get("aud")),
+            exp: number("exp")?,
+            nbf: number("nbf")?,
+            iat: number("iat")?,
+            scopes: match all.get("scope") {
+                Some(Value::String(scope)) => scope.split_whitespace().map(str::to_owned).collect(),
+                _ => strings(all.get("scp")),
+            },
+            all,
+        })
+    }
+
+    /// The claim called `name`, converted to `T`
+    pub fn get<T: DeserializeOwned>(&self, name: &str) -> Option<T> {
+        serde_json::from_value(self.all.get(name)?.clone()).ok()
+    }
+
+    /// All of the claims converted to `T`, typically a struct with a field per claim the route cares about
+    pub fn deserialize<T: DeserializeOwned>(&self) -> Option<T> {
+        serde_json::from_value(Value::Object(self.all.clone())).ok()
+    }
+}
+
+#[derive(Deserialize)]
+struct JwtHeader {
+    alg: String,
+    kid: Option<String>,
+}
+
+/// Validates JSON Web Tokens (RFC 7519) for [`crate::BearerAuth`]. For example:
+/// ```rust,no_run
+/// # use http::{AuthGuard, BearerAuth, Jwks, JwtValidator};
+/// # use std::{path::Path, sync::Arc};
+/// let jwks = Arc::new(Jwks::load(Path::new("jwks.json")).unwrap());
+/// let validator = JwtValidator::new(jwks)
+///     .issuer("https://auth.example.test")
+///     .audience("api")
+///     .require_scope("orders:write");
+/// let guard = AuthGuard::new(BearerAuth::new("api", validator));
+/// ```
+/// Tokens with a bad signature, that have expired, aren't valid yet or are for another issuer or audience are rejected with an `HTTP 401`.
+/// Valid tokens missing a required scope get an `HTTP 403`. The principal's name is the `sub` claim, and all claims are at [`Principal::claims`].
+#[derive(Debug, Clone)]
+pub struct JwtValidator {
+    keys: Arc<Jwks>,
+    issuer: Option<String>,
+    audience: Option<String>,
+    leeway: Duration,
+    scopes: Vec<String>,
+}
+
+impl JwtValidator {
+    /// Accepts any token signed by one of `keys`, allowing for 60 seconds of clock skew
+    pub fn new(keys: Arc<Jwks>) -> Self {
+        Self {
+            keys,
+            issuer: None,
+            audience: None,
+            leeway: Duration::from_secs(60),
+            scopes: Vec::new(),
+        }
+    }
+
+    /// Only accepts tokens whose `iss` is `issuer`
+    pub fn issuer(mut self, issuer: &str) -> Self {
+        self.issuer = Some(issuer.to_owned());
+        self
+    }
+
+    /// Only accepts tokens with `audience` among their `aud`
+    pub fn audience(mut self, audience: &str) -> Self {
+        self.audience = Some(audience.to_owned());
+        self
+    }
+
+    /// How far the issuer's clock may be off from ours when checking `exp` and `nbf`
+    pub fn leeway(mut self, leeway: Duration) -> Self {
+        self.leeway = leeway;
+        self
+    }
+
+    /// Only lets through tokens that have `scope`. May be called several times to require several scopes.
+    pub fn require_scope(mut self, scope: &str) -> Self {
+        self.scopes.push(scope.to_owned());
+        self
+    }
+
+    /// Verifies the token's signature and claims, returning its claims
+    pub fn verify(&self, token: &str) -> Result<Claims, AuthError> {
+        let invalid = |reason: &str| AuthError::Invalid(reason.to_owned());
+        let mut parts = token.split('.');
+        let (Some(header), Some(payload), Some(signature), None) =
+            (parts.next(), parts.next(), parts.next(), parts.next())
+        else {
+            return Err(invalid("Malformed token"));
+        };
+        let decode = |part: &str| {
+            URL_SAFE_NO_PAD
+                .decode(part)
+                .map_err(|_| invalid("Malformed token"))
+        };
+
+        let header: JwtHeader =
+            serde_json::from_slice(&decode(header)?).map_err(|_| invalid("Malformed header"))?;
+        // the algorithm comes from the token, so it is only used to pick among keys meant for it. This stops "none" and HS256 signed with a public key
+        let alg: JwtAlgorithm = serde_json::from_value(Value::String(header.alg))
+            .map_err(|_| invalid("Unsupported algorithm"))?;
+        let message = &token[..header_payload_len(token)];
+        let signature = decode(signature)?;
+        let verified = self
+            .keys
+            .0
+            .iter()
+            .filter(|key| key.alg == alg)
+            .filter(|key| header.kid.is_none() || key.kid == header.kid)
+            .any(|key| key.verify(message.as_bytes(), &signature));
+        if !verified {
+            return Err(invalid("Bad signature"));
+        }
+
+        let claims = match serde_json::from_slice(&decode(payload)?) {
+            Ok(Value::Object(claims)) => Claims::new(claims)
+                .map_err(|claim| AuthError::Invalid(format!("Malformed {claim} claim")))?,
+            _ => return Err(invalid("Malformed claims")),
+        };
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let leeway = self.leeway.as_secs();
+        if claims
+            .exp
+            .is_some_and(|exp| now > exp.saturating_add(leeway))
+        {
+            return Err(invalid("Token expired"));
+        }
+        if claims
+            .nbf
+            .is_some_and(|nbf| now.saturating_add(leeway) < nbf)
+        {
+            return Err(invalid("Token not valid yet"));
+        }
+        if self.issuer.is_some() && claims.iss != self.issuer {
+            return Err(invalid("Wrong issuer"));
+        }
+        if let Some(audience) = &self.audience {
+            if !claims.aud.contains(audience) {
+                return Err(invalid("Wrong audience"));
+            }
+        }
+        if let Some(missing) = self
+            .scopes
+            .iter()
+            .find(|scope| !claims.scopes.contains(scope))
+        {
+            return Err(AuthError::Forbidden(format!("Missing scope {missing}")));
+        }
+        Ok(claims)
+    }
+}
+
+/// Length of the `header.payload` part of a token, which is what the signature covers
+fn header_payload_len(token: &str) -> usize {
+    token.rfind('.').unwrap_or_default()
+}
+
+impl TokenValidator for JwtValidator {
+    fn validate(&self, token: &str) -> Result<Principal, AuthError> {
+        let claims = self.verify(token)?;
+        Ok(Principal {
+            name: claims.sub.clone().unwrap_or_default(),
+            claims: Some(claims),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ring::{
+        rand::SystemRandom,
+        signature::{
+            EcdsaKeyPair, KeyPair, RsaKeyPair, ECDSA_P256_SHA256_FIXED_SIGNING, RSA_PKCS1_SHA256,
+        },
+    };
+    use serde_json::json;
+
+    fn now() -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+    }
+
+    fn token(alg: &str, claims: Value, sign: impl Fn(&[u8]) -> Vec<u8>) -> String {
+        let encode = |value: Value| URL_SAFE_NO_PAD.encode(value.to_string());
+        let message = format!(
+            "{}.{}",
+            encode(json!({ "alg": alg, "typ": "JWT" })),
+            encode(claims)
+        );
+        let signature = URL_SAFE_NO_PAD.encode(sign(message.as_bytes()));
+        format!("{message}.{signature}")
+    }
+
+    #[test]
+    fn validates_hs256_claims() {
+        let secret = b"0123456789abcdef0123456789abcdef";
+        let jwks = Jwks::parse(
+            &json!({ "keys": [{ "kty": "oct", "kid": "1", "k": URL_SAFE_NO_PAD.encode(secret) }] })
+                .to_string(),
+        )
+        .unwrap();
+        let validator = JwtValidator::new(Arc::new(jwks))
+            .issuer("issuer")
+            .audience("api")
+            .require_scope("read");
+        let key = hmac::Key::new(hmac::HMAC_SHA256, secret);
+        let hs256 = |claims| {
+            token("HS256", claims, |message| {
+                hmac::sign(&key, message).as_ref().to_vec()
+            })
+        };
+
+        let claims = validator
+            .verify(&hs256(json!({
+                "sub": "alice", "iss": "issuer", "aud": ["api", "web"],
+                "exp": now() + 60, "scope": "read write", "tenant": 7
+            })))
+            .unwrap();
+        assert_eq!(claims.sub.as_deref(), Some("alice"));
+        assert_eq!(claims.get::<u32>("tenant"), Some(7));
+
+        // within the leeway, expired tokens are still fine
+        assert!(validator
+            .verify(&hs256(
+                json!({ "iss": "issuer", "aud": "api", "exp": now() - 30, "scope": "read" })
+            ))
+            .is_ok());
+        // NumericDates may have a fraction of a second
+        assert!(validator
+            .verify(&hs256(
+                json!({ "iss": "issuer", "aud": "api", "exp": now() as f64 + 60.5, "scope": "read" })
+            ))
+            .is_ok());
+        for (claims, reason) in [
+            (
+                json!({ "iss": "issuer", "aud": "api", "exp": now() as f64 - 120.5, "scope": "read" }),
+                "Token expired",
+            ),
+            (
+                json!({ "iss": "issuer", "aud": "api", "exp": "tomorrow", "scope": "read" }),
+                "Malformed exp claim",
+            ),
+            (
+                json!({ "iss": "issuer", "aud": "api", "nbf": null, "scope": "read" }),
+                "Malformed nbf claim",
+            ),
+            (
+                json!({ "iss": "issuer", "aud": "api", "exp": now() - 120, "scope": "read" }),
+                "Token expired",
+            ),
+            (
+                json!({ "iss": "issuer", "aud": "api", "nbf": now() + 120, "scope": "read" }),
+                "Token not valid yet",
+            ),
+            (
+                json!({ "iss": "other", "aud": "api", "scope": "read" }),
+                "Wrong issuer",
+            ),
+            (
+                json!({ "iss": "issuer", "aud": "web", "scope": "read" }),
+                "Wrong audience",
+            ),
+        ] {
+            assert_eq!(
+                validator.verify(&hs256(claims)),
+                Err(AuthError::Invalid(reason.to_owned()))
+            );
+        }
+        assert_eq!(
+            validator.verify(&hs256(
+                json!({ "iss": "issuer", "aud": "api", "scp": ["write"] })
+            )),
+            Err(AuthError::Forbidden("Missing scope read".to_owned()))
+        );
+
+        let forged = token(
+            "HS256",
+            json!({ "iss": "issuer", "aud": "api", "scope": "read" }),
+            |message| {
+                hmac::sign(&hmac::Key::new(hmac::HMAC_SHA256, b"wrong"), message)
+                    .as_ref()
+                    .to_vec()
+            },
+        );
+        assert_eq!(
+            validator.verify(&forged),
+            Err(AuthError::Invalid("Bad signature".to_owned()))
+        );
+        let unsigned = token(
+            "none",
+            json!({ "iss": "issuer", "aud": "api", "scope": "read" }),
+            |_| Vec::new(),
+        );
+        assert!(validator.verify(&unsigned).is_err());
+    }
+
+    /// A 2048 bit RSA key in PKCS#8, as ring can't generate RSA keys. Made with `openssl genpkey -algorithm RSA` for these tests only
+    const RSA_PKCS8: &str = "
+MIIEvQIBADANBgkqhkiG9w0BAQEFAASCBKcwggSjAgEAAoIBAQDDQyd+XUZh8AiMdDz/suGIG5xu0pglOVu9cOkIdBSV4epa
+Dj77zLpe26oSVDKN1+NOCqEEDm2v019L9dGylUT4rEopOS3ffy9OHUNhzwZYHD4DDHzfiPXxzGQGP0ckWue/hFudfZYxj6bk
+EIpKpthirNSYgnbfMOUD0BW1LRr1omULljUTPR3FGw39MSCPWI6zXY5WccC7eO8igc9IgfNW/czd6eU71k74q0C6IBOkUBGt
+8wDw1bUSvaZgBGwVKXKVO3kvACoMTDQ0tim2jmKY9G+2WdekmH/dq3Dy9NJSJ5VcXGp0BhBqbRM0gnumGY+Jf9JM16D08WXM
+GobaQtepAgMBAAECggEAGA/q2mB784Z5BTbvS+rTLlrJs6H10BOCDcWv3+PvI/vBrYyXICA94MHk0sqnqrFdoGDAsB9njqzn
+b9TIfT92DwdrQap7T9EMZy3Fv5Q7ocjELwu/SSwnc79GXbs3RQCDUcdpo04mP7AGRtFPe2zVWk6WSb99DtrHhAlk3W1kJrv/
+3NCtKMNk5UlMf2z7MTTMZj8x4d5PvrvqAhruQCziHUefgBoiUh49xV8IBOnLM5ed1iUCM1pqFQHb1/txzyztU+f7lUE8cFRI
+4kPBIywsQF8aVrgChbbeSRRI8GSyJfSJ5s0TE44C+xdufWWQGB0XF7YiKa7SA3gO1rHKxgX1EQKBgQDpNbn2+JQualelIY8Q
+4Gq4f+RTJJdV4eMtBDt78HGdwPcS+YIMXPKDx6kWyATf624rACwpsTXenQhdsqki8LRo27xJXXRPoB/zgRuFJNqeDD4cVvxy
+BFl7uNYCy3ilpplx/UsPzBMz05sNPpNJWrqbINKeTs4gBKYwZpvG+mT5cQKBgQDWWBV7lMGhgm+Cx7QP7EwhzuRCuZ15QmYG
+VSFz+mMMyh51l0d9UVnFSLeYzv6OEfPjYb/G6I+nX9WMtPiNSUBiB5d96nF0R8iyf0YLAjrB+AMIdIIr3EdGC+SNxYx1TVqH
+o20Txj7dhV6IGpFt04eFqDh+YeEtV0OABbPUVnFluQKBgC+hOnb3dAQ60eW3Aeo3e3RtImvcuE/hD63dBG0ewYWvqIamkOoH
+fbxuZdlyRpSZF+oOYfat68uolNHWMHcjZCVaY400WhH9KSlpf/5kdYX8CB9voKWEv+Xl7Ueq2Nw0N3ixe8ghLPbTzAIbeqdr
+sVsHjByrCaw+ZtbRzhUIhODxAoGBAJJl0zZ3IdGIX1V5xPhjMpSc1LbmRDJAMaL9nj2ytNhVbHxwePmVCLWAtzV9m8u5Y1TY
+vL3EtelhwV+7c1A8yeaqvMPZzufW57NSzqpSOYOmO5wYx5fheiTTlqVmWkr8LGscKjIpY2K15x0a2F2k7plRjlRz0fK/+XCF
+dJABAq6xAoGANaes/03kEPadpCoBHFqiWK4cbiVWWVuU/+khD1dBAAt2FUUKzm4iyfFgbjh3MZXft0zln5mKNjPwmGAJXeTJ
+mMHQs9T3Ysi9i8xVMOwcqWRaFzfYMXOey/MUxaDmoqvXfoRomaOJkg3e79UWDOzcKiabH5Yoea6HfRO/C5CEqL8=
+";
+
+    #[test]
+    fn validates_rs256_signatures() {
+        let der = base64::engine::general_purpose::STANDARD
+            .decode(RSA_PKCS8.replace('\n', ""))
+            .unwrap();
+        let pair = RsaKeyPair::from_pkcs8(&der).unwrap();
+        let public = RsaPublicKeyComponents::<Vec<u8>>::from(pair.public());
+        let jwk = |alg: &str| {
+            json!({ "keys": [{
+                "kty": "RSA", "kid": "rsa", "alg": alg,
+                "n": URL_SAFE_NO_PAD.encode(&public.n),
+                "e": URL_SAFE_NO_PAD.encode(&public.e),
+            }] })
+            .to_string()
+        };
+        let validator = JwtValidator::new(Arc::new(Jwks::parse(&jwk("RS256")).unwrap()));
+        let rng = SystemRandom::new();
+        let rs256 = |alg, claims| {
+            token(alg, claims, |message| {
+                let mut signature = vec![0; pair.public().modulus_len()];
+                pair.sign(&RSA_PKCS1_SHA256, &rng, message, &mut signature)
+                    .unwrap();
+                signature
+            })
+        };
+
+        let claims = validator
+            .verify(&rs256(
+                "RS256",
+                json!({ "sub": "service", "iat": 1_700_000_000 }),
+            ))
+            .unwrap();
+        assert_eq!(claims.sub.as_deref(), Some("service"));
+        assert_eq!(claims.iat, Some(1_700_000_000));
+        // the signature of one token doesn't cover the claims of another
+        let signed = rs256("RS256", json!({ "sub": "service" }));
+        let other = rs256("RS256", json!({ "sub": "admin" }));
+        let tampered = format!(
+            "{}{}",
+            &other[..header_payload_len(&other)],
+            &signed[header_payload_len(&signed)..]
+        );
+        assert_eq!(
+            validator.verify(&tampered),
+            Err(AuthError::Invalid("Bad signature".to_owned()))
+        );
+        // an RSA key only checks tokens that say they are RS256
+        assert_eq!(
+            validator.verify(&rs256("ES256", json!({ "sub": "service" }))),
+            Err(AuthError::Invalid("Bad signature".to_owned()))
+        );
+        // nor may the public key be used as an HS256 secret
+        let confused = token("HS256", json!({ "sub": "service" }), |message| {
+            hmac::sign(&hmac::Key::new(hmac::HMAC_SHA256, &public.n), message)
+                .as_ref()
+                .to_vec()
+        });
+        assert!(validator.verify(&confused).is_err());
+        // and an RSA key can't be published for another algorithm
+        assert!(Jwks::parse(&jwk("HS256")).is_err());
+    }
+
+    #[test]
+    fn validates_es256_signatures() {
+        let rng = SystemRandom::new();
+        let pkcs8 = EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &rng).unwrap();
+        let pair = EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, pkcs8.as_ref(), &rng)
+            .unwrap();
+        let point = pair.public_key().as_ref();
+        let jwks = Jwks::parse(
+            &json!({ "keys": [{
+                "kty": "EC", "crv": "P-256", "alg": "ES256",
+                "x": URL_SAFE_NO_PAD.encode(&point[1..33]),
+                "y": URL_SAFE_NO_PAD.encode(&point[33..]),
+            }] })
+            .to_string(),
+        )
+        .unwrap();
+        let validator = JwtValidator::new(Arc::new(jwks));
+
+        let es256 = token("ES256", json!({ "sub": "service" }), |message| {
+            pair.sign(&rng, message).unwrap().as_ref().to_vec()
+        });
+        assert_eq!(validator.validate(&es256).unwrap().name, "service");
+        // a token claiming to be HS256 can't use the public key as a secret
+        let confused = token("HS256", json!({ "sub": "service" }), |message| {
+            hmac::sign(&hmac::Key::new(hmac::HMAC_SHA256, point), message)
+                .as_ref()
+                .to_vec()
+        });
+        assert!(validator.verify(&confused).is_err());
+    }
+}
@@ -1,19 +1,42 @@
+mod access_log;
+mod acl;
+mod auth;
+mod connection;
+mod cookie;
+mod cookie_keys;
+mod cors;
+mod date;
+mod jwt;
+mod limits;
+mod metrics;
+mod proxy;
+mod rate_limit;
 mod request;
+mod request_id;
 mod response;
 mod route;
+mod session;
 
-pub use request::{DeconstructedHTTPRequest, HTTPRequest, HTTPRequestHeader};
-pub use response::{http_err, http_ok, HTTPResponses, HTTPResult, Response};
-pub use route::Router;
+pub use access_log::{AccessLog, AccessLogEntry, AccessLogFormat};
+pub use acl::{AclAction, IpAcl};
+pub use auth::{
+    AuthError, AuthGuard, Authenticator, Authorization, BasicAuth, BearerAuth, Principal,
+    TokenValidator,
+};
+pub use connection::{ClientCertPolicy, ClientCertificate, ConnectionInfo, ProxyAddresses};
+pub use cookie::{Cookie, CookieJar, SameSite};
+pub use cookie_keys::{CookieKeyError, CookieKeys, MIN_SECRET_LEN};
+pub use cors::Cors;
+pub use jwt::{Claims, Jwks, JwtAlgorithm, JwtValidator};
+pub use limits::{LimitError, Limits};
+pub use metrics::{GaugeGuard, Metrics};
+pub use proxy::{Cidr, ClientInfo, TrustedProxies};
+pub use rate_limit::{client_ip, KeyExtractor, RateLimit};
+pub use request::{DeconstructedHTTPRequest, ForwardingHeaders, HTTPRequest, HTTPRequestHeader};
+pub use request_id::{
+    generate_request_id, valid_request_id, MAX_REQUEST_ID_LEN, REQUEST_ID_HEADER,
+};
+pub use response::{http_err, http_ok, HTTPResponses, HTTPResult, RawHTTPResponse, Response};
+pub use route::{panic_message, RouteOptions, Router};
+pub use session::{FileStore, MemoryStore, Session, SessionRecord, SessionStore, Sessions};
 pub use HTTPResponses::*;
-
-#[macro_export]
-macro_rules! debg {
-    ($code:expr) => {
-        if cfg!(debug_assertions) {
-            dbg!($code)
-        } else {
-            $code
-        }
-    };
-}
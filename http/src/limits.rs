@@ -0,0 +1,141 @@
+use super::HTTPResponses;
+
+/// Upper bounds on the size of an incoming request. These are checked against the raw bytes read from the socket,
+/// so a client can never make the server allocate more than these values no matter what its headers claim.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Limits {
+    /// Longest request line (e.g `GET /path HTTP/1.1`) accepted, excluding the trailing `\r\n`. Violations return `414`.
+    pub max_request_line: usize,
+    /// Largest header block accepted, from the start of the request up to and including the blank line. Violations return `431`.
+    pub max_header_bytes: usize,
+    /// Maximum number of header fields accepted. Violations return `431`.
+    pub max_header_count: usize,
+    /// Largest body accepted, as declared by `Content-Length`. Violations return `413`. May be overridden per route.
+    pub max_body: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            max_request_line: 8 * 1024,
+            max_header_bytes: 16 * 1024,
+            max_header_count: 100,
+            max_body: 10 * 1024 * 1024,
+        }
+    }
+}
+
+/// The limit that a request broke. Converts into the HTTP error response that should be sent back to the client.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LimitError {
+    RequestLineTooLong,
+    HeadersTooLarge,
+    TooManyHeaders,
+    BodyTooLarge,
+}
+
+impl Limits {
+    /// Checks the start of a request against the request line, header block and header count limits.
+    /// `head` may be incomplete (the blank line has not been received yet), in which case only what is present is checked.
+    pub fn check_head(&self, head: &[u8]) -> Result<(), LimitError> {
+        let mut lines = head.split(|&b| b == b'\n');
+
+        let request_line = lines.next().unwrap_or_default();
+        if request_line
+            .strip_suffix(b"\r")
+            .unwrap_or(request_line)
+            .len()
+            > self.max_request_line
+        {
+            return Err(LimitError::RequestLineTooLong);
+        }
+
+        if head.len() > self.max_header_bytes {
+            return Err(LimitError::HeadersTooLarge);
+        }
+
+        // Every non empty line after the request line is a header field
+        if lines.filter(|line| !matches!(line, [] | [b'\r'])).count() > self.max_header_count {
+            return Err(LimitError::TooManyHeaders);
+        }
+        Ok(())
+    }
+
+    /// Checks a declared body length against `max_body`. This must be called before any buffer is sized from `content_length`.
+    pub fn check_body(&self, content_length: usize) -> Result<(), LimitError> {
+        if content_length > self.max_body {
+            Err(LimitError::BodyTooLarge)
+        } else {
+            Ok(())
+        }
+    }
+}
+
+impl LimitError {
+    /// A short snake case name for the limit, e.g. for [`crate::Metrics::parse_error`]
+    pub fn kind(&self) -> &'static str {
+        match self {
+            LimitError::RequestLineTooLong => "request_line_too_long",
+            LimitError::HeadersTooLarge => "headers_too_large",
+            LimitError::TooManyHeaders => "too_many_headers",
+            LimitError::BodyTooLarge => "body_too_large",
+        }
+    }
+}
+
+impl From<LimitError> for Box<HTTPResponses> {
+    fn from(value: LimitError) -> Self {
+        match value {
+            LimitError::RequestLineTooLong => HTTPResponses::uri_too_long(),
+            LimitError::HeadersTooLarge | LimitError::TooManyHeaders => {
+                HTTPResponses::header_fields_too_large()
+            }
+            LimitError::BodyTooLarge => HTTPResponses::payload_too_large(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn small_limits() -> Limits {
+        Limits {
+            max_request_line: 16,
+            max_header_bytes: 64,
+            max_header_count: 2,
+            max_body: 10,
+        }
+    }
+
+    #[test]
+    fn accepts_request_within_limits() {
+        let head = b"GET / HTTP/1.1\r\nHost: a\r\nAccept: */*\r\n\r\n";
+        assert_eq!(small_limits().check_head(head), Ok(()));
+        assert_eq!(small_limits().check_body(10), Ok(()));
+    }
+
+    #[test]
+    fn rejects_each_limit() {
+        let limits = small_limits();
+        assert_eq!(
+            limits.check_head(b"GET /a/very/long/path HTTP/1.1\r\n\r\n"),
+            Err(LimitError::RequestLineTooLong)
+        );
+        // An unterminated request line is still measured
+        assert_eq!(
+            limits.check_head(b"GET /a/very/long/pa"),
+            Err(LimitError::RequestLineTooLong)
+        );
+        assert_eq!(
+            limits.check_head(b"GET / HTTP/1.1\r\nA: 1\r\nB: 2\r\nC: 3\r\n\r\n"),
+            Err(LimitError::TooManyHeaders)
+        );
+        assert_eq!(
+            limits
+                .check_head(format!("GET / HTTP/1.1\r\nA: {}\r\n\r\n", "x".repeat(64)).as_bytes()),
+            Err(LimitError::HeadersTooLarge)
+        );
+        assert_eq!(limits.check_body(11), Err(LimitError::BodyTooLarge));
+    }
+}
@@ -0,0 +1,283 @@
+use std::{
+    collections::BTreeMap,
+    fmt::Write as _,
+    sync::{
+        atomic::{AtomicI64, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+/// Upper bounds, in seconds, of the request latency histogram's buckets
+const LATENCY_BUCKETS: [f64; 11] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+/// Methods counted under their own name. Anything else a client sends is counted as `OTHER`, so made up methods can't grow the metrics without bound
+const METHODS: [&str; 9] = [
+    "GET", "HEAD", "POST", "PUT", "DELETE", "CONNECT", "OPTIONS", "TRACE", "PATCH",
+];
+
+/// The route label of requests no route matched
+const UNMATCHED: &str = "unmatched";
+
+#[derive(Debug, Default)]
+struct Histogram {
+    /// How many observations fell in each bucket, not counting those of smaller buckets
+    buckets: [u64; LATENCY_BUCKETS.len()],
+    sum: f64,
+    count: u64,
+}
+
+#[derive(Debug, Default)]
+struct Counts {
+    /// By route pattern, method and status
+    requests: BTreeMap<(String, &'static str, u16), u64>,
+    /// By route pattern and method
+    latencies: BTreeMap<(String, &'static str), Histogram>,
+    parse_errors: BTreeMap<&'static str, u64>,
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    counts: Mutex<Counts>,
+    in_flight: Arc<AtomicI64>,
+    connections: Arc<AtomicI64>,
+    received: AtomicU64,
+    sent: AtomicU64,
+}
+
+/// Counts what the server does, for Prometheus to scrape in its text format from the path given to [`crate::Router::with_metrics`]. For example:
+/// ```rust
+/// # use http::{Metrics, Router};
+/// let router = Router::new().with_metrics(Metrics::new(), "/metrics");
+/// ```
+/// The router counts the requests it answers, by route pattern, method and status, times them and tracks how many are in flight.
+/// Connections, bytes and requests that couldn't be parsed are only seen by the server, which records them with [`Metrics::connection_opened`], [`Metrics::received`], [`Metrics::sent`] and [`Metrics::parse_error`].
+/// Clones share their counts.
+#[derive(Debug, Clone, Default)]
+pub struct Metrics(Arc<Inner>);
+
+/// Counts one towards a gauge until it is dropped
+#[derive(Debug)]
+#[must_use = "the gauge goes back down as soon as the guard is dropped"]
+pub struct GaugeGuard(Arc<AtomicI64>);
+
+impl GaugeGuard {
+    fn new(gauge: &Arc<AtomicI64>) -> Self {
+        gauge.fetch_add(1, Ordering::Relaxed);
+        Self(Arc::clone(gauge))
+    }
+}
+
+impl Drop for GaugeGuard {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Counts a connection as open until the guard is dropped
+    pub fn connection_opened(&self) -> GaugeGuard {
+        GaugeGuard::new(&self.0.connections)
+    }
+
+    /// Counts a request as in flight until the guard is dropped
+    pub(crate) fn request_started(&self) -> GaugeGuard {
+        GaugeGuard::new(&self.0.in_flight)
+    }
+
+    /// Adds bytes read from a client
+    pub fn received(&self, bytes: usize) {
+        self.0.received.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    /// Adds bytes written to a client
+    pub fn sent(&self, bytes: usize) {
+        self.0.sent.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    /// Counts a request that couldn't be read, by what went wrong, e.g. `malformed` or `header_timeout`
+    pub fn parse_error(&self, kind: &'static str) {
+        *self.counts().parse_errors.entry(kind).or_default() += 1;
+    }
+
+    /// Counts an answered request. `route` is the pattern of the route that answered it, `None` if no route matched
+    pub(crate) fn observe(
+        &self,
+        route: Option<&str>,
+        method: &str,
+        status: u16,
+        latency: Duration,
+    ) {
+        let route = route.unwrap_or(UNMATCHED).to_owned();
+        let method = METHODS
+            .into_iter()
+            .find(|known| *known == method)
+            .unwrap_or("OTHER");
+        let mut counts = self.counts();
+        *counts
+            .requests
+            .entry((route.clone(), method, status))
+            .or_default() += 1;
+
+        let histogram = counts.latencies.entry((route, method)).or_default();
+        let secs = latency.as_secs_f64();
+        if let Some(bucket) = LATENCY_BUCKETS.iter().position(|bound| secs <= *bound) {
+            histogram.buckets[bucket] += 1;
+        }
+        histogram.sum += secs;
+        histogram.count += 1;
+    }
+
+    fn counts(&self) -> std::sync::MutexGuard<'_, Counts> {
+        self.0.counts.lock().unwrap_or_else(|err| err.into_inner())
+    }
+
+    /// Everything counted so far, in the Prometheus text exposition format
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        let counts = self.counts();
+
+        family(
+            &mut out,
+            "http_requests_total",
+            "counter",
+            "Requests answered by the router, by route pattern, method and status.",
+        );
+        for ((route, method, status), count) in &counts.requests {
+            let _ = writeln!(
+                out,
+                "http_requests_total{{route=\"{}\",method=\"{method}\",status=\"{status}\"}} {count}",
+                escape(route)
+            );
+        }
+
+        family(
+            &mut out,
+            "http_request_duration_seconds",
+            "histogram",
+            "How long the router took to answer requests, by route pattern and method.",
+        );
+        for ((route, method), histogram) in &counts.latencies {
+            let labels = format!("route=\"{}\",method=\"{method}\"", escape(route));
+            let mut cumulative = 0;
+            for (bound, count) in LATENCY_BUCKETS.iter().zip(histogram.buckets) {
+                cumulative += count;
+                let _ = writeln!(
+                    out,
+                    "http_request_duration_seconds_bucket{{{labels},le=\"{bound}\"}} {cumulative}"
+                );
+            }
+            let _ = writeln!(
+                out,
+                "http_request_duration_seconds_bucket{{{labels},le=\"+Inf\"}} {}\n\
+                http_request_duration_seconds_sum{{{labels}}} {}\n\
+                http_request_duration_seconds_count{{{labels}}} {}",
+                histogram.count, histogram.sum, histogram.count
+            );
+        }
+
+        family(
+            &mut out,
+            "http_parse_errors_total",
+            "counter",
+            "Requests that couldn't be read, by what went wrong.",
+        );
+        for (kind, count) in &counts.parse_errors {
+            let _ = writeln!(out, "http_parse_errors_total{{kind=\"{kind}\"}} {count}");
+        }
+        drop(counts);
+
+        for (name, kind, help, value) in [
+            (
+                "http_requests_in_flight",
+                "gauge",
+                "Requests the router is answering right now.",
+                self.0.in_flight.load(Ordering::Relaxed).to_string(),
+            ),
+            (
+                "http_open_connections",
+                "gauge",
+                "Client connections open right now.",
+                self.0.connections.load(Ordering::Relaxed).to_string(),
+            ),
+            (
+                "http_received_bytes_total",
+                "counter",
+                "Bytes read from clients.",
+                self.0.received.load(Ordering::Relaxed).to_string(),
+            ),
+            (
+                "http_sent_bytes_total",
+                "counter",
+                "Bytes written to clients.",
+                self.0.sent.load(Ordering::Relaxed).to_string(),
+            ),
+        ] {
+            family(&mut out, name, kind, help);
+            let _ = writeln!(out, "{name} {value}");
+        }
+        out
+    }
+}
+
+/// Writes the `HELP` and `TYPE` lines that start a metric family
+fn family(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {name} {help}\n# TYPE {name} {kind}");
+}
+
+/// Escapes a label value, which may hold anything, e.g. a route's regex
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn renders_what_was_counted() {
+        let metrics = Metrics::new();
+        let shared = metrics.clone();
+        metrics.observe(Some("/users/\\d+"), "GET", 200, Duration::from_millis(30));
+        metrics.observe(Some("/users/\\d+"), "GET", 200, Duration::from_secs(20));
+        metrics.observe(None, "BREW", 404, Duration::from_millis(1));
+        metrics.parse_error("malformed");
+        metrics.received(100);
+        metrics.sent(250);
+        let connection = metrics.connection_opened();
+        let in_flight = metrics.request_started();
+
+        let rendered = shared.render();
+        for line in [
+            "http_requests_total{route=\"/users/\\\\d+\",method=\"GET\",status=\"200\"} 2",
+            "http_requests_total{route=\"unmatched\",method=\"OTHER\",status=\"404\"} 1",
+            "http_request_duration_seconds_bucket{route=\"/users/\\\\d+\",method=\"GET\",le=\"0.025\"} 0",
+            "http_request_duration_seconds_bucket{route=\"/users/\\\\d+\",method=\"GET\",le=\"0.05\"} 1",
+            "http_request_duration_seconds_bucket{route=\"/users/\\\\d+\",method=\"GET\",le=\"10\"} 1",
+            "http_request_duration_seconds_bucket{route=\"/users/\\\\d+\",method=\"GET\",le=\"+Inf\"} 2",
+            "http_request_duration_seconds_count{route=\"/users/\\\\d+\",method=\"GET\"} 2",
+            "http_parse_errors_total{kind=\"malformed\"} 1",
+            "http_requests_in_flight 1",
+            "http_open_connections 1",
+            "http_received_bytes_total 100",
+            "http_sent_bytes_total 250",
+            "# TYPE http_request_duration_seconds histogram",
+        ] {
+            assert!(rendered.lines().any(|l| l == line), "{line} in\n{rendered}");
+        }
+
+        drop((connection, in_flight));
+        let rendered = metrics.render();
+        assert!(rendered.contains("http_requests_in_flight 0\n"));
+        assert!(rendered.contains("http_open_connections 0\n"));
+    }
+}
@@ -0,0 +1,314 @@
+use crate::HTTPRequestHeader;
+use std::{
+    fmt,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    str::FromStr,
+};
+
+/// A block of IP addresses, e.g. `10.0.0.0/8` or `2001:db8::/32`. A single address is a block of one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cidr {
+    network: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        // IPv4 clients on a dual stack socket show up as IPv4 mapped IPv6 addresses
+        let ip = match ip {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(ip, IpAddr::V4),
+            ip => ip,
+        };
+        match (self.network, ip) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                let mask = u32::MAX
+                    .checked_shl(32 - u32::from(self.prefix))
+                    .unwrap_or(0);
+                u32::from(network) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                let mask = u128::MAX
+                    .checked_shl(128 - u32::from(self.prefix))
+                    .unwrap_or(0);
+                u128::from(network) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (network, prefix) = s.trim().split_once('/').unwrap_or((s.trim(), ""));
+        let network: IpAddr = network
+            .parse()
+            .map_err(|err| format!("Invalid address in {s:?} => {err}"))?;
+        let max = if network.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            "" => max,
+            prefix => prefix
+                .parse()
+                .ok()
+                .filter(|prefix| *prefix <= max)
+                .ok_or_else(|| format!("Invalid prefix length in {s:?}"))?,
+        };
+        Ok(Self { network, prefix })
+    }
+}
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.network, self.prefix)
+    }
+}
+
+/// Who a request really came from, at [`HTTPRequestHeader::client`]. Filled in by the router, looking through [`TrustedProxies`] set with [`crate::Router::with_trusted_proxies`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ClientInfo {
+    /// The client's IP address. The peer's address unless it is a trusted proxy
+    pub ip: Option<IpAddr>,
+    /// `http` or `https`, as the client sees it
+    pub scheme: String,
+    /// The host the client asked for, with its port if it gave one
+    pub host: Option<String>,
+}
+
+impl ClientInfo {
+    /// The absolute URL of `path` as the client sees it, e.g. to redirect to, or `None` if the host isn't known
+    pub fn url(&self, path: &str) -> Option<String> {
+        Some(format!("{}://{}{path}", self.scheme, self.host.as_ref()?))
+    }
+}
+
+/// One proxy's record of a request it passed on
+#[derive(Debug, Default)]
+struct Hop {
+    /// `None` for hops the proxy kept hidden or didn't know, e.g. `for=unknown`
+    ip: Option<IpAddr>,
+    proto: Option<String>,
+    host: Option<String>,
+}
+
+/// The proxies whose `Forwarded`, or `X-Forwarded-For`, `X-Forwarded-Proto` and `X-Forwarded-Host`, headers are believed. For example:
+/// ```rust
+/// # use http::{Router, TrustedProxies};
+/// let router = Router::new()
+///     .with_trusted_proxies(TrustedProxies::new(vec!["10.0.0.0/8".parse().unwrap()]));
+/// ```
+/// The headers are read from right to left, as each proxy appends to them, until an address that isn't trusted is found. That is the client, as anything further left could have been made up by it.
+/// Requests from peers that aren't trusted keep the peer's address, whatever their headers say. `Forwarded` is used over the `X-Forwarded-*` headers if a request has both.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TrustedProxies(Vec<Cidr>);
+
+impl TrustedProxies {
+    pub fn new(proxies: Vec<Cidr>) -> Self {
+        Self(proxies)
+    }
+
+    fn trusts(&self, ip: IpAddr) -> bool {
+        self.0.iter().any(|cidr| cidr.contains(ip))
+    }
+
+    /// Works out who the request came from
+    pub(crate) fn resolve(&self, header: &HTTPRequestHeader) -> ClientInfo {
+        // a PROXY protocol header from the load balancer stands in for the peer
+        let peer = match header.connection.proxy {
+            Some(proxy) => Some(proxy.source.ip()),
+            None => header.connection.peer.map(|peer| peer.ip()),
+        };
+        let mut client = ClientInfo {
+            ip: peer,
+            scheme: if header.connection.tls {
+                "https"
+            } else {
+                "http"
+            }
+            .to_owned(),
+            host: header.authority.clone(),
+        };
+        if !peer.is_some_and(|peer| self.trusts(peer)) {
+            return client;
+        }
+
+        // each hop was added by the proxy to its right, which has already been found trustworthy
+        for hop in hops(header).iter().rev() {
+            if let Some(proto) = hop.proto.as_deref().and_then(scheme) {
+                client.scheme = proto.to_owned();
+            }
+            if let Some(host) = hop.host.as_deref().filter(|host| valid_host(host)) {
+                client.host = Some(host.to_owned());
+            }
+            match hop.ip {
+                Some(ip) => {
+                    client.ip = Some(ip);
+                    if !self.trusts(ip) {
+                        break;
+                    }
+                }
+                // nothing further can be known, so the last proxy seen is as close as it gets
+                None => break,
+            }
+        }
+        client
+    }
+}
+
+/// The hops recorded in the request's forwarding headers, from the client to the last proxy
+fn hops(header: &HTTPRequestHeader) -> Vec<Hop> {
+    let forwarding = &header.forwarding;
+    if let Some(forwarded) = &forwarding.forwarded {
+        return forwarded
+            .split(',')
+            .map(|element| {
+                let mut hop = Hop::default();
+                for (name, value) in element.split(';').filter_map(|pair| pair.split_once('=')) {
+                    let value = value.trim().trim_matches('"');
+                    match name.trim().to_ascii_lowercase().as_str() {
+                        "for" => hop.ip = node_ip(value),
+                        "proto" => hop.proto = Some(value.to_owned()),
+                        "host" => hop.host = Some(value.to_owned()),
+                        _ => {}
+                    }
+                }
+                hop
+            })
+            .collect();
+    }
+
+    let list = |value: &Option<String>| -> Vec<String> {
+        value
+            .iter()
+            .flat_map(|value| value.split(','))
+            .map(|value| value.trim().to_owned())
+            .collect()
+    };
+    let (ips, protos, hosts) = (
+        list(&forwarding.x_forwarded_for),
+        list(&forwarding.x_forwarded_proto),
+        list(&forwarding.x_forwarded_host),
+    );
+    // a proxy may only say which scheme or host it was asked for, with no addresses
+    let count = ips.len().max(1);
+    // the proto and host lists line up with the addresses if every proxy appended to them.
+    // Otherwise only the last value is known to come from the nearest proxy, so it goes with the last hop
+    let aligned = |values: &[String], i: usize| {
+        if values.len() == count {
+            values.get(i).cloned()
+        } else {
+            values.last().filter(|_| i == count - 1).cloned()
+        }
+    };
+    (0..count)
+        .map(|i| Hop {
+            ip: ips.get(i).and_then(|ip| node_ip(ip)),
+            proto: aligned(&protos, i),
+            host: aligned(&hosts, i),
+        })
+        .collect()
+}
+
+/// The address of a node as written in `Forwarded` or `X-Forwarded-For`, e.g. `192.0.2.43`, `192.0.2.43:47011`, `[2001:db8::1]:4711` or `2001:db8::1`
+fn node_ip(node: &str) -> Option<IpAddr> {
+    if let Some(bracketed) = node.strip_prefix('[') {
+        return bracketed
+            .split(']')
+            .next()?
+            .parse::<Ipv6Addr>()
+            .ok()
+            .map(IpAddr::V6);
+    }
+    node.parse().ok().or_else(|| {
+        let (ip, _port) = node.split_once(':')?;
+        ip.parse::<Ipv4Addr>().ok().map(IpAddr::V4)
+    })
+}
+
+fn scheme(proto: &str) -> Option<&'static str> {
+    match proto.to_ascii_lowercase().as_str() {
+        "http" => Some("http"),
+        "https" => Some("https"),
+        _ => None,
+    }
+}
+
+/// Only host names, addresses and ports, so a forwarded host can't smuggle a path or credentials into a URL built from it
+fn valid_host(host: &str) -> bool {
+    !host.is_empty()
+        && host
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '-' | '_' | ':' | '[' | ']'))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ConnectionInfo, ForwardingHeaders};
+
+    fn cidr(s: &str) -> Cidr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn matches_cidr_blocks() {
+        assert!(cidr("10.0.0.0/8").contains("10.1.2.3".parse().unwrap()));
+        assert!(!cidr("10.0.0.0/8").contains("11.0.0.1".parse().unwrap()));
+        assert!(cidr("10.0.0.0/8").contains("::ffff:10.0.0.1".parse().unwrap()));
+        assert!(cidr("2001:db8::/32").contains("2001:db8:1::1".parse().unwrap()));
+        assert!(cidr("0.0.0.0/0").contains("203.0.113.9".parse().unwrap()));
+        assert!(cidr("192.0.2.1").contains("192.0.2.1".parse().unwrap()));
+        assert!(!cidr("192.0.2.1").contains("192.0.2.2".parse().unwrap()));
+        assert!("10.0.0.0/33".parse::<Cidr>().is_err());
+    }
+
+    #[test]
+    fn resolves_clients_behind_trusted_proxies() {
+        let proxies = TrustedProxies::new(vec![cidr("10.0.0.0/8")]);
+        let request = |peer: &str, forwarding: ForwardingHeaders| HTTPRequestHeader {
+            authority: Some("internal:8080".to_owned()),
+            forwarding,
+            connection: ConnectionInfo {
+                peer: Some(peer.parse().unwrap()),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let xff = |ips: &str, proto: &str| ForwardingHeaders {
+            x_forwarded_for: Some(ips.to_owned()),
+            x_forwarded_proto: Some(proto.to_owned()),
+            x_forwarded_host: Some("example.test".to_owned()),
+            ..Default::default()
+        };
+
+        let client = proxies.resolve(&request(
+            "10.0.0.2:5000",
+            xff("1.1.1.1, 203.0.113.7, 10.0.0.1", "https"),
+        ));
+        assert_eq!(client.ip, Some("203.0.113.7".parse().unwrap()));
+        assert_eq!(
+            client.url("/home").as_deref(),
+            Some("https://example.test/home")
+        );
+
+        // anyone else's headers are ignored
+        let client = proxies.resolve(&request("198.51.100.1:5000", xff("1.1.1.1", "https")));
+        assert_eq!(client.ip, Some("198.51.100.1".parse().unwrap()));
+        assert_eq!(client.url("/").as_deref(), Some("http://internal:8080/"));
+
+        let client = proxies.resolve(&request(
+            "10.0.0.2:5000",
+            ForwardingHeaders {
+                forwarded: Some(
+                    "for=192.0.2.60;proto=http, for=\"[2001:db8::1]:4711\";proto=https;host=api.example.test"
+                        .to_owned(),
+                ),
+                ..xff("1.1.1.1", "http")
+            },
+        ));
+        assert_eq!(client.ip, Some("2001:db8::1".parse().unwrap()));
+        assert_eq!(
+            client.url("/").as_deref(),
+            Some("https://api.example.test/")
+        );
+    }
+}
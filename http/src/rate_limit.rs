@@ -0,0 +1,318 @@
+use crate::{HTTPRequestHeader, HTTPResponses, RawHTTPResponse};
+use std::{
+    collections::HashMap,
+    fmt,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+/// How often stale clients are swept out of a [`RateLimit`], at most
+const SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Picks what a [`RateLimit`] counts requests by. Implemented for closures, so a custom key can be written inline.
+/// Requests the extractor returns `None` for aren't limited.
+pub trait KeyExtractor: Send + Sync {
+    fn key(&self, header: &HTTPRequestHeader) -> Option<String>;
+}
+
+impl<F: Fn(&HTTPRequestHeader) -> Option<String> + Send + Sync> KeyExtractor for F {
+    fn key(&self, header: &HTTPRequestHeader) -> Option<String> {
+        self(header)
+    }
+}
+
+/// The client's IP address, looking through trusted proxies. The default key
+pub fn client_ip(header: &HTTPRequestHeader) -> Option<String> {
+    header.client.ip.map(|ip| ip.to_string())
+}
+
+/// How a [`RateLimit`] decides whether a request is over its quota
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Algorithm {
+    /// Allows bursts of up to the whole quota, refilled evenly over the window
+    TokenBucket,
+    /// Allows the quota within any window long stretch, estimated from the counts of the current and previous fixed windows
+    SlidingWindow,
+}
+
+/// What each client's requests so far add up to
+#[derive(Debug, Clone, Copy)]
+enum Bucket {
+    Tokens {
+        tokens: f64,
+        updated: Instant,
+    },
+    Windows {
+        started: Instant,
+        current: u32,
+        previous: u32,
+    },
+}
+
+/// The outcome of counting a request against a [`RateLimit`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Decision {
+    limit: u32,
+    pub(crate) remaining: u32,
+    /// Until the client has its whole quota back, or the current window ends
+    reset: Duration,
+    /// Set if the request is over the quota, to how long until it would be let through
+    retry_after: Option<Duration>,
+}
+
+impl Decision {
+    pub(crate) fn allowed(&self) -> bool {
+        self.retry_after.is_none()
+    }
+
+    /// The `RateLimit-*` headers telling the client where it stands, plus `Retry-After` if it is throttled
+    pub(crate) fn headers(&self) -> Vec<(String, String)> {
+        let mut headers = vec![
+            ("RateLimit-Limit".to_owned(), self.limit.to_string()),
+            ("RateLimit-Remaining".to_owned(), self.remaining.to_string()),
+            ("RateLimit-Reset".to_owned(), secs(self.reset).to_string()),
+        ];
+        if let Some(retry_after) = self.retry_after {
+            headers.push(("Retry-After".to_owned(), secs(retry_after).to_string()));
+        }
+        headers
+    }
+
+    /// The `HTTP 429` sent to a throttled client
+    pub(crate) fn rejection(&self) -> RawHTTPResponse {
+        let mut response = RawHTTPResponse::from(HTTPResponses::too_many_requests());
+        response.headers.extend(self.headers());
+        response
+    }
+}
+
+/// Whole seconds, rounded up so clients never come back too early
+fn secs(duration: Duration) -> u64 {
+    duration.as_secs() + u64::from(duration.subsec_nanos() > 0)
+}
+
+struct State {
+    buckets: HashMap<String, Bucket>,
+    swept: Instant,
+}
+
+/// Limits how many requests each client may make, attached to a whole router with [`crate::Router::with_rate_limit`] or to routes with [`crate::RouteOptions::rate_limit`]. For example:
+/// ```rust
+/// # use http::{HTTPRequestHeader, RateLimit};
+/// # use std::time::Duration;
+/// // 100 requests a minute per client IP, in bursts of up to 100
+/// let per_ip = RateLimit::token_bucket(100, Duration::from_secs(60));
+/// // 1000 requests an hour per API key
+/// let per_key = RateLimit::sliding_window(1000, Duration::from_secs(3600)).key_by(
+///     |header: &HTTPRequestHeader| Some(header.authorization.as_ref()?.credentials("Bearer")?.to_owned()),
+/// );
+/// ```
+/// Throttled requests get an `HTTP 429` with a `Retry-After` header, without the route's callback running. All responses it applies to get `RateLimit-Limit`, `RateLimit-Remaining` and `RateLimit-Reset` headers.
+/// Clones share their counts, so one limit given to several routes is shared between them.
+#[derive(Clone)]
+pub struct RateLimit {
+    algorithm: Algorithm,
+    quota: u32,
+    window: Duration,
+    key: Arc<dyn KeyExtractor>,
+    state: Arc<Mutex<State>>,
+}
+
+impl RateLimit {
+    fn new(algorithm: Algorithm, quota: u32, window: Duration) -> Self {
+        Self {
+            algorithm,
+            quota: quota.max(1),
+            window: window.max(Duration::from_millis(1)),
+            key: Arc::new(client_ip),
+            state: Arc::new(Mutex::new(State {
+                buckets: HashMap::new(),
+                swept: Instant::now(),
+            })),
+        }
+    }
+
+    /// Lets each client make `quota` requests at once, then one more every `window / quota` as its bucket refills
+    pub fn token_bucket(quota: u32, window: Duration) -> Self {
+        Self::new(Algorithm::TokenBucket, quota, window)
+    }
+
+    /// Lets each client make `quota` requests within any `window`, with no bursts over it
+    pub fn sliding_window(quota: u32, window: Duration) -> Self {
+        Self::new(Algorithm::SlidingWindow, quota, window)
+    }
+
+    /// Consumes self and counts requests by the key `extractor` returns instead of by client IP
+    pub fn key_by(mut self, extractor: impl KeyExtractor + 'static) -> Self {
+        self.key = Arc::new(extractor);
+        self
+    }
+
+    /// Counts the request against its client's quota. `None` if the request has no key and isn't limited
+    pub(crate) fn check(&self, header: &HTTPRequestHeader) -> Option<Decision> {
+        let key = self.key.key(header)?;
+        Some(self.check_at(key, Instant::now()))
+    }
+
+    fn check_at(&self, key: String, now: Instant) -> Decision {
+        let mut state = self.state.lock().unwrap_or_else(|err| err.into_inner());
+        // a client that has been quiet for a whole window is back to a full quota, so it can be forgotten
+        if now.saturating_duration_since(state.swept) >= SWEEP_INTERVAL.max(self.window) {
+            let window = self.window;
+            state.buckets.retain(|_, bucket| {
+                let last = match bucket {
+                    Bucket::Tokens { updated, .. } => *updated,
+                    Bucket::Windows { started, .. } => *started + window,
+                };
+                now.saturating_duration_since(last) < window
+            });
+            state.swept = now;
+        }
+
+        let quota = f64::from(self.quota);
+        let window = self.window.as_secs_f64();
+        let bucket = state.buckets.entry(key).or_insert(match self.algorithm {
+            Algorithm::TokenBucket => Bucket::Tokens {
+                tokens: quota,
+                updated: now,
+            },
+            Algorithm::SlidingWindow => Bucket::Windows {
+                started: now,
+                current: 0,
+                previous: 0,
+            },
+        });
+        let (remaining, reset, retry_after) = match bucket {
+            Bucket::Tokens { tokens, updated } => {
+                let rate = quota / window;
+                *tokens = (*tokens + now.saturating_duration_since(*updated).as_secs_f64() * rate)
+                    .min(quota);
+                *updated = now;
+                let retry_after = if *tokens >= 1.0 {
+                    *tokens -= 1.0;
+                    None
+                } else {
+                    Some((1.0 - *tokens) / rate)
+                };
+                (*tokens, (quota - *tokens) / rate, retry_after)
+            }
+            Bucket::Windows {
+                started,
+                current,
+                previous,
+            } => {
+                let windows = now.saturating_duration_since(*started).as_secs_f64() / window;
+                if windows >= 2.0 {
+                    (*previous, *current) = (0, 0);
+                } else if windows >= 1.0 {
+                    (*previous, *current) = (*current, 0);
+                }
+                *started += self.window * windows.floor() as u32;
+                let elapsed = now.saturating_duration_since(*started).as_secs_f64();
+                // the previous window counts for the part of it still within a window of now
+                let estimate = |elapsed: f64, previous: u32, current: u32| {
+                    f64::from(previous) * (1.0 - elapsed / window) + f64::from(current)
+                };
+                let used = estimate(elapsed, *previous, *current);
+                let retry_after = if used + 1.0 <= quota {
+                    *current += 1;
+                    None
+                } else if f64::from(*current) + 1.0 <= quota {
+                    // wait for enough of the previous window to slide out
+                    let share = (quota - 1.0 - f64::from(*current)) / f64::from(*previous);
+                    Some(window * (1.0 - share) - elapsed)
+                } else {
+                    // this window is used up, wait for it to end and enough of it to slide out
+                    let share = (quota - 1.0) / f64::from(*current);
+                    Some(window - elapsed + window * (1.0 - share))
+                };
+                (
+                    quota - estimate(elapsed, *previous, *current),
+                    window - elapsed,
+                    retry_after,
+                )
+            }
+        };
+        Decision {
+            limit: self.quota,
+            remaining: remaining.max(0.0).floor() as u32,
+            reset: Duration::from_secs_f64(reset.max(0.0)),
+            retry_after: retry_after.map(|secs| Duration::from_secs_f64(secs.max(0.0))),
+        }
+    }
+}
+
+impl fmt::Debug for RateLimit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RateLimit")
+            .field("algorithm", &self.algorithm)
+            .field("quota", &self.quota)
+            .field("window", &self.window)
+            .finish()
+    }
+}
+
+/// Limits are equal if they share their counts
+impl PartialEq for RateLimit {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.state, &other.state)
+    }
+}
+
+impl Eq for RateLimit {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn refills_token_buckets() {
+        let limit = RateLimit::token_bucket(2, Duration::from_secs(8));
+        let start = Instant::now();
+        let check =
+            |key: &str, secs| limit.check_at(key.to_owned(), start + Duration::from_secs(secs));
+
+        assert_eq!(check("a", 0).remaining, 1);
+        assert_eq!(check("a", 0).remaining, 0);
+        let throttled = check("a", 1);
+        assert_eq!(throttled.retry_after, Some(Duration::from_secs(3)));
+        assert_eq!(
+            throttled.headers(),
+            [
+                ("RateLimit-Limit", "2"),
+                ("RateLimit-Remaining", "0"),
+                ("RateLimit-Reset", "7"),
+                ("Retry-After", "3"),
+            ]
+            .map(|(name, value)| (name.to_owned(), value.to_owned()))
+        );
+        // other clients have their own bucket
+        assert!(check("b", 1).allowed());
+        assert!(check("a", 5).allowed());
+        assert!(!check("a", 5).allowed());
+    }
+
+    #[test]
+    fn slides_windows() {
+        let limit = RateLimit::sliding_window(4, Duration::from_secs(10));
+        let start = Instant::now();
+        let check = |secs| limit.check_at("a".to_owned(), start + Duration::from_secs(secs));
+
+        for _ in 0..4 {
+            assert!(check(8).allowed());
+        }
+        let throttled = check(9);
+        assert!(!throttled.allowed());
+        // windows start at the client's first request, so the next one starts at 18.
+        // The 4 requests from then on count for 3 of them at 20.5 seconds, leaving room for 1
+        assert_eq!(
+            throttled.retry_after,
+            Some(Duration::from_millis(20500 - 9000))
+        );
+        assert!(!check(20).allowed());
+        assert!(check(21).allowed());
+        assert!(!check(21).allowed());
+        // after two quiet windows the quota is back
+        assert_eq!(check(40).remaining, 3);
+    }
+}
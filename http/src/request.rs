@@ -4,16 +4,56 @@ use std::{
     panic::catch_unwind,
     str::{from_utf8, FromStr},
 };
+use tracing::trace;
 
-use crate::debg;
+use crate::{Authorization, ClientInfo, ConnectionInfo, CookieJar, Principal, Session};
 
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, Default, PartialEq, Eq)]
 pub struct HTTPRequestHeader {
     pub method: String,
     pub path: String,
     pub http_version: String,
     pub content_length: Option<usize>,
     pub content_type: Option<String>,
+    /// The host the request is for, lowercased and without the port. See [`HTTPRequestHeader::set_host`]
+    pub host: Option<String>,
+    /// The `Host` header or HTTP/2 `:authority` as the client sent it, with its port
+    pub authority: Option<String>,
+    /// The cookies from every `Cookie` header
+    pub cookies: CookieJar,
+    pub authorization: Option<Authorization>,
+    /// The `Origin` of a cross-origin request, e.g. `https://app.example.test`
+    pub origin: Option<String>,
+    /// The method a CORS preflight asks to use, from `Access-Control-Request-Method`
+    pub access_control_request_method: Option<String>,
+    /// The headers a CORS preflight asks to send, from `Access-Control-Request-Headers`
+    pub access_control_request_headers: Option<String>,
+    /// The `User-Agent` the client identified itself with
+    pub user_agent: Option<String>,
+    /// The page that linked to the request, from `Referer`
+    pub referer: Option<String>,
+    /// The ID the request can be followed through the logs by. Taken from `X-Request-Id`, which the client or a proxy in front of the server may tag it with, and filled in by the router if that is missing or invalid, see [`HTTPRequestHeader::assign_request_id`]
+    pub request_id: Option<String>,
+    /// The headers proxies record the client in. Only to be believed from trusted proxies, see [`HTTPRequestHeader::client`]
+    pub forwarding: ForwardingHeaders,
+    /// Who the request really came from, filled in by the router from the connection and, for trusted proxies, the forwarding headers
+    pub client: ClientInfo,
+    /// Who the request was authenticated as, filled in by the router for routes with an [`crate::AuthGuard`]
+    pub principal: Option<Principal>,
+    /// The client's session, filled in by the router when it has [`crate::Sessions`] set up. Empty otherwise
+    pub session: Session,
+    /// Filled in by the server from the connection rather than parsed from the request
+    pub connection: ConnectionInfo,
+}
+
+/// The headers proxies add to requests they pass on, as sent. Headers that appear more than once are joined with `, ` in the order they came in, as proxies append to them.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ForwardingHeaders {
+    /// `Forwarded` (RFC 7239)
+    pub forwarded: Option<String>,
+    pub x_forwarded_for: Option<String>,
+    pub x_forwarded_proto: Option<String>,
+    pub x_forwarded_host: Option<String>,
 }
 
 // Wrapper for HTTPRequestHeader and a Vec<u8> representing the body
@@ -39,7 +79,8 @@ impl<'a> TryFrom<&'a [u8]> for DeconstructedHTTPRequest {
         // Cannot use map as that will wrap the from str result within a result resulting in nested results.
         // Return a Deconstructed HTTP request containing the request and index marking the end of the headers and body beginning
 
-        debg!(from_utf8(value))
+        from_utf8(value)
+            .inspect(|head| trace!("Parsing request head {head:?}"))
             .map_err(|err| format!("Could not convert byte sequence to UTF-8 => {err}"))
             .and_then(HTTPRequestHeader::from_str)
             .map(|headers| DeconstructedHTTPRequest(headers, value.len() + boundary.as_str().len()))
@@ -50,9 +91,8 @@ impl FromStr for HTTPRequestHeader {
     type Err = String;
     // Input: s as a request line, up to the first \r\n\r\n
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        let (first_line, rest) = s
-            .split_once("\r\n")
-            .ok_or("Could not find first line of HTTP Request".to_owned())?;
+        // a request without any headers is just the first line
+        let (first_line, rest) = s.split_once("\r\n").unwrap_or((s, ""));
 
         let re = Regex::new(r"([A-Z]+)\s+(/[^\s]*)\s+HTTP/(\d\.\d)")
             .map_err(|err| format!("Could not get regex to parse first line => {err}"))?;
@@ -68,14 +108,26 @@ impl FromStr for HTTPRequestHeader {
                 })
             })?;
 
-        // Get Content Length
-        let re = Regex::new(r"content-length: (\d+)")
-            .map_err(|err| format!("Could not get regex to parse content-length => {err}"))?;
-
-        let content_length: Option<usize> = re
-            .captures(rest)
-            .and_then(|s| s.get(1))
-            .map(|length| length.as_str().parse().unwrap());
+        // Get Content Length. Repeats, in several headers or a list in one, are only accepted if they agree, as a proxy that picked a different one than the server would see the body end elsewhere and let a request be smuggled past it
+        let lengths = rest
+            .split("\r\n")
+            .filter_map(|line| line.split_once(':'))
+            .filter(|(name, _)| name.eq_ignore_ascii_case("content-length"))
+            .flat_map(|(_, value)| value.split(','))
+            .map(|length| {
+                let length = length.trim();
+                if length.is_empty() || !length.bytes().all(|b| b.is_ascii_digit()) {
+                    return Err(format!("Invalid Content-Length {length:?}"));
+                }
+                length
+                    .parse::<usize>()
+                    .map_err(|_| format!("Content-Length {length} is too large"))
+            })
+            .collect::<Result<Vec<usize>, _>>()?;
+        let content_length = lengths.first().copied();
+        if lengths.iter().any(|length| Some(*length) != content_length) {
+            return Err(format!("Conflicting Content-Length headers {lengths:?}"));
+        }
 
         //Get Content Type
         let re = Regex::new(r"Content-Type: (.+)\r\n")
@@ -86,13 +138,91 @@ impl FromStr for HTTPRequestHeader {
             .and_then(|s| s.get(1))
             .map(|length| length.as_str().to_owned());
 
-        Ok(HTTPRequestHeader {
+        // Get Host. HTTP/1.1 requires exactly one, a request with several could be routed differently by each server along the way
+        let mut hosts = rest
+            .split("\r\n")
+            .filter_map(|line| line.split_once(':'))
+            .filter(|(name, _)| name.eq_ignore_ascii_case("host"))
+            .map(|(_, value)| value);
+        let host = hosts.next();
+        if hosts.next().is_some() {
+            return Err("Found more than one Host header".to_owned());
+        }
+        if host.is_none() && http_version == "1.1" {
+            return Err("Missing Host header in HTTP/1.1 request".to_owned());
+        }
+
+        // Get the headers only read once, the first one wins if they repeat
+        let header_value = |header: &str| {
+            rest.split("\r\n")
+                .filter_map(|line| line.split_once(':'))
+                .find(|(name, _)| name.eq_ignore_ascii_case(header))
+                .map(|(_, value)| value.trim().to_owned())
+        };
+        let authorization = header_value("authorization").map(Authorization);
+        let header_values = |header: &str| {
+            let values: Vec<&str> = rest
+                .split("\r\n")
+                .filter_map(|line| line.split_once(':'))
+                .filter(|(name, _)| name.eq_ignore_ascii_case(header))
+                .map(|(_, value)| value.trim())
+                .collect();
+            (!values.is_empty()).then(|| values.join(", "))
+        };
+        let forwarding = ForwardingHeaders {
+            forwarded: header_values("forwarded"),
+            x_forwarded_for: header_values("x-forwarded-for"),
+            x_forwarded_proto: header_values("x-forwarded-proto"),
+            x_forwarded_host: header_values("x-forwarded-host"),
+        };
+
+        // Get Cookies
+        let mut cookies = CookieJar::default();
+        rest.split("\r\n")
+            .filter_map(|line| line.split_once(':'))
+            .filter(|(name, _)| name.eq_ignore_ascii_case("cookie"))
+            .for_each(|(_, value)| cookies.add_header(value));
+
+        let mut header = HTTPRequestHeader {
             method: method.to_owned(),
             path: path.to_owned(),
             http_version: http_version.to_owned(),
             content_length,
             content_type,
-        })
+            host: None,
+            authority: None,
+            cookies,
+            authorization,
+            origin: header_value("origin"),
+            access_control_request_method: header_value("access-control-request-method"),
+            access_control_request_headers: header_value("access-control-request-headers"),
+            user_agent: header_value("user-agent"),
+            referer: header_value("referer"),
+            request_id: header_value("x-request-id"),
+            forwarding,
+            client: ClientInfo::default(),
+            principal: None,
+            session: Session::default(),
+            connection: ConnectionInfo::default(),
+        };
+        if let Some(host) = host {
+            header.set_host(host);
+        }
+        Ok(header)
+    }
+}
+
+impl HTTPRequestHeader {
+    /// Sets [`HTTPRequestHeader::host`] from a `Host` header or HTTP/2 `:authority`, lowercasing it and dropping the port so it can be compared against host names.
+    /// IPv6 addresses keep their brackets, e.g. `[::1]:8080` becomes `[::1]`. The authority as given is kept at [`HTTPRequestHeader::authority`].
+    pub fn set_host(&mut self, authority: &str) {
+        let authority = authority.trim();
+        self.authority = Some(authority.to_owned());
+        let host = match authority.find(']') {
+            Some(end) if authority.starts_with('[') => &authority[..=end],
+            _ => authority.split(':').next().unwrap_or_default(),
+        };
+        self.host = Some(host.to_ascii_lowercase());
     }
 }
 
@@ -120,6 +250,7 @@ mod tests {
             http_version: http_version.to_owned(),
             content_length,
             content_type: content_type.map(|s| s.to_owned()),
+            ..Default::default()
         }
     }
     #[test]
@@ -137,7 +268,10 @@ mod tests {
             0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
             0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
         ];
-        let expected_answer: HTTPRequestHeader = new_request("GET", "/", "1.1", None, None);
+        let mut expected_answer: HTTPRequestHeader = new_request("GET", "/", "1.1", None, None);
+        expected_answer.set_host("localhost:8080");
+        expected_answer.user_agent =
+            Some("Thunder Client (https://www.thunderclient.com)".to_owned());
 
         let DeconstructedHTTPRequest(actual_answer, _) = test_bytes
             .try_into()
@@ -173,7 +307,13 @@ mod tests {
             110, 111, 110, 101, 13, 10, 83, 101, 99, 45, 70, 101, 116, 99, 104, 45, 85, 115, 101,
             114, 58, 32, 63, 49, 13, 10, 13, 10, 0, 0,
         ];
-        let expected_answer: HTTPRequestHeader = new_request("GET", "/hello", "1.1", None, None);
+        let mut expected_answer: HTTPRequestHeader =
+            new_request("GET", "/hello", "1.1", None, None);
+        expected_answer.set_host("localhost:8080");
+        expected_answer.user_agent = Some(
+            "Mozilla/5.0 (Windows NT 10.0; Win64; x64; rv:109.0) Gecko/20100101 Firefox/115.0"
+                .to_owned(),
+        );
 
         let DeconstructedHTTPRequest(actual_answer, _) = test_bytes
             .try_into()
@@ -181,4 +321,47 @@ mod tests {
 
         assert_eq!(expected_answer, actual_answer);
     }
+
+    #[test]
+    fn rejects_bad_content_length() {
+        let parse = |headers: &str| {
+            format!("POST / HTTP/1.1\r\nHost: a.test\r\n{headers}")
+                .parse::<HTTPRequestHeader>()
+                .map(|header| header.content_length)
+        };
+
+        assert_eq!(parse("Content-Length: 5"), Ok(Some(5)));
+        assert_eq!(parse("content-length:12 "), Ok(Some(12)));
+        assert_eq!(parse("Content-Length: 5\r\nContent-Length: 5"), Ok(Some(5)));
+        assert_eq!(parse("Content-Length: 5, 5"), Ok(Some(5)));
+        assert_eq!(parse("Accept: */*"), Ok(None));
+
+        assert!(parse("Content-Length: 99999999999999999999999").is_err());
+        assert!(parse("Content-Length: 5\r\nContent-Length: 50").is_err());
+        assert!(parse("Content-Length: 5, 6").is_err());
+        assert!(parse("Content-Length: -5").is_err());
+        assert!(parse("Content-Length: +5").is_err());
+        assert!(parse("Content-Length: ").is_err());
+    }
+
+    #[test]
+    fn requires_one_host_for_http_1_1() {
+        let parse = |request: &str| {
+            request
+                .parse::<HTTPRequestHeader>()
+                .map(|header| header.host)
+        };
+
+        assert!(parse("GET / HTTP/1.1\r\nAccept: */*").is_err());
+        assert!(parse("GET / HTTP/1.1\r\nHost: a.test\r\nHost: b.test").is_err());
+        assert_eq!(parse("GET / HTTP/1.0"), Ok(None));
+        assert_eq!(
+            parse("GET / HTTP/1.1\r\nhOsT: API.Example.test:8080"),
+            Ok(Some("api.example.test".to_owned()))
+        );
+        assert_eq!(
+            parse("GET / HTTP/1.1\r\nHost: [::1]:8080"),
+            Ok(Some("[::1]".to_owned()))
+        );
+    }
 }
@@ -0,0 +1,112 @@
+use crate::{HTTPRequestHeader, RawHTTPResponse};
+use ring::rand::{SecureRandom, SystemRandom};
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tracing::error;
+
+/// The header request IDs are read from and echoed back in
+pub const REQUEST_ID_HEADER: &str = "X-Request-Id";
+
+/// Longest `X-Request-Id` taken from a client. Longer ones are replaced, so they can't bloat every log line
+pub const MAX_REQUEST_ID_LEN: usize = 128;
+
+/// Whether a client's `X-Request-Id` is safe to log and echo: 1 to [`MAX_REQUEST_ID_LEN`] letters, digits or any of `-_.:/+=@`, which covers UUIDs, hex and base64 IDs
+pub fn valid_request_id(id: &str) -> bool {
+    (1..=MAX_REQUEST_ID_LEN).contains(&id.len())
+        && id
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b"-_.:/+=@".contains(&b))
+}
+
+/// A new random ID, formatted as a version 4 UUID
+pub fn generate_request_id() -> String {
+    let mut bytes = [0u8; 16];
+    if SystemRandom::new().fill(&mut bytes).is_err() {
+        // only needs to be unique, not unpredictable, so the time and a counter do
+        error!(
+            "Could not generate a random request ID, the system's random number generator failed"
+        );
+        static COUNTER: AtomicU64 = AtomicU64::new(0);
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos() as u64;
+        bytes[..8].copy_from_slice(&nanos.to_be_bytes());
+        bytes[8..].copy_from_slice(&COUNTER.fetch_add(1, Ordering::Relaxed).to_be_bytes());
+    }
+    bytes[6] = (bytes[6] & 0x0f) | 0x40;
+    bytes[8] = (bytes[8] & 0x3f) | 0x80;
+    let hex: String = bytes.iter().map(|b| format!("{b:02x}")).collect();
+    format!(
+        "{}-{}-{}-{}-{}",
+        &hex[..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..]
+    )
+}
+
+impl HTTPRequestHeader {
+    /// Keeps the request's ID if it is valid, see [`valid_request_id`], and gives it a new one otherwise. Returns the ID.
+    /// The router does this before the request reaches a route, the server can do it sooner to log the ID while the request is being read.
+    pub fn assign_request_id(&mut self) -> &str {
+        if !self.request_id.as_deref().is_some_and(valid_request_id) {
+            self.request_id = Some(generate_request_id());
+        }
+        self.request_id.as_deref().unwrap_or_default()
+    }
+}
+
+impl RawHTTPResponse {
+    /// Sets the `X-Request-Id` header to `id`, replacing any the route set itself
+    pub fn set_request_id(&mut self, id: &str) {
+        self.headers
+            .retain(|(name, _)| !name.eq_ignore_ascii_case(REQUEST_ID_HEADER));
+        self.headers
+            .push((REQUEST_ID_HEADER.to_owned(), id.to_owned()));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_valid_ids_and_replaces_the_rest() {
+        let mut header = HTTPRequestHeader {
+            request_id: Some("req-42_a.b:c/d+e=@f".to_owned()),
+            ..Default::default()
+        };
+        assert_eq!(header.assign_request_id(), "req-42_a.b:c/d+e=@f");
+
+        for invalid in [
+            None,
+            Some(String::new()),
+            Some("has space".to_owned()),
+            Some("evil\r\nSet-Cookie: a=b".to_owned()),
+            Some("x".repeat(MAX_REQUEST_ID_LEN + 1)),
+        ] {
+            let mut header = HTTPRequestHeader {
+                request_id: invalid.clone(),
+                ..Default::default()
+            };
+            let id = header.assign_request_id().to_owned();
+            assert_ne!(Some(&id), invalid.as_ref());
+            assert!(valid_request_id(&id));
+            // assigning again keeps the ID
+            assert_eq!(header.assign_request_id(), id);
+        }
+    }
+
+    #[test]
+    fn generates_uuids() {
+        let id = generate_request_id();
+        assert_eq!(id.len(), 36);
+        assert_eq!(id.as_bytes()[14], b'4');
+        assert!(matches!(id.as_bytes()[19], b'8' | b'9' | b'a' | b'b'));
+        assert_ne!(id, generate_request_id());
+    }
+}
@@ -1,7 +1,9 @@
+use crate::Cookie;
 use std::collections::HashMap;
 use std::convert::Infallible;
 use std::result;
 use std::str::FromStr;
+use tracing::warn;
 /// The HTTP Result type.
 pub type HTTPResult = result::Result<Box<HTTPResponses>, Box<HTTPResponses>>;
 
@@ -44,6 +46,11 @@ pub enum HTTPResponses {
         headers: Option<HashMap<String, String>>,
         body: Vec<u8>,
     },
+    /// Another response with extra headers added after its own. Headers may repeat, e.g. several `Set-Cookie`. Built with [`HTTPResponses::with_header`] and [`HTTPResponses::with_cookie`].
+    WithHeaders {
+        response: Box<HTTPResponses>,
+        headers: Vec<(String, String)>,
+    },
 }
 /// Syntatic sugar for using [`Response::to_response`] on a [`Box<HTTPRequest>`]. Uses the `*` operator of the box pointers to dereference it and calls the `to_response` method  implemented for [`HTTPResponses`]
 impl Response for Box<HTTPResponses> {
@@ -51,53 +58,164 @@ impl Response for Box<HTTPResponses> {
         (*self).to_response()
     }
 }
-/// When converting to response, if statements handle special cases. For instance, redirect's HTTP status code is different from the rest, so it needs to be handled separatley. This helps to avoid writing duplicate code.
+/// Serializes the response as HTTP/1.1. See [`RawHTTPResponse`] for the protocol independent form.
 impl Response for HTTPResponses {
     fn to_response(self) -> Vec<u8> {
+        RawHTTPResponse::from(self).to_http1()
+    }
+}
+
+/// A response broken down into its status, headers and body, before being serialized for a particular protocol version.
+/// `Content-Length` is not part of `headers`, it is added from the body when serializing.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RawHTTPResponse {
+    pub status_code: i32,
+    pub message: String,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl RawHTTPResponse {
+    /// Whether the status allows a body. Responses that don't, like an `HTTP 204`, are sent without a `Content-Length`
+    pub fn allows_body(&self) -> bool {
+        !matches!(self.status_code, 100..=199 | 204 | 304)
+    }
+
+    /// Serializes the response as an HTTP/1.1 status line, headers and body
+    pub fn to_http1(self) -> Vec<u8> {
+        let content_length = if self.allows_body() {
+            format!("Content-Length: {}\r\n", self.body.len())
+        } else {
+            String::new()
+        };
+        let Self {
+            status_code,
+            message,
+            headers,
+            mut body,
+        } = self;
+        let headers: String = headers
+            .into_iter()
+            .map(|(a, b)| format!("{a}: {b}\r\n"))
+            .collect();
+        let mut response = format!(
+            "HTTP/1.1 {status_code} {message}\r\n\
+            {headers}\
+            {content_length}\r\n",
+        )
+        .into_bytes();
+        response.append(&mut body);
+        response
+    }
+}
+
+/// When converting to a raw response, if statements handle special cases. For instance, redirect's HTTP status code is different from the rest, so it needs to be handled separatley. This helps to avoid writing duplicate code.
+impl From<HTTPResponses> for RawHTTPResponse {
+    fn from(value: HTTPResponses) -> Self {
         // handle the redirect case separate
-        if let Self::Redirect(s) = self {
-            format!(
-                "HTTP/1.1 301 Moved Permanently\r\n\
-                X-Content-Type-Options: nosniff\r\n\
-                Location: {s}\r\n\r\n"
-            )
-            .into_bytes()
+        if let HTTPResponses::Redirect(s) = value {
+            RawHTTPResponse {
+                status_code: 301,
+                message: "Moved Permanently".to_owned(),
+                headers: vec![
+                    ("X-Content-Type-Options".to_owned(), "nosniff".to_owned()),
+                    ("Location".to_owned(), s),
+                ],
+                body: Vec::new(),
+            }
         } else {
-            match self {
-                Self::PlainText(s) => Self::craft_string_response(200, "OK", "text/plain", s),
-                Self::Html(s) => {
-                    Self::craft_string_response(200, "OK", "text/html; charset=utf-8", s)
+            match value {
+                HTTPResponses::PlainText(s) => {
+                    HTTPResponses::craft_string_response(200, "OK", "text/plain", s)
+                }
+                HTTPResponses::Html(s) => {
+                    HTTPResponses::craft_string_response(200, "OK", "text/html; charset=utf-8", s)
+                }
+                HTTPResponses::JavaScript(s) => {
+                    HTTPResponses::craft_string_response(200, "OK", "text/javascript", s)
                 }
-                Self::JavaScript(s) => Self::craft_string_response(200, "OK", "text/javascript", s),
-                Self::Css(s) => Self::craft_string_response(200, "OK", "text/css", s),
-                Self::Json(s) => Self::craft_string_response(200, "OK", "application/json", s),
-                Self::HTTPError {
+                HTTPResponses::Css(s) => {
+                    HTTPResponses::craft_string_response(200, "OK", "text/css", s)
+                }
+                HTTPResponses::Json(s) => {
+                    HTTPResponses::craft_string_response(200, "OK", "application/json", s)
+                }
+                HTTPResponses::HTTPError {
                     status_code,
                     message,
                     body,
-                } => Self::craft_string_response(status_code, message.as_str(), "text/plain", body),
-                Self::Image { ext, content } => Self::craft_byte_response(
+                } => HTTPResponses::craft_string_response(
+                    status_code,
+                    message.as_str(),
+                    "text/plain",
+                    body,
+                ),
+                HTTPResponses::Image { ext, content } => HTTPResponses::craft_byte_response(
                     200,
                     "OK",
                     format!("image/{ext}").as_str(),
                     None,
                     content,
                 ),
-                Self::Custom {
+                HTTPResponses::Custom {
                     code,
                     message,
                     ctype,
                     headers,
                     body,
-                } => {
-                    Self::craft_byte_response(code, message.as_str(), ctype.as_str(), headers, body)
+                } => HTTPResponses::craft_byte_response(
+                    code,
+                    message.as_str(),
+                    ctype.as_str(),
+                    headers,
+                    body,
+                ),
+                HTTPResponses::WithHeaders { response, headers } => {
+                    let mut raw = RawHTTPResponse::from(response);
+                    raw.headers.extend(headers);
+                    raw
                 }
                 _ => unreachable!(),
             }
         }
     }
 }
+
+/// Syntatic sugar for converting a [`Box<HTTPResponses>`], as returned by routes, into a [`RawHTTPResponse`]
+impl From<Box<HTTPResponses>> for RawHTTPResponse {
+    fn from(value: Box<HTTPResponses>) -> Self {
+        Self::from(*value)
+    }
+}
+
 impl HTTPResponses {
+    /// Consumes self and adds a header to the response. Headers with a carriage return or line feed in them are dropped, as they would let the value write headers of its own.
+    pub fn with_header(self, name: &str, value: &str) -> Self {
+        if [name, value].iter().any(|part| part.contains(['\r', '\n'])) {
+            warn!("Dropping response header {name:?} containing a line break");
+            return self;
+        }
+        let header = (name.to_owned(), value.to_owned());
+        match self {
+            Self::WithHeaders {
+                response,
+                mut headers,
+            } => {
+                headers.push(header);
+                Self::WithHeaders { response, headers }
+            }
+            response => Self::WithHeaders {
+                response: Box::new(response),
+                headers: vec![header],
+            },
+        }
+    }
+
+    /// Consumes self and adds a `Set-Cookie` header to the response. Can be called several times to set several cookies.
+    pub fn with_cookie(self, cookie: Cookie) -> Self {
+        self.with_header("Set-Cookie", &cookie.to_string())
+    }
+
     pub fn not_found() -> Box<Self> {
         Box::new(Self::HTTPError {
             status_code: 404,
@@ -113,8 +231,87 @@ impl HTTPResponses {
             body: "The server has encountered an unexpected error.".to_owned(),
         })
     }
+
+    pub fn bad_request() -> Box<Self> {
+        Box::new(Self::HTTPError {
+            status_code: 400,
+            message: "Bad Request".to_owned(),
+            body: "The server could not understand the request.".to_owned(),
+        })
+    }
+
+    /// Requests without valid credentials. Should be sent with a `WWW-Authenticate` challenge, as [`crate::AuthGuard`] does
+    pub fn unauthorized() -> Box<Self> {
+        Box::new(Self::HTTPError {
+            status_code: 401,
+            message: "Unauthorized".to_owned(),
+            body: "You need to authenticate to access the requested content.".to_owned(),
+        })
+    }
+
+    pub fn forbidden() -> Box<Self> {
+        Box::new(Self::HTTPError {
+            status_code: 403,
+            message: "Forbidden".to_owned(),
+            body: "You are not allowed to access the requested content.".to_owned(),
+        })
+    }
+
+    pub fn request_timeout() -> Box<Self> {
+        Box::new(Self::HTTPError {
+            status_code: 408,
+            message: "Request Timeout".to_owned(),
+            body: "The server timed out waiting for the request.".to_owned(),
+        })
+    }
+
+    /// Requests over a [`crate::RateLimit`]. Should be sent with a `Retry-After` header
+    pub fn too_many_requests() -> Box<Self> {
+        Box::new(Self::HTTPError {
+            status_code: 429,
+            message: "Too Many Requests".to_owned(),
+            body: "You have sent too many requests, try again later.".to_owned(),
+        })
+    }
+
+    pub fn service_unavailable() -> Box<Self> {
+        Box::new(Self::HTTPError {
+            status_code: 503,
+            message: "Service Unavailable".to_owned(),
+            body: "The server is unable to handle the request right now.".to_owned(),
+        })
+    }
+
+    pub fn payload_too_large() -> Box<Self> {
+        Box::new(Self::HTTPError {
+            status_code: 413,
+            message: "Payload Too Large".to_owned(),
+            body: "The request body is larger than the server is willing to accept.".to_owned(),
+        })
+    }
+
+    pub fn uri_too_long() -> Box<Self> {
+        Box::new(Self::HTTPError {
+            status_code: 414,
+            message: "URI Too Long".to_owned(),
+            body: "The request line is longer than the server is willing to accept.".to_owned(),
+        })
+    }
+
+    pub fn header_fields_too_large() -> Box<Self> {
+        Box::new(Self::HTTPError {
+            status_code: 431,
+            message: "Request Header Fields Too Large".to_owned(),
+            body: "The request headers are larger than the server is willing to accept.".to_owned(),
+        })
+    }
     // Crafts a successful 2XX response on "text" content (HTML, PlainText, Json, etc...)
-    fn craft_string_response(code: i32, message: &str, ctype: &str, content: String) -> Vec<u8> {
+    fn craft_string_response(
+        code: i32,
+        message: &str,
+        ctype: &str,
+        content: String,
+    ) -> RawHTTPResponse {
         Self::craft_byte_response(code, message, ctype, None, content.into_bytes())
     }
 
@@ -124,26 +321,19 @@ impl HTTPResponses {
         message: &str,
         ctype: &str,
         headers: Option<HashMap<String, String>>,
-        mut content: Vec<u8>,
-    ) -> Vec<u8> {
-        let headers: String = headers
-            .map(|h| {
-                h.into_iter()
-                    .map(|(a, b)| format!("{a}: {b}\r\n"))
-                    .collect()
-            })
-            .unwrap_or(String::from(""));
-        let mut response = format!(
-            "HTTP/1.1 {code} {message}\r\n\
-            X-Content-Type-Options: nosniff\r\n\
-            Content-Type: {ctype}\r\n\
-            {headers}\
-            Content-Length: {}\r\n\r\n",
-            content.len(),
-        )
-        .into_bytes();
-        response.append(&mut content);
-        response
+        content: Vec<u8>,
+    ) -> RawHTTPResponse {
+        let mut response_headers = vec![
+            ("X-Content-Type-Options".to_owned(), "nosniff".to_owned()),
+            ("Content-Type".to_owned(), ctype.to_owned()),
+        ];
+        response_headers.extend(headers.unwrap_or_default());
+        RawHTTPResponse {
+            status_code: code,
+            message: message.to_owned(),
+            headers: response_headers,
+            body: content,
+        }
     }
 }
 
@@ -196,3 +386,25 @@ impl FromStr for HTTPResponses {
         Ok(Self::from(s.to_owned()))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sets_several_cookies() {
+        let response = HTTPResponses::from("Hi")
+            .with_cookie(Cookie::new("a", "1"))
+            .with_cookie(Cookie::new("b", "2").http_only(true))
+            .with_header("Set-Cookie", "c=3\r\nX-Injected: yes");
+        assert_eq!(
+            response.to_response(),
+            b"HTTP/1.1 200 OK\r\n\
+            X-Content-Type-Options: nosniff\r\n\
+            Content-Type: text/plain\r\n\
+            Set-Cookie: a=1\r\n\
+            Set-Cookie: b=2; HttpOnly\r\n\
+            Content-Length: 2\r\n\r\nHi"
+        );
+    }
+}
@@ -1,24 +1,61 @@
-use super::{HTTPRequest, HTTPResponses, HTTPResult, Response};
+use super::{
+    AccessLog, AuthGuard, ClientCertPolicy, ClientInfo, Cors, HTTPRequest, HTTPRequestHeader,
+    HTTPResponses, HTTPResult, IpAcl, Limits, Metrics, RateLimit, RawHTTPResponse, Sessions,
+    TrustedProxies,
+};
 
 // import the Regex and Regex Error package
 use regex::{Error, Regex};
-use std::result;
+use std::{any::Any, panic::catch_unwind, result, sync::Arc, time::Instant};
+use tracing::{error, Span};
 
 #[derive(Debug)]
 struct InternalRoute {
     method: Regex,
     path: Regex,
     http_version: String,
+    options: RouteOptions,
     callback: fn(HTTPRequest) -> HTTPResult,
 }
 
+/// Per route settings. [`RouteOptions::default`] gives a route that behaves like one registered with [`Router::route`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RouteOptions {
+    /// Overrides [`Limits::max_body`] for requests handled by this route. `None` falls back to the router.
+    /// The other limits apply to the request line and headers, which have to be read before the route is known, so they can only be set on the router.
+    pub max_body: Option<usize>,
+    /// Whether requests to this route need a TLS client certificate. Defaults to [`ClientCertPolicy::Optional`].
+    pub client_cert: ClientCertPolicy,
+    /// Credentials requests to this route must have. The principal they authenticate as is passed to the callback at [`HTTPRequestHeader::principal`].
+    pub auth: Option<AuthGuard>,
+    /// Lets browser apps on other origins call this route. Its preflights are answered by the router.
+    pub cors: Option<Cors>,
+    /// Limits how often each client may call this route, on top of any limit set with [`Router::with_rate_limit`]
+    pub rate_limit: Option<RateLimit>,
+    /// Which client addresses may call this route, looking through trusted proxies. Others get an `HTTP 403`, as do requests whose address isn't known.
+    pub acl: Option<IpAcl>,
+}
+
+impl InternalRoute {
+    fn matches(&self, other: &HTTPRequestHeader) -> bool {
+        self.matches_with_method(&other.method, other)
+    }
+
+    /// Whether the route would handle the request if it used `method` instead
+    fn matches_with_method(&self, method: &str, other: &HTTPRequestHeader) -> bool {
+        self.method.is_match_at(method, 0)
+            && self.path.is_match_at(&other.path, 0)
+            && (self.http_version == other.http_version
+                // HTTP/2 keeps HTTP/1.1's semantics, only the framing differs, so 1.1 routes serve both
+                || (self.http_version == "1.1" && other.http_version == "2.0"))
+    }
+}
+
 /// In this partial eq implementation, we use function parameter pattern matchin (see https://doc.rust-lang.org/book/ch18-01-all-the-places-for-patterns.html#function-parameters) to extract only the headers, which we then use for comparison.
 /// The eq method still takes the whole, request, but we only care about the headers and wildcard the body.
 impl PartialEq<HTTPRequest> for InternalRoute {
     fn eq(&self, HTTPRequest(other, _): &HTTPRequest) -> bool {
-        self.method.is_match_at(&other.method, 0)
-            && self.path.is_match_at(&other.path, 0)
-            && self.http_version == other.http_version
+        self.matches(other)
     }
 }
 
@@ -32,8 +69,44 @@ where
     }
 }
 
+/// A host name a [`Router`] is registered for with [`Router::host`]
+#[derive(Debug)]
+enum HostPattern {
+    Exact(String),
+    /// `*.example.test`, stored as `.example.test`. Matches subdomains at any depth, but not `example.test` itself
+    Wildcard(String),
+}
+
+impl HostPattern {
+    fn new(host: &str) -> Self {
+        let host = host.to_ascii_lowercase();
+        match host.strip_prefix('*') {
+            Some(suffix) if suffix.starts_with('.') => Self::Wildcard(suffix.to_owned()),
+            _ => Self::Exact(host),
+        }
+    }
+
+    fn matches(&self, host: &str) -> bool {
+        match self {
+            Self::Exact(exact) => exact == host,
+            Self::Wildcard(suffix) => host.len() > suffix.len() && host.ends_with(suffix.as_str()),
+        }
+    }
+}
+
 pub struct Router {
     internal_route_vec: Vec<InternalRoute>,
+    hosts: Vec<(HostPattern, Router)>,
+    limits: Limits,
+    panic_response: fn() -> Box<HTTPResponses>,
+    sessions: Option<Arc<Sessions>>,
+    rate_limit: Option<RateLimit>,
+    trusted_proxies: TrustedProxies,
+    access_log: Option<AccessLog>,
+    /// The metrics and the path they are served at
+    metrics: Option<(Metrics, String)>,
+    /// Which client addresses may read the metrics
+    metrics_acl: Option<IpAcl>,
 }
 
 impl Default for Router {
@@ -46,15 +119,198 @@ impl Router {
     pub fn new() -> Self {
         Self {
             internal_route_vec: Vec::new(),
+            hosts: Vec::new(),
+            limits: Limits::default(),
+            panic_response: HTTPResponses::internal_server_error,
+            sessions: None,
+            rate_limit: None,
+            trusted_proxies: TrustedProxies::default(),
+            access_log: None,
+            metrics: None,
+            metrics_acl: None,
         }
     }
 
-    /// Consumes self and other router and attaches other router's routes to current router
+    /// Consumes self and replaces the response sent when a route's callback panics. The default is [`HTTPResponses::internal_server_error`].
+    pub fn on_panic(mut self, panic_response: fn() -> Box<HTTPResponses>) -> Self {
+        self.panic_response = panic_response;
+        self
+    }
+
+    /// Consumes self and loads each request's [`crate::Session`] before its route runs, saving it afterwards.
+    /// Like the panic response, a router given to [`Router::host`] uses its own sessions rather than these.
+    pub fn with_sessions(mut self, sessions: Sessions) -> Self {
+        self.sessions = Some(Arc::new(sessions));
+        self
+    }
+
+    /// Consumes self and believes the forwarding headers of requests from `proxies` when working out [`HTTPRequestHeader::client`].
+    /// Only this router's proxies are used, as the client is worked out before the request is handed to a router given to [`Router::host`].
+    pub fn with_trusted_proxies(mut self, proxies: TrustedProxies) -> Self {
+        self.trusted_proxies = proxies;
+        self
+    }
+
+    /// Consumes self and sets the log the server writes a line to for every request it answers, see [`Router::access_log`]
+    pub fn with_access_log(mut self, log: AccessLog) -> Self {
+        self.access_log = Some(log);
+        self
+    }
+
+    /// The log set with [`Router::with_access_log`]. The router doesn't write to it itself, as only the server knows when a response has been sent and how long that took
+    pub fn access_log(&self) -> Option<&AccessLog> {
+        self.access_log.as_ref()
+    }
+
+    /// Consumes self, counts every request the router answers in `metrics` and serves them to `GET path` requests, on every host, in Prometheus' text format.
+    /// Requests for the metrics themselves aren't counted.
+    pub fn with_metrics(mut self, metrics: Metrics, path: &str) -> Self {
+        self.metrics = Some((metrics, path.to_owned()));
+        self
+    }
+
+    /// Consumes self and only serves the metrics set with [`Router::with_metrics`] to the addresses `acl` allows, looking through trusted proxies. Others get an `HTTP 403`, as do requests whose address isn't known.
+    pub fn with_metrics_acl(mut self, acl: IpAcl) -> Self {
+        self.metrics_acl = Some(acl);
+        self
+    }
+
+    /// The metrics set with [`Router::with_metrics`], for the server to count connections, bytes and requests it couldn't parse
+    pub fn metrics(&self) -> Option<&Metrics> {
+        self.metrics.as_ref().map(|(metrics, _)| metrics)
+    }
+
+    /// Who the request came from, looking through the proxies set with [`Router::with_trusted_proxies`]. The router fills this in at [`HTTPRequestHeader::client`] itself, this is for the server to know it too
+    pub fn client(&self, header: &HTTPRequestHeader) -> ClientInfo {
+        self.trusted_proxies.resolve(header)
+    }
+
+    /// Consumes self and counts every request against `limit`, including those no route matches, before the route's own [`RouteOptions::rate_limit`].
+    /// Like the sessions, a router given to [`Router::host`] uses its own limit rather than this one.
+    pub fn with_rate_limit(mut self, limit: RateLimit) -> Self {
+        self.rate_limit = Some(limit);
+        self
+    }
+
+    /// Consumes self and guards every route registered so far, including those of routers given to [`Router::host`], that doesn't have a guard of its own.
+    /// Routes registered afterwards are not guarded, so a group of routes can be guarded by building them in their own router and attaching it with [`Router::with`].
+    pub fn with_auth(mut self, guard: AuthGuard) -> Self {
+        self.guard(&guard);
+        self
+    }
+
+    fn guard(&mut self, guard: &AuthGuard) {
+        for route in &mut self.internal_route_vec {
+            route.options.auth.get_or_insert_with(|| guard.clone());
+        }
+        for (_, router) in &mut self.hosts {
+            router.guard(guard);
+        }
+    }
+
+    /// Consumes self and applies `cors` to every route registered so far, including those of routers given to [`Router::host`], that doesn't have a policy of its own.
+    /// Like [`Router::with_auth`], routes registered afterwards are left alone.
+    pub fn with_cors(mut self, cors: Cors) -> Self {
+        self.apply_cors(&cors);
+        self
+    }
+
+    fn apply_cors(&mut self, cors: &Cors) {
+        for route in &mut self.internal_route_vec {
+            route.options.cors.get_or_insert_with(|| cors.clone());
+        }
+        for (_, router) in &mut self.hosts {
+            router.apply_cors(cors);
+        }
+    }
+
+    /// Consumes self and restricts every route registered so far, including those of routers given to [`Router::host`], that doesn't have an ACL of its own, to the addresses `acl` allows.
+    /// Like [`Router::with_auth`], routes registered afterwards are left alone.
+    pub fn with_acl(mut self, acl: IpAcl) -> Self {
+        self.restrict(&acl);
+        self
+    }
+
+    fn restrict(&mut self, acl: &IpAcl) {
+        for route in &mut self.internal_route_vec {
+            route.options.acl.get_or_insert_with(|| acl.clone());
+        }
+        for (_, router) in &mut self.hosts {
+            router.restrict(acl);
+        }
+    }
+
+    /// Consumes self and replaces the router wide request size limits. Routes may still override the body limit through [`RouteOptions`].
+    pub fn with_limits(mut self, limits: Limits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    /// The router wide request size limits
+    pub fn limits(&self) -> &Limits {
+        &self.limits
+    }
+
+    /// The request size limits for the route that will handle a request with these headers.
+    /// Falls back to the router wide limits if no route matches or the route does not override them.
+    pub fn limits_for(&self, header: &HTTPRequestHeader) -> Limits {
+        let router = self.router_for(header);
+        let route = router
+            .internal_route_vec
+            .iter()
+            .find(|route| route.matches(header));
+        Limits {
+            max_body: route
+                .and_then(|route| route.options.max_body)
+                .unwrap_or(router.limits.max_body),
+            ..self.limits
+        }
+    }
+
+    /// Consumes self and other router and attaches other router's routes and hosts to current router. The current router's limits and panic response are kept.
     pub fn with(mut self, mut other: Router) -> Self {
         self.internal_route_vec
             .append(&mut other.internal_route_vec);
+        self.hosts.append(&mut other.hosts);
         self
     }
+
+    /// Consumes self and serves requests for `host` with `router` instead of this router's own routes.
+    /// `host` is matched case insensitively against the request's `Host` without its port. A leading `*.` matches any subdomain, e.g. `*.example.test` matches `api.example.test` but not `example.test`.
+    /// Exact hosts are preferred over wildcards, and longer wildcards over shorter ones. Requests for any other host, or without a host, are served by this router's own routes, which makes it the default host.
+    /// The request line and header limits are read before the host is known, so only this router's apply to them. `router`'s body limit and panic response are used for its requests.
+    pub fn host(mut self, host: &str, router: Router) -> Self {
+        self.hosts.push((HostPattern::new(host), router));
+        self
+    }
+
+    /// The router registered with [`Router::host`] for the request's host, or self if there isn't one
+    fn router_for(&self, header: &HTTPRequestHeader) -> &Router {
+        let Some(host) = header.host.as_deref() else {
+            return self;
+        };
+        let exact = self
+            .hosts
+            .iter()
+            .find(|(pattern, _)| matches!(pattern, HostPattern::Exact(exact) if exact == host))
+            .map(|(_, router)| router);
+        let wildcard = || {
+            self.hosts
+                .iter()
+                .filter_map(|(pattern, router)| match pattern {
+                    HostPattern::Wildcard(suffix) if pattern.matches(host) => {
+                        Some((suffix.len(), router))
+                    }
+                    _ => None,
+                })
+                .max_by_key(|(length, _)| *length)
+                .map(|(_, router)| router)
+        };
+        match exact.or_else(wildcard) {
+            Some(router) => router.router_for(header),
+            None => self,
+        }
+    }
     /// Registers a route in the router object. Consumes self and returns it back in either an Ok variant or an error when parsing the path
     /// # Parameters
     ///  * method      : The method name. This is matched as a string.
@@ -62,16 +318,35 @@ impl Router {
     ///  * http_version: This is matched as a string. The HTTP Version
     ///  * callback    : A function poiner that accepts an HTTP request and a vector of bytes being the body of the request. Return a Result variant comprising of Ok(good response) or Err(Error Response)            
     pub fn route(
+        self,
+        method: &str,
+        path: &str,
+        http_version: &str,
+        callback: fn(HTTPRequest) -> HTTPResult,
+    ) -> result::Result<Self, Error> {
+        self.route_with(
+            method,
+            path,
+            http_version,
+            RouteOptions::default(),
+            callback,
+        )
+    }
+
+    /// Same as [`Router::route`], but with [`RouteOptions`] that override the router wide settings for this route only.
+    pub fn route_with(
         mut self,
         method: &str,
         path: &str,
         http_version: &str,
+        options: RouteOptions,
         callback: fn(HTTPRequest) -> HTTPResult,
     ) -> result::Result<Self, Error> {
         self.internal_route_vec.push(InternalRoute {
             method: Regex::new(method)?,
             path: Regex::new(path)?,
             http_version: http_version.to_owned(),
+            options,
             callback,
         });
 
@@ -81,12 +356,593 @@ impl Router {
     /// Takes a mutable reference to self, consumes an HTTPRequest and body and returns a vector of bytes which is an HTTP Response encoded.
     /// If there aren't any routes that handle the request, then an `HTTP 404` error is returned. Additional errors may be returned from the callback of the route that handles the request.
     /// Is async, so it returns a [`Future`] with a [`Vec<u8>`] output.
+    /// The callback runs on tokio's blocking thread pool, so a slow callback does not stall other connections and the returned future can be abandoned (e.g. by a timeout) while it runs.
+    /// Requests to routes with [`ClientCertPolicy::Required`] that have no client certificate get an `HTTP 403` without the callback running.
+    /// Requests to routes with an [`AuthGuard`] that it doesn't let through get an `HTTP 401` or `HTTP 403` with a `WWW-Authenticate` challenge, also without the callback running.
+    /// CORS preflights for routes with a [`Cors`] policy are answered without the callback running, see [`Cors`].
+    /// Requests over a [`RateLimit`] get an `HTTP 429`, also without the callback running.
+    /// Requests from addresses the route's [`IpAcl`] doesn't allow get an `HTTP 403`, without the callback running either.
+    /// Every request is given an ID before the callback runs, see [`HTTPRequestHeader::assign_request_id`], which the response echoes in `X-Request-Id`.
+    /// If the callback panics, the panic is logged and the response set with [`Router::on_panic`] is returned instead, so one bad request never takes the connection down without an answer.
     pub async fn handle_request(&self, request: HTTPRequest) -> Vec<u8> {
-        self.internal_route_vec
+        self.respond(request).await.to_http1()
+    }
+
+    /// Same as [`Router::handle_request`], but returns the response before it is serialized, for protocols other than HTTP/1.1.
+    pub async fn respond(&self, mut request: HTTPRequest) -> RawHTTPResponse {
+        request.0.client = self.client(&request.0);
+        let sent = request.0.request_id.clone();
+        let id = request.0.assign_request_id().to_owned();
+        // a server that assigned the ID itself has recorded it already, recording it twice would log it twice
+        if sent.as_ref() != Some(&id) {
+            Span::current().record("request_id", id.as_str());
+        }
+        let mut response = self.answer(request).await;
+        response.set_request_id(&id);
+        response
+    }
+
+    /// Answers the request with the metrics if it asks for them, and otherwise dispatches it to the router for its host, counting it in the metrics
+    async fn answer(&self, request: HTTPRequest) -> RawHTTPResponse {
+        let Some((metrics, path)) = &self.metrics else {
+            return self.router_for(&request.0).dispatch(request).await;
+        };
+        let header = &request.0;
+        if header.method == "GET" && header.path.split('?').next() == Some(path) {
+            if let Some(acl) = &self.metrics_acl {
+                if !header.client.ip.is_some_and(|ip| acl.allows(ip)) {
+                    return HTTPResponses::forbidden().into();
+                }
+            }
+            return HTTPResponses::Custom {
+                code: 200,
+                message: "OK".to_owned(),
+                ctype: "text/plain; version=0.0.4".to_owned(),
+                headers: None,
+                body: metrics.render().into_bytes(),
+            }
+            .into();
+        }
+
+        let _in_flight = metrics.request_started();
+        let started = Instant::now();
+        let router = self.router_for(header);
+        let route = router
+            .internal_route_vec
+            .iter()
+            .find(|route| route.matches(header))
+            .map(|route| route.path.as_str().to_owned());
+        let method = header.method.clone();
+        let response = router.dispatch(request).await;
+        metrics.observe(
+            route.as_deref(),
+            &method,
+            response.status_code as u16,
+            started.elapsed(),
+        );
+        response
+    }
+
+    /// Runs the request through this router's own routes, ignoring [`Router::host`]
+    async fn dispatch(&self, request: HTTPRequest) -> RawHTTPResponse {
+        let router_limit = self
+            .rate_limit
+            .as_ref()
+            .and_then(|limit| limit.check(&request.0));
+        if let Some(decision) = router_limit.filter(|decision| !decision.allowed()) {
+            return decision.rejection();
+        }
+        let route = match self
+            .internal_route_vec
             .iter()
             .find(|route| route == &&request)
-            .ok_or(HTTPResponses::not_found())
-            .and_then(|route| (route.callback)(request))
-            .map_or_else(Response::to_response, Response::to_response)
+        {
+            Some(route) => route,
+            None => {
+                let mut response = self
+                    .preflight(&request.0)
+                    .unwrap_or_else(|| HTTPResponses::not_found().into());
+                if let Some(decision) = router_limit {
+                    response.headers.extend(decision.headers());
+                }
+                return response;
+            }
+        };
+
+        // the server's request span, if it has one, says which route the request went to
+        Span::current().record("route", route.path.as_str());
+        let origin = request.0.origin.clone();
+        if let Some(acl) = &route.options.acl {
+            if !request.0.client.ip.is_some_and(|ip| acl.allows(ip)) {
+                return HTTPResponses::forbidden().into();
+            }
+        }
+        let route_limit = route
+            .options
+            .rate_limit
+            .as_ref()
+            .and_then(|limit| limit.check(&request.0));
+        let mut response = match route_limit {
+            Some(decision) if !decision.allowed() => decision.rejection(),
+            _ => {
+                let mut response = self.run(route, request).await;
+                // the limit closest to running out is the one worth telling the client about
+                if let Some(decision) = [router_limit, route_limit]
+                    .into_iter()
+                    .flatten()
+                    .min_by_key(|decision| decision.remaining)
+                {
+                    response.headers.extend(decision.headers());
+                }
+                response
+            }
+        };
+        // browser apps need the CORS headers to read the 429 too
+        if let Some(cors) = &route.options.cors {
+            cors.apply(origin.as_deref(), &mut response);
+        }
+        response
+    }
+
+    /// Answers a CORS preflight, which is an `OPTIONS` request with an `Origin` and `Access-Control-Request-Method`, for the route that would handle the requested method.
+    /// Returns `None` if the request isn't a preflight or there's no such route with a [`Cors`] policy.
+    fn preflight(&self, header: &HTTPRequestHeader) -> Option<RawHTTPResponse> {
+        if header.method != "OPTIONS" || header.origin.is_none() {
+            return None;
+        }
+        let method = header.access_control_request_method.as_deref()?;
+        let matches_path = |route: &&InternalRoute| {
+            route.options.cors.is_some() && route.matches_with_method(method, header)
+        };
+        // routes for other methods on the same path may have a policy too, which is what answers the preflight when the method isn't allowed
+        let route = self
+            .internal_route_vec
+            .iter()
+            .find(matches_path)
+            .or_else(|| {
+                self.internal_route_vec.iter().find(|route| {
+                    route.options.cors.is_some() && route.path.is_match_at(&header.path, 0)
+                })
+            })?;
+        let cors = route.options.cors.as_ref()?;
+        Some(cors.preflight(header, |method| route.matches_with_method(method, header)))
+    }
+
+    /// Runs a request through the route that matched it
+    async fn run(&self, route: &InternalRoute, mut request: HTTPRequest) -> RawHTTPResponse {
+        match route.options.client_cert {
+            ClientCertPolicy::Required if request.0.connection.client_cert.is_none() => {
+                return HTTPResponses::forbidden().into()
+            }
+            ClientCertPolicy::Ignored => request.0.connection.client_cert = None,
+            _ => {}
+        }
+
+        let callback = route.callback;
+        let request_line = format!("{} {}", request.0.method, request.0.path);
+        let sessions = self.sessions.clone();
+        let auth = route.options.auth.clone();
+        // the callback's own logging belongs to the request too
+        let span = Span::current();
+        // credentials are checked and sessions loaded and saved on the blocking thread too, as hashing passwords and stores may block.
+        // A route that panics doesn't get its session changes saved
+        let (result, cookie) = tokio::task::spawn_blocking(move || {
+            let _entered = span.enter();
+            if let Some(auth) = auth {
+                match auth.check(&request.0) {
+                    Ok(principal) => request.0.principal = Some(principal),
+                    Err(response) => return (Ok(Err(response)), None),
+                }
+            }
+            let loaded = sessions
+                .as_ref()
+                .map(|sessions| sessions.load(&mut request.0));
+            let session = request.0.session.clone();
+            let result = catch_unwind(move || callback(request));
+            let cookie = match (&result, sessions, loaded) {
+                (Ok(_), Some(sessions), Some(loaded)) => sessions.save(loaded, &session),
+                _ => None,
+            };
+            (result, cookie)
+        })
+        .await
+        .unwrap_or_else(|err| match err.try_into_panic() {
+            Ok(panic) => (Err(panic), None),
+            // the task never ran as the runtime is shutting down
+            Err(err) => {
+                error!(
+                    "Route {} {} cancelled handling {request_line} => {err}",
+                    route.method, route.path
+                );
+                (Ok(Err(HTTPResponses::service_unavailable())), None)
+            }
+        });
+
+        let mut response = result
+            .unwrap_or_else(|panic| {
+                error!(
+                    "Route {} {} panicked handling {request_line} => {}",
+                    route.method,
+                    route.path,
+                    panic_message(panic.as_ref())
+                );
+                Err((self.panic_response)())
+            })
+            .map_or_else(RawHTTPResponse::from, RawHTTPResponse::from);
+        if let Some(cookie) = cookie {
+            response
+                .headers
+                .push(("Set-Cookie".to_owned(), cookie.to_string()));
+        }
+        response
+    }
+}
+
+/// Gets the message out of a panic payload, which is a `&str` or `String` for panics raised with `panic!`, `expect` and friends
+pub fn panic_message(payload: &(dyn Any + Send)) -> &str {
+    payload
+        .downcast_ref::<&str>()
+        .copied()
+        .or_else(|| payload.downcast_ref::<String>().map(String::as_str))
+        .unwrap_or("Box<dyn Any>")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{http_ok, valid_request_id};
+
+    fn request(path: &str) -> HTTPRequest {
+        HTTPRequest(
+            HTTPRequestHeader {
+                method: "GET".to_owned(),
+                path: path.to_owned(),
+                http_version: "1.1".to_owned(),
+                request_id: Some(REQUEST_ID.to_owned()),
+                ..Default::default()
+            },
+            Vec::new(),
+        )
+    }
+
+    const REQUEST_ID: &str = "test-request";
+
+    /// What the router sends back for `response` to a request made with [`request`], which echoes the request's ID
+    fn answered(response: impl Into<RawHTTPResponse>) -> Vec<u8> {
+        let mut response = response.into();
+        response.set_request_id(REQUEST_ID);
+        response.to_http1()
+    }
+
+    fn panics(_: HTTPRequest) -> HTTPResult {
+        panic!("Handler blew up")
+    }
+
+    #[tokio::test]
+    async fn handler_panic_becomes_error_response() {
+        let router = Router::new()
+            .route("GET", "/panic$", "1.1", panics)
+            .and_then(|router| router.route("GET", "/$", "1.1", |_| http_ok("Hi".into())))
+            .unwrap();
+
+        assert_eq!(
+            router.handle_request(request("/panic")).await,
+            answered(HTTPResponses::internal_server_error())
+        );
+        // the router keeps serving after a panic
+        assert_eq!(
+            router.handle_request(request("/")).await,
+            answered(HTTPResponses::PlainText("Hi".to_owned()))
+        );
+
+        let router = router.on_panic(HTTPResponses::service_unavailable);
+        assert_eq!(
+            router.handle_request(request("/panic")).await,
+            answered(HTTPResponses::service_unavailable())
+        );
+    }
+
+    fn has_cert(HTTPRequest(header, _): HTTPRequest) -> HTTPResult {
+        http_ok(header.connection.client_cert.is_some().to_string().into())
+    }
+
+    #[tokio::test]
+    async fn enforces_client_cert_policy() {
+        let policy = |client_cert| RouteOptions {
+            client_cert,
+            ..Default::default()
+        };
+        let router = Router::new()
+            .route_with(
+                "GET",
+                "/required$",
+                "1.1",
+                policy(ClientCertPolicy::Required),
+                has_cert,
+            )
+            .and_then(|router| {
+                router.route_with(
+                    "GET",
+                    "/ignored$",
+                    "1.1",
+                    policy(ClientCertPolicy::Ignored),
+                    has_cert,
+                )
+            })
+            .unwrap();
+        let with_cert = |path| {
+            let mut request = request(path);
+            request.0.connection.client_cert = Some(crate::ClientCertificate {
+                subject: "CN=test".to_owned(),
+                sans: Vec::new(),
+                fingerprint: "00".to_owned(),
+            });
+            request
+        };
+
+        assert_eq!(
+            router.handle_request(request("/required")).await,
+            answered(HTTPResponses::forbidden())
+        );
+        assert_eq!(
+            router.handle_request(with_cert("/required")).await,
+            answered(HTTPResponses::from("true"))
+        );
+        assert_eq!(
+            router.handle_request(with_cert("/ignored")).await,
+            answered(HTTPResponses::from("false"))
+        );
+    }
+
+    #[tokio::test]
+    async fn dispatches_by_host() {
+        let site = |callback| Router::new().route("GET", "/$", "1.1", callback).unwrap();
+        let router = Router::new()
+            .route("GET", "/$", "1.1", |_| http_ok("default".into()))
+            .unwrap()
+            .host("*.example.test", site(|_| http_ok("wildcard".into())))
+            .host("API.example.test", site(|_| http_ok("api".into())))
+            .host("*.eu.example.test", site(|_| http_ok("nested".into())));
+        let for_host = |host: Option<&str>| {
+            let mut request = request("/");
+            request.0.host = host.map(str::to_owned);
+            request
+        };
+
+        for (host, expected) in [
+            (Some("api.example.test"), "api"),
+            (Some("www.example.test"), "wildcard"),
+            (Some("a.b.example.test"), "wildcard"),
+            (Some("www.eu.example.test"), "nested"),
+            (Some("example.test"), "default"),
+            (Some("other.test"), "default"),
+            (None, "default"),
+        ] {
+            assert_eq!(
+                router.handle_request(for_host(host)).await,
+                answered(HTTPResponses::from(expected)),
+                "{host:?}"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn answers_cors_preflights() {
+        let router = Router::new()
+            .route("POST", "/user_json$", "1.1", |_| http_ok("saved".into()))
+            .unwrap()
+            .with_cors(
+                Cors::new()
+                    .allow_origin("https://app.example.test")
+                    .allow_headers(&["Content-Type"])
+                    .allow_credentials(true),
+            )
+            .route("GET", "/private$", "1.1", |_| http_ok("secret".into()))
+            .unwrap();
+        let cors_request = |method: &str, path, origin: &str| {
+            let mut request = request(path);
+            request.0.method = method.to_owned();
+            request.0.origin = Some(origin.to_owned());
+            request
+        };
+        let preflight = |method: &str, headers: &str, origin| {
+            let mut request = cors_request("OPTIONS", "/user_json", origin);
+            request.0.access_control_request_method = Some(method.to_owned());
+            request.0.access_control_request_headers = Some(headers.to_owned());
+            request
+        };
+
+        assert_eq!(
+            router
+                .handle_request(preflight(
+                    "POST",
+                    "content-type",
+                    "https://app.example.test"
+                ))
+                .await,
+            b"HTTP/1.1 204 No Content\r\n\
+            Vary: Origin\r\n\
+            Access-Control-Allow-Origin: https://app.example.test\r\n\
+            Access-Control-Allow-Credentials: true\r\n\
+            Vary: Access-Control-Request-Method, Access-Control-Request-Headers\r\n\
+            Access-Control-Allow-Methods: POST\r\n\
+            Access-Control-Allow-Headers: content-type\r\n\
+            X-Request-Id: test-request\r\n\r\n"
+        );
+        for rejected in [
+            preflight("POST", "content-type", "https://evil.test"),
+            preflight("DELETE", "", "https://app.example.test"),
+            preflight("POST", "x-secret", "https://app.example.test"),
+        ] {
+            assert_eq!(
+                router.handle_request(rejected).await,
+                answered(HTTPResponses::forbidden())
+            );
+        }
+
+        let response = router
+            .respond(cors_request(
+                "POST",
+                "/user_json",
+                "https://app.example.test",
+            ))
+            .await;
+        assert!(response.headers.contains(&(
+            "Access-Control-Allow-Origin".to_owned(),
+            "https://app.example.test".to_owned()
+        )));
+        // routes registered after the policy don't get it
+        assert_eq!(
+            router
+                .handle_request(cors_request("GET", "/private", "https://app.example.test"))
+                .await,
+            answered(HTTPResponses::from("secret"))
+        );
+        let mut request = request("/private");
+        request.0.method = "OPTIONS".to_owned();
+        request.0.origin = Some("https://app.example.test".to_owned());
+        request.0.access_control_request_method = Some("GET".to_owned());
+        assert_eq!(
+            router.handle_request(request).await,
+            answered(HTTPResponses::not_found())
+        );
+    }
+
+    #[tokio::test]
+    async fn restricts_routes_by_address() {
+        let router = Router::new()
+            .route("GET", "/admin$", "1.1", |_| http_ok("admin".into()))
+            .unwrap()
+            .with_acl(IpAcl::new().allow("10.0.0.0/8".parse().unwrap()))
+            .route("GET", "/public$", "1.1", |_| http_ok("public".into()))
+            .unwrap()
+            .with_trusted_proxies(TrustedProxies::new(vec!["192.0.2.1".parse().unwrap()]));
+        let from = |path, peer: &str, forwarded_for: Option<&str>| {
+            let mut request = request(path);
+            request.0.connection.peer = Some(peer.parse().unwrap());
+            request.0.forwarding.x_forwarded_for = forwarded_for.map(str::to_owned);
+            request
+        };
+
+        assert_eq!(
+            router
+                .handle_request(from("/admin", "10.1.2.3:5000", None))
+                .await,
+            answered(HTTPResponses::from("admin"))
+        );
+        // the client behind a trusted proxy is what counts
+        assert_eq!(
+            router
+                .handle_request(from("/admin", "192.0.2.1:5000", Some("10.1.2.3")))
+                .await,
+            answered(HTTPResponses::from("admin"))
+        );
+        for denied in [
+            from("/admin", "203.0.113.7:5000", None),
+            from("/admin", "192.0.2.1:5000", Some("203.0.113.7")),
+            request("/admin"),
+        ] {
+            assert_eq!(
+                router.handle_request(denied).await,
+                answered(HTTPResponses::forbidden())
+            );
+        }
+        assert_eq!(
+            router
+                .handle_request(from("/public", "203.0.113.7:5000", None))
+                .await,
+            answered(HTTPResponses::from("public"))
+        );
+    }
+
+    #[tokio::test]
+    async fn counts_requests_in_metrics() {
+        let metrics = Metrics::new();
+        let router = Router::new()
+            .route("GET", "/users/\\d+$", "1.1", |_| http_ok("user".into()))
+            .unwrap()
+            .with_metrics(metrics.clone(), "/metrics");
+
+        router.handle_request(request("/users/1")).await;
+        router.handle_request(request("/users/2")).await;
+        router.handle_request(request("/nowhere")).await;
+        let response = router.respond(request("/metrics?format=text")).await;
+        assert_eq!(response.status_code, 200);
+        let body = String::from_utf8(response.body).unwrap();
+        assert_eq!(body, metrics.render());
+        assert!(body.contains(
+            "http_requests_total{route=\"/users/\\\\d+$\",method=\"GET\",status=\"200\"} 2\n"
+        ));
+        assert!(body.contains(
+            "http_requests_total{route=\"unmatched\",method=\"GET\",status=\"404\"} 1\n"
+        ));
+        // scrapes aren't counted
+        assert!(!body.contains("route=\"/metrics\""));
+        assert!(body.contains("http_requests_in_flight 0\n"));
+    }
+
+    #[tokio::test]
+    async fn restricts_metrics_by_address() {
+        let router = Router::new()
+            .with_metrics(Metrics::new(), "/metrics")
+            .with_metrics_acl(IpAcl::new().allow("10.0.0.0/8".parse().unwrap()));
+        let from = |peer: Option<&str>| {
+            let mut request = request("/metrics");
+            request.0.connection.peer = peer.map(|peer| peer.parse().unwrap());
+            request
+        };
+
+        assert_eq!(
+            router
+                .respond(from(Some("10.1.2.3:5000")))
+                .await
+                .status_code,
+            200
+        );
+        for denied in [from(Some("203.0.113.7:5000")), from(None)] {
+            let response = router.respond(denied).await;
+            assert_eq!(response.status_code, 403);
+            assert!(!String::from_utf8(response.body)
+                .unwrap()
+                .contains("http_requests_total"));
+        }
+    }
+
+    #[tokio::test]
+    async fn tags_requests_with_ids() {
+        let router = Router::new()
+            .route("GET", "/id$", "1.1", |HTTPRequest(header, _)| {
+                http_ok(header.request_id.unwrap_or_default().into())
+            })
+            .unwrap();
+        let with_id = |id: Option<&str>| {
+            let mut request = request("/id");
+            request.0.request_id = id.map(str::to_owned);
+            request
+        };
+        let echoed = |response: &RawHTTPResponse| {
+            response
+                .headers
+                .iter()
+                .find(|(name, _)| name == "X-Request-Id")
+                .map(|(_, id)| id.clone())
+                .unwrap()
+        };
+
+        let response = router.respond(with_id(Some("abc-123"))).await;
+        assert_eq!(response.body, b"abc-123");
+        assert_eq!(echoed(&response), "abc-123");
+
+        for id in [None, Some("bad id\r\n")] {
+            let response = router.respond(with_id(id)).await;
+            let generated = echoed(&response);
+            assert!(valid_request_id(&generated));
+            // the handler sees the ID the response echoes
+            assert_eq!(response.body, generated.as_bytes());
+        }
+        // so do requests no route answers
+        let mut unrouted = request("/nowhere");
+        unrouted.0.request_id = None;
+        assert!(valid_request_id(&echoed(&router.respond(unrouted).await)));
     }
 }
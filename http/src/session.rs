@@ -0,0 +1,589 @@
+use crate::{Cookie, HTTPRequestHeader, SameSite};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use ring::rand::{SecureRandom, SystemRandom};
+use std::{
+    collections::HashMap,
+    fmt, fs, io,
+    path::PathBuf,
+    str::FromStr,
+    sync::{Arc, Mutex, MutexGuard},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+use tracing::error;
+
+/// Random bytes in a session ID. Base64 encoded, this gives IDs of [`SESSION_ID_CHARS`] characters
+const SESSION_ID_BYTES: usize = 32;
+const SESSION_ID_CHARS: usize = 43;
+
+/// How often stores look for expired sessions to throw away
+const SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+/// The data kept for a session between requests
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SessionRecord {
+    pub data: HashMap<String, String>,
+    /// When the session was started, for [`Sessions::absolute_timeout`]
+    pub created: SystemTime,
+    /// When the session was last used, for [`Sessions::idle_timeout`]
+    pub last_seen: SystemTime,
+}
+
+/// Where sessions are kept between requests. Called from the blocking thread pool, so implementations may block.
+pub trait SessionStore: Send + Sync {
+    fn load(&self, id: &str) -> io::Result<Option<SessionRecord>>;
+    fn save(&self, id: &str, record: &SessionRecord) -> io::Result<()>;
+    fn remove(&self, id: &str) -> io::Result<()>;
+}
+
+/// Keeps sessions in memory, so they are lost on restart. Sessions are dropped once they haven't been saved for `ttl`.
+pub struct MemoryStore {
+    ttl: Duration,
+    sessions: Mutex<HashMap<String, (SessionRecord, Instant)>>,
+    last_sweep: Mutex<Instant>,
+}
+
+impl MemoryStore {
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            ttl,
+            sessions: Mutex::new(HashMap::new()),
+            last_sweep: Mutex::new(Instant::now()),
+        }
+    }
+
+    fn sessions(&self) -> MutexGuard<'_, HashMap<String, (SessionRecord, Instant)>> {
+        self.sessions.lock().unwrap_or_else(|err| err.into_inner())
+    }
+}
+
+impl SessionStore for MemoryStore {
+    fn load(&self, id: &str) -> io::Result<Option<SessionRecord>> {
+        Ok(self
+            .sessions()
+            .get(id)
+            .filter(|(_, saved)| saved.elapsed() < self.ttl)
+            .map(|(record, _)| record.clone()))
+    }
+
+    fn save(&self, id: &str, record: &SessionRecord) -> io::Result<()> {
+        let mut sessions = self.sessions();
+        sessions.insert(id.to_owned(), (record.clone(), Instant::now()));
+        let mut last_sweep = self
+            .last_sweep
+            .lock()
+            .unwrap_or_else(|err| err.into_inner());
+        if last_sweep.elapsed() > SWEEP_INTERVAL {
+            sessions.retain(|_, (_, saved)| saved.elapsed() < self.ttl);
+            *last_sweep = Instant::now();
+        }
+        Ok(())
+    }
+
+    fn remove(&self, id: &str) -> io::Result<()> {
+        self.sessions().remove(id);
+        Ok(())
+    }
+}
+
+/// Keeps each session in its own file in a directory, so sessions survive restarts and can be shared between processes on one machine.
+/// Files that haven't been written to for `ttl` are deleted.
+pub struct FileStore {
+    dir: PathBuf,
+    ttl: Duration,
+    last_sweep: Mutex<Instant>,
+}
+
+impl FileStore {
+    /// Creates `dir` if it doesn't exist yet
+    pub fn new(dir: impl Into<PathBuf>, ttl: Duration) -> io::Result<Self> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir)?;
+        Ok(Self {
+            dir,
+            ttl,
+            last_sweep: Mutex::new(Instant::now()),
+        })
+    }
+
+    fn sweep(&self) -> io::Result<()> {
+        let mut last_sweep = self
+            .last_sweep
+            .lock()
+            .unwrap_or_else(|err| err.into_inner());
+        if last_sweep.elapsed() < SWEEP_INTERVAL {
+            return Ok(());
+        }
+        *last_sweep = Instant::now();
+        for entry in fs::read_dir(&self.dir)? {
+            let entry = entry?;
+            let expired = entry
+                .metadata()?
+                .modified()?
+                .elapsed()
+                .is_ok_and(|age| age > self.ttl);
+            // another process sharing the directory may have removed it already
+            if expired {
+                let _ = fs::remove_file(entry.path());
+            }
+        }
+        Ok(())
+    }
+}
+
+impl SessionStore for FileStore {
+    fn load(&self, id: &str) -> io::Result<Option<SessionRecord>> {
+        match fs::read_to_string(self.dir.join(id)) {
+            Ok(file) => decode_record(&file).map(Some).ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("Session file for {id} is corrupt"),
+                )
+            }),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+
+    fn save(&self, id: &str, record: &SessionRecord) -> io::Result<()> {
+        // write to a temporary file first so a crash never leaves a half written session behind
+        let temp = self.dir.join(format!(".{id}.tmp"));
+        fs::write(&temp, encode_record(record))?;
+        fs::rename(temp, self.dir.join(id))?;
+        self.sweep()
+    }
+
+    fn remove(&self, id: &str) -> io::Result<()> {
+        match fs::remove_file(self.dir.join(id)) {
+            Err(err) if err.kind() != io::ErrorKind::NotFound => Err(err),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Two lines with the timestamps, then a tab separated key and value per line
+fn encode_record(record: &SessionRecord) -> String {
+    let secs = |time: SystemTime| {
+        time.duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs()
+    };
+    let mut file = format!("{}\n{}\n", secs(record.created), secs(record.last_seen));
+    for (key, value) in &record.data {
+        file.push_str(&format!("{}\t{}\n", escape(key), escape(value)));
+    }
+    file
+}
+
+fn decode_record(file: &str) -> Option<SessionRecord> {
+    let mut lines = file.lines();
+    let mut time = || {
+        lines
+            .next()?
+            .parse()
+            .ok()
+            .map(|secs| UNIX_EPOCH + Duration::from_secs(secs))
+    };
+    let (created, last_seen) = (time()?, time()?);
+    let data = lines
+        .map(|line| {
+            let (key, value) = line.split_once('\t')?;
+            Some((unescape(key), unescape(value)))
+        })
+        .collect::<Option<_>>()?;
+    Some(SessionRecord {
+        data,
+        created,
+        last_seen,
+    })
+}
+
+fn escape(s: &str) -> String {
+    s.replace('\\', "\\\\")
+        .replace('\t', "\\t")
+        .replace('\n', "\\n")
+        .replace('\r', "\\r")
+}
+
+fn unescape(s: &str) -> String {
+    let mut unescaped = String::with_capacity(s.len());
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            unescaped.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('t') => unescaped.push('\t'),
+            Some('n') => unescaped.push('\n'),
+            Some('r') => unescaped.push('\r'),
+            Some(c) => unescaped.push(c),
+            None => break,
+        }
+    }
+    unescaped
+}
+
+#[derive(Debug, Default)]
+struct SessionState {
+    data: HashMap<String, String>,
+    rotate: bool,
+    destroyed: bool,
+}
+
+/// The session of the client making a request, at [`HTTPRequestHeader::session`]. Changes made by the route are saved once it returns, as long as the router has [`Sessions`] set up.
+/// Values are stored as strings, and converted to and from other types with [`FromStr`] and [`ToString`].
+#[derive(Clone, Default)]
+pub struct Session(Arc<Mutex<SessionState>>);
+
+impl Session {
+    fn state(&self) -> MutexGuard<'_, SessionState> {
+        self.0.lock().unwrap_or_else(|err| err.into_inner())
+    }
+
+    /// The value at `key`, if there is one and it parses into `T`
+    pub fn get<T: FromStr>(&self, key: &str) -> Option<T> {
+        self.state().data.get(key)?.parse().ok()
+    }
+
+    pub fn insert<T: ToString>(&self, key: &str, value: T) {
+        self.state().data.insert(key.to_owned(), value.to_string());
+    }
+
+    pub fn remove(&self, key: &str) {
+        self.state().data.remove(key);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.state().data.is_empty()
+    }
+
+    /// Moves the session to a new ID, keeping its data. Call this whenever the client's privileges change, e.g. on login, so an ID an attacker planted or saw beforehand is useless afterwards.
+    pub fn rotate_id(&self) {
+        self.state().rotate = true;
+    }
+
+    /// Throws the session away and tells the client to forget its ID, e.g. on logout
+    pub fn destroy(&self) {
+        let mut state = self.state();
+        state.data.clear();
+        state.destroyed = true;
+    }
+}
+
+/// Shows which keys are set, but not their values as they may be secret
+impl fmt::Debug for Session {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_set().entries(self.state().data.keys()).finish()
+    }
+}
+
+impl PartialEq for Session {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.0, &other.0) || self.state().data == other.state().data
+    }
+}
+
+impl Eq for Session {}
+
+/// The session a request came in with, so the changes can be saved after the route ran
+pub(crate) struct LoadedSession {
+    /// The ID of the live session the request belongs to
+    id: Option<String>,
+    /// Whether the client sent a session cookie at all, live or not
+    had_cookie: bool,
+    created: SystemTime,
+}
+
+/// Loads the session named by a cookie before each request and saves it afterwards. Set up on a router with [`crate::Router::with_sessions`].
+/// A session, and its cookie, only comes into existence once a route stores something in it.
+pub struct Sessions {
+    store: Box<dyn SessionStore>,
+    cookie_name: String,
+    idle_timeout: Duration,
+    absolute_timeout: Duration,
+    secure: bool,
+    rng: SystemRandom,
+}
+
+impl Sessions {
+    /// Sessions kept in `store`, with a cookie called `session` that expire after 30 minutes without a request or 24 hours after they started
+    pub fn new(store: impl SessionStore + 'static) -> Self {
+        Self {
+            store: Box::new(store),
+            cookie_name: "session".to_owned(),
+            idle_timeout: Duration::from_secs(30 * 60),
+            absolute_timeout: Duration::from_secs(24 * 60 * 60),
+            secure: false,
+            rng: SystemRandom::new(),
+        }
+    }
+
+    pub fn cookie_name(mut self, cookie_name: &str) -> Self {
+        self.cookie_name = cookie_name.to_owned();
+        self
+    }
+
+    /// How long a session lasts without any requests
+    pub fn idle_timeout(mut self, idle_timeout: Duration) -> Self {
+        self.idle_timeout = idle_timeout;
+        self
+    }
+
+    /// How long a session lasts after it started, however active it is
+    pub fn absolute_timeout(mut self, absolute_timeout: Duration) -> Self {
+        self.absolute_timeout = absolute_timeout;
+        self
+    }
+
+    /// Whether the session cookie is only sent over HTTPS. Should be set whenever the server is only reachable over TLS.
+    pub fn secure(mut self, secure: bool) -> Self {
+        self.secure = secure;
+        self
+    }
+
+    /// Attaches the session named by the request's cookie, or an empty one if there isn't a live session with that ID
+    pub(crate) fn load(&self, header: &mut HTTPRequestHeader) -> LoadedSession {
+        self.load_at(header, SystemTime::now())
+    }
+
+    fn load_at(&self, header: &mut HTTPRequestHeader, now: SystemTime) -> LoadedSession {
+        let id = header
+            .cookies
+            .get(&self.cookie_name)
+            // the ID comes from the client, so make sure it can't be used to reach outside the store, e.g. with a path
+            .filter(|id| {
+                id.len() == SESSION_ID_CHARS
+                    && id
+                        .bytes()
+                        .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_')
+            });
+        let record = id.and_then(|id| match self.store.load(id) {
+            Ok(record) => record,
+            Err(err) => {
+                error!("Could not load session => {err}");
+                None
+            }
+        });
+        let live = |record: &SessionRecord| {
+            let age = |time: SystemTime| now.duration_since(time).unwrap_or_default();
+            age(record.last_seen) < self.idle_timeout && age(record.created) < self.absolute_timeout
+        };
+
+        match (id, record) {
+            (Some(id), Some(record)) if live(&record) => {
+                header.session = Session::default();
+                header.session.state().data = record.data;
+                LoadedSession {
+                    id: Some(id.to_owned()),
+                    had_cookie: true,
+                    created: record.created,
+                }
+            }
+            // expired and unknown IDs are never reused, so clients can't pick their own session IDs
+            (id, record) => {
+                if let (Some(id), Some(_)) = (id, record) {
+                    self.remove(id);
+                }
+                LoadedSession {
+                    id: None,
+                    had_cookie: header.cookies.get(&self.cookie_name).is_some(),
+                    created: now,
+                }
+            }
+        }
+    }
+
+    /// Saves the session after the route ran, returning the cookie to send if the client's session ID changed
+    pub(crate) fn save(&self, loaded: LoadedSession, session: &Session) -> Option<Cookie> {
+        self.save_at(loaded, session, SystemTime::now())
+    }
+
+    fn save_at(&self, loaded: LoadedSession, session: &Session, now: SystemTime) -> Option<Cookie> {
+        let SessionState {
+            data,
+            rotate,
+            destroyed,
+        } = std::mem::take(&mut *session.state());
+        if destroyed || data.is_empty() {
+            if let Some(id) = &loaded.id {
+                self.remove(id);
+            }
+            return loaded.had_cookie.then(|| {
+                Cookie::removal(&self.cookie_name)
+                    .path("/")
+                    .http_only(true)
+                    .secure(self.secure)
+            });
+        }
+
+        let (id, issued) = match loaded.id {
+            Some(id) if !rotate => (id, false),
+            old => {
+                if let Some(old) = old {
+                    self.remove(&old);
+                }
+                (self.new_id()?, true)
+            }
+        };
+        let record = SessionRecord {
+            data,
+            created: loaded.created,
+            last_seen: now,
+        };
+        if let Err(err) = self.store.save(&id, &record) {
+            error!("Could not save session => {err}");
+            return None;
+        }
+        issued.then(|| {
+            Cookie::new(&self.cookie_name, &id)
+                .path("/")
+                .http_only(true)
+                .same_site(SameSite::Lax)
+                .secure(self.secure)
+        })
+    }
+
+    fn remove(&self, id: &str) {
+        if let Err(err) = self.store.remove(id) {
+            error!("Could not remove session => {err}");
+        }
+    }
+
+    fn new_id(&self) -> Option<String> {
+        let mut id = [0; SESSION_ID_BYTES];
+        match self.rng.fill(&mut id) {
+            Ok(()) => Some(URL_SAFE_NO_PAD.encode(id)),
+            Err(_) => {
+                error!(
+                    "Could not generate a session ID, the system's random number generator failed"
+                );
+                None
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{http_ok, HTTPRequest, HTTPResult, Router};
+
+    fn login(HTTPRequest(header, _): HTTPRequest) -> HTTPResult {
+        header.session.insert("user", "alice");
+        header.session.rotate_id();
+        http_ok("Logged in".into())
+    }
+
+    fn whoami(HTTPRequest(header, _): HTTPRequest) -> HTTPResult {
+        let user: Option<String> = header.session.get("user");
+        http_ok(user.unwrap_or_default().into())
+    }
+
+    fn logout(HTTPRequest(header, _): HTTPRequest) -> HTTPResult {
+        header.session.destroy();
+        http_ok("Logged out".into())
+    }
+
+    /// Sends a request with the session cookie, if there is one, returning the body and the new cookie if one was set
+    async fn send(router: &Router, path: &str, id: &str) -> (String, Option<String>) {
+        let mut header = HTTPRequestHeader {
+            method: "GET".to_owned(),
+            path: path.to_owned(),
+            http_version: "1.1".to_owned(),
+            ..Default::default()
+        };
+        if !id.is_empty() {
+            header.cookies.add_header(&format!("session={id}"));
+        }
+        let response = router.respond(HTTPRequest(header, Vec::new())).await;
+        let cookie = response
+            .headers
+            .iter()
+            .find(|(name, _)| name == "Set-Cookie")
+            .map(|(_, cookie)| cookie.split(';').next().unwrap()["session=".len()..].to_owned());
+        (String::from_utf8(response.body).unwrap(), cookie)
+    }
+
+    #[tokio::test]
+    async fn keeps_state_between_requests() {
+        let router = Router::new()
+            .route("GET", "/login$", "1.1", login)
+            .and_then(|router| router.route("GET", "/whoami$", "1.1", whoami))
+            .and_then(|router| router.route("GET", "/logout$", "1.1", logout))
+            .unwrap()
+            .with_sessions(Sessions::new(MemoryStore::new(Duration::from_secs(60))));
+
+        // nothing stored, so no session is started
+        assert_eq!(send(&router, "/whoami", "").await, (String::new(), None));
+
+        let (_, id) = send(&router, "/login", "").await;
+        let id = id.expect("Login should start a session");
+        assert_eq!(
+            send(&router, "/whoami", &id).await,
+            ("alice".to_owned(), None)
+        );
+
+        // logging in again moves the session to a new ID and the old one stops working
+        let (_, rotated) = send(&router, "/login", &id).await;
+        let rotated = rotated.expect("Login should rotate the session ID");
+        assert_ne!(rotated, id);
+        assert_eq!(send(&router, "/whoami", &id).await.0, "");
+        assert_eq!(send(&router, "/whoami", &rotated).await.0, "alice");
+
+        assert_eq!(
+            send(&router, "/logout", &rotated).await.1,
+            Some(String::new())
+        );
+        assert_eq!(send(&router, "/whoami", &rotated).await.0, "");
+    }
+
+    #[test]
+    fn expires_sessions() {
+        let sessions = Sessions::new(MemoryStore::new(Duration::from_secs(60 * 60)))
+            .idle_timeout(Duration::from_secs(60))
+            .absolute_timeout(Duration::from_secs(5 * 60));
+        let start = SystemTime::now();
+        // a request `secs` after the start with the session `id`, returning who it was logged in as and the ID it ends up with
+        let visit = |id: &str, secs: u64| {
+            let mut header = HTTPRequestHeader::default();
+            header.cookies.add_header(&format!("session={id}"));
+            let loaded = sessions.load_at(&mut header, start + Duration::from_secs(secs));
+            let user: Option<String> = header.session.get("user");
+            header.session.insert("user", "alice");
+            let cookie =
+                sessions.save_at(loaded, &header.session, start + Duration::from_secs(secs));
+            (
+                user,
+                cookie.map_or(id.to_owned(), |cookie| cookie.value().to_owned()),
+            )
+        };
+
+        let (_, id) = visit("", 0);
+        assert_eq!(visit(&id, 59), (Some("alice".to_owned()), id.clone()));
+        // a minute without requests ends it, and the next request starts a new one
+        let (user, id) = visit(&id, 119);
+        assert_eq!(user, None);
+
+        // a session in constant use still ends five minutes after it started
+        for secs in (149..419).step_by(30) {
+            assert_eq!(visit(&id, secs).0.as_deref(), Some("alice"));
+        }
+        let (user, renewed) = visit(&id, 419);
+        assert_eq!(user, None);
+        assert_ne!(renewed, id);
+        assert_eq!(visit(&id, 420).0, None);
+    }
+
+    #[test]
+    fn round_trips_session_files() {
+        let record = SessionRecord {
+            data: HashMap::from([
+                ("user".to_owned(), "alice".to_owned()),
+                ("note".to_owned(), "tab\there\nnew line \\ slash".to_owned()),
+            ]),
+            created: UNIX_EPOCH + Duration::from_secs(1_000),
+            last_seen: UNIX_EPOCH + Duration::from_secs(2_000),
+        };
+        assert_eq!(decode_record(&encode_record(&record)), Some(record));
+    }
+}
@@ -0,0 +1,87 @@
+use http::panic_message;
+use std::{io, time::Duration};
+use tokio::task::JoinError;
+use tracing::error;
+
+/// Shortest and longest pause after the process runs out of file descriptors or memory while accepting
+pub const MIN_BACKOFF: Duration = Duration::from_millis(10);
+pub const MAX_BACKOFF: Duration = Duration::from_secs(1);
+
+/// How the accept loop should react to an error from `accept()`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AcceptError {
+    /// Something went wrong with the one connection being accepted (e.g. the client gave up). Keep accepting.
+    Transient,
+    /// The process or system is out of a resource (file descriptors, buffers, memory). Pause accepting so connections can finish and free it up.
+    Exhausted,
+    /// The listener itself is broken and accepting again will not help
+    Fatal,
+}
+
+impl AcceptError {
+    pub fn classify(err: &io::Error) -> Self {
+        #[cfg(unix)]
+        if let Some(code) = err.raw_os_error() {
+            match code {
+                libc::EMFILE | libc::ENFILE | libc::ENOBUFS | libc::ENOMEM => {
+                    return Self::Exhausted
+                }
+                // Linux passes on pending network errors of the new socket through accept, see accept(2)
+                libc::ECONNABORTED
+                | libc::EPROTO
+                | libc::EPERM
+                | libc::ENETDOWN
+                | libc::ENETUNREACH
+                | libc::EHOSTDOWN
+                | libc::EHOSTUNREACH
+                | libc::ENOPROTOOPT
+                | libc::EOPNOTSUPP => return Self::Transient,
+                _ => {}
+            }
+        }
+
+        match err.kind() {
+            io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionRefused
+            | io::ErrorKind::Interrupted
+            | io::ErrorKind::WouldBlock
+            | io::ErrorKind::TimedOut => Self::Transient,
+            io::ErrorKind::OutOfMemory => Self::Exhausted,
+            _ => Self::Fatal,
+        }
+    }
+}
+
+/// Logs a connection task that did not finish normally. Panics are reported with their message, cancellations are expected during shutdown and ignored.
+pub fn log_task_result(result: Result<(), JoinError>) {
+    if let Err(err) = result {
+        if err.is_panic() {
+            error!(
+                "Connection task panicked => {}",
+                panic_message(err.into_panic().as_ref())
+            );
+        }
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn classifies_accept_errors() {
+        assert_eq!(
+            AcceptError::classify(&io::Error::from_raw_os_error(libc::EMFILE)),
+            AcceptError::Exhausted
+        );
+        assert_eq!(
+            AcceptError::classify(&io::Error::from_raw_os_error(libc::ECONNABORTED)),
+            AcceptError::Transient
+        );
+        assert_eq!(
+            AcceptError::classify(&io::Error::from_raw_os_error(libc::EBADF)),
+            AcceptError::Fatal
+        );
+    }
+}
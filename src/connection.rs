@@ -0,0 +1,1002 @@
+use crate::{
+    counting::Counted, http2, prefixed::Prefixed, proxy_protocol, shutdown::Shutdown,
+    tls::client_identity,
+};
+use http::{
+    AccessLogEntry, Cidr, ConnectionInfo, DeconstructedHTTPRequest, HTTPRequest, HTTPRequestHeader,
+    HTTPResponses, IpAcl, LimitError, Limits, Metrics, RawHTTPResponse, Router,
+};
+use std::{
+    io,
+    net::{IpAddr, SocketAddr},
+    str::from_utf8,
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::TcpStream,
+    time::{timeout, timeout_at, Instant},
+};
+use tokio_rustls::TlsAcceptor;
+use tracing::{
+    debug,
+    field::{display, Empty},
+    info_span, warn, Instrument, Span,
+};
+
+const BUF_SIZE: usize = 1024;
+
+/// Sent to accept an `Upgrade: h2c` request, before the connection switches to HTTP/2
+const SWITCHING_PROTOCOLS: &[u8] =
+    b"HTTP/1.1 101 Switching Protocols\r\nConnection: Upgrade\r\nUpgrade: h2c\r\n\r\n";
+
+/// How long each stage of a connection may take before it is abandoned
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Timeouts {
+    /// Time allowed from when a connection starts waiting for a request until its whole request line and header block have arrived. Time spent idle before the client starts sending counts towards it
+    pub header_read: Duration,
+    /// Time allowed to wait for each read of the body. Large bodies may take longer than this in total, as long as data keeps arriving.
+    pub body_read: Duration,
+    /// Time allowed for the route's callback to produce a response
+    pub handler: Duration,
+    /// Time allowed to write the response back to the client
+    pub write: Duration,
+    /// Slowest average rate, in bytes per second, a client may send its request at once `min_rate_grace` has passed. 0 disables the check.
+    pub min_data_rate: usize,
+    /// How long a connection may stay under `min_data_rate` before it counts against it
+    pub min_rate_grace: Duration,
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Self {
+            header_read: Duration::from_secs(10),
+            body_read: Duration::from_secs(30),
+            handler: Duration::from_secs(30),
+            write: Duration::from_secs(10),
+            min_data_rate: 256,
+            min_rate_grace: Duration::from_secs(5),
+        }
+    }
+}
+
+/// Reasons a request could not be read from a connection
+#[derive(Debug)]
+pub enum ReadError {
+    /// The client closed the connection before sending the complete request
+    Closed,
+    Io(io::Error),
+    Limit(LimitError),
+    /// The client is sending slower than [`Timeouts::min_data_rate`]
+    TooSlow,
+}
+
+/// Tracks the average rate a client is sending at, so connections that trickle data in (slowloris) can be dropped. Each HTTP/1.1 request and HTTP/2 stream gets its own
+pub struct RateGuard {
+    started: Instant,
+    received: usize,
+    min_data_rate: usize,
+    grace: Duration,
+}
+
+impl RateGuard {
+    pub fn new(timeouts: &Timeouts) -> Self {
+        Self {
+            started: Instant::now(),
+            received: 0,
+            min_data_rate: timeouts.min_data_rate,
+            grace: timeouts.min_rate_grace,
+        }
+    }
+
+    /// Records `read` more bytes and checks the average rate since the guard was created
+    pub fn record(&mut self, read: usize) -> Result<(), ReadError> {
+        self.received += read;
+        let elapsed = self.started.elapsed();
+        if self.min_data_rate > 0
+            && elapsed > self.grace
+            && (self.received as f64 / elapsed.as_secs_f64()) < self.min_data_rate as f64
+        {
+            Err(ReadError::TooSlow)
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// Keeps reading from the stream into a growable buffer until the blank line ending the headers is found, so headers split across several reads (or TCP segments) are kept whole.
+/// The buffer can never grow much beyond [`Limits::max_header_bytes`], as the limits are checked after every read.
+/// `head` is owned by the caller so it can tell whether anything was received if this is cancelled. On success, returns the index right after the blank line.
+async fn read_head<S: AsyncRead + Unpin>(
+    stream: &mut S,
+    head: &mut Vec<u8>,
+    limits: &Limits,
+    rate: &mut RateGuard,
+) -> Result<usize, ReadError> {
+    let mut buf: [u8; BUF_SIZE] = [0; BUF_SIZE];
+    loop {
+        let read = stream
+            .read(buf.as_mut_slice())
+            .await
+            .map_err(ReadError::Io)?;
+        if read == 0 {
+            return Err(ReadError::Closed);
+        }
+
+        // the blank line may straddle two reads, so look back a few bytes into what was already searched
+        let search_from = head.len().saturating_sub(3);
+        head.extend_from_slice(&buf[..read]);
+
+        let head_end = head[search_from..]
+            .windows(4)
+            .position(|window| window == b"\r\n\r\n")
+            .map(|position| search_from + position + 4);
+
+        limits
+            .check_head(&head[..head_end.unwrap_or(head.len())])
+            .map_err(ReadError::Limit)?;
+
+        if let Some(head_end) = head_end {
+            return Ok(head_end);
+        }
+        rate.record(read)?;
+    }
+}
+
+/// Reads the rest of the body into `body` until it holds at least `content_length` bytes. Anything past that is the start of the next request. Each read has to complete within [`Timeouts::body_read`].
+async fn read_body<S: AsyncRead + Unpin>(
+    stream: &mut S,
+    body: &mut Vec<u8>,
+    content_length: usize,
+    timeouts: &Timeouts,
+    rate: &mut RateGuard,
+) -> Result<(), Option<ReadError>> {
+    let mut buf: [u8; BUF_SIZE] = [0; BUF_SIZE];
+    while body.len() < content_length {
+        let read = timeout(timeouts.body_read, stream.read(buf.as_mut_slice()))
+            .await
+            .map_err(|_| None)?
+            .map_err(|err| Some(ReadError::Io(err)))?;
+        if read == 0 {
+            return Err(Some(ReadError::Closed));
+        }
+        body.extend_from_slice(&buf[..read]);
+        rate.record(read).map_err(Some)?;
+    }
+    Ok(())
+}
+
+/// A request read off a connection, and what it asks of the connection
+#[derive(Debug)]
+struct Incoming {
+    request: HTTPRequest,
+    /// Whether it asks to switch to HTTP/2, see [`http2::wants_upgrade`]. Only cleartext requests without a body are switched, others are answered over HTTP/1.1 as the upgrade mechanism allows.
+    upgrade: bool,
+    /// Whether the client may send another request on the connection once this one is answered, see [`keep_alive`]
+    keep_alive: bool,
+    /// Bytes received after the end of the request, which belong to the next one
+    rest: Vec<u8>,
+}
+
+/// Reads a whole request off the stream. The request line and headers have to arrive before `deadline`, the body is bounded by [`Timeouts::body_read`] instead.
+/// On failure, returns the error response to send back, or `None` if the connection should just be closed.
+async fn read_request<S: AsyncRead + Unpin>(
+    stream: &mut S,
+    router: &Router,
+    timeouts: &Timeouts,
+    deadline: Instant,
+    connection: &ConnectionInfo,
+) -> Result<Incoming, Option<Box<HTTPResponses>>> {
+    let mut rate = RateGuard::new(timeouts);
+    let mut head: Vec<u8> = Vec::with_capacity(BUF_SIZE);
+
+    let body_start = match timeout_at(
+        deadline,
+        read_head(stream, &mut head, router.limits(), &mut rate),
+    )
+    .await
+    {
+        Ok(Ok(body_start)) => body_start,
+        // a client that never sent anything gets no response, one that stalled part way through gets a 408
+        Err(_) if head.is_empty() => return Err(None),
+        Err(_) => {
+            warn!("Timed out reading request headers");
+            parse_error(router, "header_timeout");
+            return Err(Some(HTTPResponses::request_timeout()));
+        }
+        Ok(Err(ReadError::Closed)) => return Err(None),
+        Ok(Err(ReadError::Io(err))) => {
+            warn!("Could not read from stream! => {err}");
+            return Err(None);
+        }
+        Ok(Err(ReadError::Limit(err))) => {
+            warn!("Request rejected => {err:?}");
+            parse_error(router, err.kind());
+            return Err(Some(err.into()));
+        }
+        Ok(Err(ReadError::TooSlow)) => {
+            warn!("Closing connection sending request headers too slowly");
+            parse_error(router, "too_slow");
+            return Err(Some(HTTPResponses::request_timeout()));
+        }
+    };
+
+    let mut request_line = match DeconstructedHTTPRequest::try_from(&head[..body_start]) {
+        Ok(DeconstructedHTTPRequest(request_line, _)) => request_line,
+        Err(err) => {
+            warn!("Could not convert buffer to HTTP Request => {err}");
+            parse_error(router, "malformed");
+            return Err(Some(HTTPResponses::bad_request()));
+        }
+    };
+
+    request_line.connection = connection.clone();
+    record_request(&mut request_line);
+
+    // the body limit depends on the route, so it can only be checked once the headers are parsed
+    let limits = router.limits_for(&request_line);
+    let content_length = request_line.content_length.unwrap_or_default();
+    if let Err(err) = limits.check_body(content_length) {
+        warn!("Request rejected => {err:?}");
+        parse_error(router, err.kind());
+        return Err(Some(err.into()));
+    }
+
+    // allocate a vector of bytes that has a capcity of the content length if it exists or 0.
+    // This is safe as the content length was checked against the body limit above
+    let mut body: Vec<u8> = Vec::with_capacity(content_length);
+    body.extend_from_slice(&head[body_start..]);
+
+    // finish the stream if body length < content_length
+    match read_body(stream, &mut body, content_length, timeouts, &mut rate).await {
+        Ok(()) => {}
+        Err(None) => {
+            warn!(
+                "Timed out finishing body stream. Was able to read {} bytes out of {content_length} bytes.",
+                body.len()
+            );
+            parse_error(router, "body_timeout");
+            return Err(Some(HTTPResponses::request_timeout()));
+        }
+        Err(Some(ReadError::TooSlow)) => {
+            warn!("Closing connection sending request body too slowly");
+            parse_error(router, "too_slow");
+            return Err(Some(HTTPResponses::request_timeout()));
+        }
+        Err(Some(err)) => {
+            warn!(
+                "Error finishing body stream. Was able to read {} bytes out of {content_length} bytes. Error => {err:?}",
+                body.len()
+            );
+            return Err(None);
+        }
+    }
+    let rest = body.split_off(content_length);
+    let head = &head[..body_start];
+    Ok(Incoming {
+        upgrade: !connection.tls
+            && request_line.http_version == "1.1"
+            && content_length == 0
+            && http2::wants_upgrade(head),
+        keep_alive: keep_alive(&request_line, head),
+        request: HTTPRequest(request_line, body),
+        rest,
+    })
+}
+
+/// Whether the connection can be used for another request once `header` is answered. HTTP/1.1 connections are kept open unless the client sends `Connection: close`, HTTP/1.0 ones only if it sends `Connection: keep-alive`.
+/// Connections are closed after requests with a `Transfer-Encoding`, as their body isn't read and would be taken for the next request, and after `HEAD` requests, as they are answered with the body a `GET` would get, which the client won't read
+fn keep_alive(header: &HTTPRequestHeader, head: &[u8]) -> bool {
+    let wanted = match header.http_version.as_str() {
+        "1.1" => !header_lists(head, "connection", "close"),
+        "1.0" => header_lists(head, "connection", "keep-alive"),
+        _ => false,
+    };
+    wanted && header.method != "HEAD" && header_values(head, "transfer-encoding").next().is_none()
+}
+
+/// The values of every `name` header in a request head, trimmed
+pub fn header_values<'a>(head: &'a [u8], name: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+    from_utf8(head)
+        .unwrap_or_default()
+        .split("\r\n")
+        .skip(1)
+        .filter_map(|line| line.split_once(':'))
+        .filter(move |(header, _)| header.trim().eq_ignore_ascii_case(name))
+        .map(|(_, value)| value.trim())
+}
+
+/// Whether any `name` header in a request head lists `token` among its comma separated values, ignoring case
+pub fn header_lists(head: &[u8], name: &str, token: &str) -> bool {
+    header_values(head, name)
+        .flat_map(|value| value.split(','))
+        .any(|listed| listed.trim().eq_ignore_ascii_case(token))
+}
+
+/// Serves HTTP/1.1 requests on the connection one after the other for as long as the client keeps it alive, or hands it over to [`http2::serve`] if the client opens with the HTTP/2 preface (h2c with prior knowledge).
+/// Cleartext `Upgrade: h2c` requests get `101 Switching Protocols` and the connection carries on as HTTP/2, see [`http2::serve_upgraded`].
+/// Until the client starts sending a request the connection counts as idle: it is closed once [`Timeouts::header_read`] passes or the server starts shutting down. The same deadline then bounds the rest of the request head.
+/// Once a request has started it is always finished, even during shutdown, and answered with `Connection: close`.
+pub async fn handle_connection<S: AsyncRead + AsyncWrite + Unpin>(
+    mut stream: S,
+    connection: ConnectionInfo,
+    router: Arc<Router>,
+    timeouts: Timeouts,
+    mut shutdown: Shutdown,
+) {
+    let mut deadline = Instant::now() + timeouts.header_read;
+    let mut start = Vec::with_capacity(BUF_SIZE);
+    if !wait_for_request(&mut stream, &mut start, deadline, &mut shutdown).await {
+        return;
+    }
+    // the preface may be split across several reads, so keep reading until it is whole or something else turns up
+    while start.len() < http2::PREFACE.len() && http2::PREFACE.starts_with(&start) {
+        match timeout_at(deadline, read_more(&mut stream, &mut start)).await {
+            Ok(Ok(read)) if read > 0 => {}
+            _ => return,
+        }
+    }
+    let prior_knowledge = start.starts_with(http2::PREFACE);
+    // nothing read so far is lost, the HTTP/2 server or request parser reads it again first
+    let mut stream = Prefixed::new(start, stream);
+    if prior_knowledge {
+        return http2::serve(stream, connection, router, timeouts, shutdown).await;
+    }
+
+    loop {
+        match answer(
+            &mut stream,
+            &connection,
+            &router,
+            &timeouts,
+            deadline,
+            &shutdown,
+        )
+        .instrument(request_span())
+        .await
+        {
+            Answered::KeepAlive => {}
+            Answered::Close => return,
+            Answered::Upgrade(request) => {
+                return http2::serve_upgraded(
+                    stream, connection, router, timeouts, shutdown, *request,
+                )
+                .await
+            }
+        }
+        // a pipelined request is read straight back from what was left over
+        let mut next = Vec::with_capacity(BUF_SIZE);
+        deadline = Instant::now() + timeouts.header_read;
+        if !wait_for_request(&mut stream, &mut next, deadline, &mut shutdown).await {
+            debug!("Closing idle connection");
+            let _ = timeout(timeouts.write, stream.shutdown()).await;
+            return;
+        }
+        stream.unread(&next);
+    }
+}
+
+/// Waits for the client to start sending a request, reading what it sends first onto `start`.
+/// Returns `false` if the connection should be closed instead, as the client hung up, sent nothing before `deadline` or the server started shutting down
+async fn wait_for_request<S: AsyncRead + Unpin>(
+    stream: &mut S,
+    start: &mut Vec<u8>,
+    deadline: Instant,
+    shutdown: &mut Shutdown,
+) -> bool {
+    tokio::select! {
+        biased;
+        _ = shutdown.requested() => false,
+        read = timeout_at(deadline, read_more(stream, start)) => matches!(read, Ok(Ok(read)) if read > 0),
+    }
+}
+
+/// Reads whatever the client sends next onto the end of `start`, returning how much that was
+async fn read_more<S: AsyncRead + Unpin>(stream: &mut S, start: &mut Vec<u8>) -> io::Result<usize> {
+    let mut buf: [u8; BUF_SIZE] = [0; BUF_SIZE];
+    let read = stream.read(buf.as_mut_slice()).await?;
+    start.extend_from_slice(&buf[..read]);
+    Ok(read)
+}
+
+/// What becomes of a connection once a request on it has been answered
+#[derive(Debug)]
+enum Answered {
+    /// Wait for the next request
+    KeepAlive,
+    Close,
+    /// Switch to HTTP/2 and answer the request there
+    Upgrade(Box<HTTPRequest>),
+}
+
+/// Reads the request that has started to arrive on the connection, answers it and logs it. Its head has to arrive before `deadline`. Anything received after it is put back to be read as the next request.
+/// A request that asks to switch to h2c gets `101 Switching Protocols` instead and is returned, to be answered over HTTP/2
+async fn answer<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut Prefixed<S>,
+    connection: &ConnectionInfo,
+    router: &Router,
+    timeouts: &Timeouts,
+    deadline: Instant,
+    shutdown: &Shutdown,
+) -> Answered {
+    let (time, started) = (SystemTime::now(), Instant::now());
+    let incoming = read_request(stream, router, timeouts, deadline, connection).await;
+    if let Ok(Incoming { rest, .. }) = &incoming {
+        stream.unread(rest);
+    }
+    let (mut entry, mut response, keep_alive) = match incoming {
+        Ok(Incoming {
+            request,
+            upgrade: true,
+            ..
+        }) => {
+            let switch = async {
+                stream.write_all(SWITCHING_PROTOCOLS).await?;
+                stream.flush().await
+            };
+            return match timeout(timeouts.write, switch).await {
+                Ok(Ok(())) => {
+                    debug!("Switching to HTTP/2");
+                    Answered::Upgrade(Box::new(request))
+                }
+                Ok(Err(err)) => {
+                    warn!("Error writing response => {err}");
+                    Answered::Close
+                }
+                Err(_) => {
+                    warn!("Timed out writing response");
+                    Answered::Close
+                }
+            };
+        }
+        Ok(Incoming {
+            mut request,
+            keep_alive,
+            ..
+        }) => {
+            request.0.client = router.client(&request.0);
+            let entry = AccessLogEntry::new(&request.0, time);
+            match timeout(timeouts.handler, router.respond(request)).await {
+                Ok(response) => (entry, response, keep_alive),
+                Err(_) => {
+                    warn!("Handler did not respond within {:?}", timeouts.handler);
+                    (entry, HTTPResponses::service_unavailable().into(), false)
+                }
+            }
+        }
+        // what follows a request that couldn't be read can't be trusted to start the next one
+        Err(Some(response)) => (
+            unparsed_entry(router, connection, time),
+            RawHTTPResponse::from(response),
+            false,
+        ),
+        Err(None) => return Answered::Close,
+    };
+    let keep_alive = keep_alive && !shutdown.is_requested();
+    // the router echoes the request ID itself, this is for the responses sent without it
+    if let Some(id) = &entry.request_id {
+        response.set_request_id(id);
+    }
+    response.headers.push((
+        "Connection".to_owned(),
+        if keep_alive { "keep-alive" } else { "close" }.to_owned(),
+    ));
+    entry.set_response(&response);
+    let response = response.to_http1();
+
+    // shutting down the write half of a connection that is done flushes anything buffered and, for TLS, sends close_notify.
+    // The client may already have hung up once it has the whole response, so errors doing that don't matter
+    let write = async {
+        stream.write_all(response.as_slice()).await?;
+        stream.flush().await?;
+        if !keep_alive {
+            let _ = stream.shutdown().await;
+        }
+        Ok::<(), io::Error>(())
+    };
+    let written = match timeout(timeouts.write, write).await {
+        Ok(Ok(())) => true,
+        Ok(Err(err)) => {
+            warn!("Error writing response => {err}");
+            false
+        }
+        Err(_) => {
+            warn!("Timed out writing response");
+            false
+        }
+    };
+    finish_request(router, entry, started);
+    if keep_alive && written {
+        Answered::KeepAlive
+    } else {
+        Answered::Close
+    }
+}
+
+/// The span a request is handled in, a child of its connection's. The method, path and request ID are recorded once the request is parsed, see [`record_request`], the route by the router, and the status and latency by [`finish_request`]
+pub fn request_span() -> Span {
+    info_span!(
+        "request",
+        method = Empty,
+        path = Empty,
+        request_id = Empty,
+        route = Empty,
+        status = Empty,
+        latency_ms = Empty
+    )
+}
+
+/// Gives the request its ID, see [`HTTPRequestHeader::assign_request_id`], and records it, the method and the path on the current request span, so everything logged while the request is read and answered carries them
+pub fn record_request(header: &mut HTTPRequestHeader) {
+    Span::current()
+        .record("request_id", header.assign_request_id())
+        .record("method", header.method.as_str())
+        .record("path", header.path.as_str());
+}
+
+/// Records how the request was answered on the current request span, and writes it to the access log
+pub fn finish_request(router: &Router, mut entry: AccessLogEntry, started: Instant) {
+    entry.duration = started.elapsed();
+    Span::current().record("status", entry.status).record(
+        "latency_ms",
+        display(format_args!("{:.3}", entry.duration.as_secs_f64() * 1000.0)),
+    );
+    debug!("Answered request");
+    if let Some(log) = router.access_log() {
+        log.log(&entry);
+    }
+}
+
+/// Counts a request that couldn't be read in the router's metrics, if it has any
+pub fn parse_error(router: &Router, kind: &'static str) {
+    if let Some(metrics) = router.metrics() {
+        metrics.parse_error(kind);
+    }
+}
+
+/// An access log entry for a request that was answered before its headers could be parsed, e.g. with an `HTTP 400`. It is given a new request ID, which is recorded on the current request span
+pub fn unparsed_entry(
+    router: &Router,
+    connection: &ConnectionInfo,
+    time: SystemTime,
+) -> AccessLogEntry {
+    let mut header = HTTPRequestHeader {
+        connection: connection.clone(),
+        ..Default::default()
+    };
+    Span::current().record("request_id", header.assign_request_id());
+    AccessLogEntry::new(
+        &HTTPRequestHeader {
+            client: router.client(&header),
+            ..header
+        },
+        time,
+    )
+}
+
+/// The span a connection is served in. For connections through a load balancer, the client it names is recorded once its PROXY protocol header has been read
+pub fn connection_span(peer: SocketAddr) -> Span {
+    info_span!("connection", %peer, client = Empty)
+}
+
+/// Who may connect, and which peers say who they connect for
+#[derive(Debug, Clone, Default)]
+pub struct Admission {
+    /// Load balancers that send a PROXY protocol header ahead of each connection, see [`proxy_protocol::read_header`]. Empty if the PROXY protocol isn't accepted
+    pub proxy_protocol: Vec<Cidr>,
+    /// Which addresses may connect. For connections through a load balancer, the client it names is checked rather than the load balancer, unless its header names none (`LOCAL` or `UNKNOWN`)
+    pub acl: Option<IpAcl>,
+}
+
+impl Admission {
+    /// Whether a connection from `peer` may be read from. Load balancers are let through here, as the client they connect for is only known once its PROXY protocol header has been read
+    pub fn admits(&self, peer: IpAddr) -> bool {
+        self.acl.as_ref().is_none_or(|acl| acl.allows(peer))
+            || self.proxy_protocol.iter().any(|cidr| cidr.contains(peer))
+    }
+}
+
+/// Serves an accepted connection, first completing the TLS handshake if `tls` is set. The handshake has to finish within [`Timeouts::header_read`].
+/// Clients that negotiated `h2` through ALPN are served over HTTP/2, everyone else over HTTP/1.1.
+/// If [`Admission::proxy_protocol`] isn't empty, a PROXY protocol header is read before anything else, with the same deadline, and the client it names is checked against [`Admission::acl`].
+/// The peer's address, the addresses from the PROXY protocol header, and the identity from a verified client certificate, are attached to the request.
+/// The connection and the bytes sent over it after the PROXY protocol header, TLS included, are counted in the router's [`Metrics`].
+pub async fn serve(
+    mut socket: TcpStream,
+    peer: SocketAddr,
+    tls: Option<TlsAcceptor>,
+    admission: Arc<Admission>,
+    router: Arc<Router>,
+    timeouts: Timeouts,
+    shutdown: Shutdown,
+) {
+    let _open = router.metrics().map(Metrics::connection_opened);
+    let proxy = if admission.proxy_protocol.is_empty() {
+        None
+    } else {
+        match timeout(
+            timeouts.header_read,
+            proxy_protocol::read_header(&mut socket, peer, &admission.proxy_protocol),
+        )
+        .await
+        {
+            Ok(Ok(proxy)) => proxy,
+            Ok(Err(err)) => return warn!("Rejecting connection from {peer} => {err}"),
+            Err(_) => return warn!("PROXY protocol header from {peer} timed out"),
+        }
+    };
+    if let Some(proxy) = proxy {
+        Span::current().record("client", display(proxy.source));
+    }
+    if let Some(acl) = &admission.acl {
+        match proxy {
+            Some(proxy) if !acl.allows(proxy.source.ip()) => {
+                return warn!(
+                    "Rejecting connection from {} through {peer}, not allowed by the ACL",
+                    proxy.source
+                )
+            }
+            // a LOCAL or UNKNOWN header names no client, so the load balancer has to be allowed itself
+            None if !acl.allows(peer.ip()) => {
+                return warn!("Rejecting connection from {peer}, not allowed by the ACL")
+            }
+            _ => {}
+        }
+    }
+    let socket = Counted::new(socket, router.metrics().cloned());
+    match tls {
+        Some(acceptor) => match timeout(timeouts.header_read, acceptor.accept(socket)).await {
+            Ok(Ok(stream)) => {
+                let connection = ConnectionInfo {
+                    peer: Some(peer),
+                    tls: true,
+                    client_cert: stream
+                        .get_ref()
+                        .1
+                        .peer_certificates()
+                        .and_then(|chain| chain.first())
+                        .map(client_identity),
+                    proxy,
+                };
+                if stream.get_ref().1.alpn_protocol() == Some(b"h2") {
+                    http2::serve(stream, connection, router, timeouts, shutdown).await
+                } else {
+                    handle_connection(stream, connection, router, timeouts, shutdown).await
+                }
+            }
+            Ok(Err(err)) => warn!("TLS handshake with {peer} failed => {err}"),
+            Err(_) => warn!("TLS handshake with {peer} timed out"),
+        },
+        None => {
+            let connection = ConnectionInfo {
+                peer: Some(peer),
+                tls: false,
+                client_cert: None,
+                proxy,
+            };
+            handle_connection(socket, connection, router, timeouts, shutdown).await
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    #[tokio::test]
+    async fn reads_head_split_across_segments() {
+        let (mut client, mut server) = tokio::io::duplex(16);
+        let request = format!(
+            "GET / HTTP/1.1\r\nAuthorization: Bearer {}\r\n\r\nbody",
+            "x".repeat(4 * BUF_SIZE)
+        );
+        let expected = request.trim_end_matches("body").to_owned();
+        tokio::spawn(async move { client.write_all(request.as_bytes()).await });
+
+        let mut head = Vec::new();
+        let head_end = read_head(
+            &mut server,
+            &mut head,
+            &Limits::default(),
+            &mut RateGuard::new(&Timeouts::default()),
+        )
+        .await
+        .expect("Could not read head");
+        assert_eq!(&head[..head_end], expected.as_bytes());
+    }
+
+    #[tokio::test]
+    async fn rejects_head_over_cap() {
+        let (mut client, mut server) = tokio::io::duplex(BUF_SIZE);
+        tokio::spawn(async move {
+            let _ = client
+                .write_all(
+                    format!("GET / HTTP/1.1\r\nCookie: {}", "x".repeat(64 * BUF_SIZE)).as_bytes(),
+                )
+                .await;
+        });
+
+        let limits = Limits {
+            max_header_bytes: 4 * BUF_SIZE,
+            ..Limits::default()
+        };
+        assert!(matches!(
+            read_head(
+                &mut server,
+                &mut Vec::new(),
+                &limits,
+                &mut RateGuard::new(&Timeouts::default())
+            )
+            .await,
+            Err(ReadError::Limit(LimitError::HeadersTooLarge))
+        ));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn times_out_stalled_request() {
+        let (mut client, mut server) = tokio::io::duplex(BUF_SIZE);
+        client
+            .write_all(b"POST / HTTP/1.1\r\nHost: localhost\r\ncontent-length: 10\r\n\r\nabc")
+            .await
+            .unwrap();
+
+        let response = read_request(
+            &mut server,
+            &Router::new(),
+            &Timeouts::default(),
+            Instant::now() + Timeouts::default().header_read,
+            &ConnectionInfo::default(),
+        )
+        .await
+        .expect_err("Stalled body should not produce a request");
+        assert_eq!(response, Some(HTTPResponses::request_timeout()));
+
+        // a connection that never sends anything is closed without a response
+        let (_client, mut server) = tokio::io::duplex(BUF_SIZE);
+        let response = read_request(
+            &mut server,
+            &Router::new(),
+            &Timeouts::default(),
+            Instant::now() + Timeouts::default().header_read,
+            &ConnectionInfo::default(),
+        )
+        .await
+        .expect_err("Idle connection should not produce a request");
+        assert_eq!(response, None);
+    }
+
+    /// Reads one HTTP/1.1 response, which has to have a Content-Length, returning its head and body
+    async fn read_response<S: AsyncRead + Unpin>(stream: &mut S) -> (String, String) {
+        let mut head = Vec::new();
+        while !head.ends_with(b"\r\n\r\n") {
+            head.push(stream.read_u8().await.unwrap());
+        }
+        let head = String::from_utf8(head).unwrap();
+        let len = header_values(head.as_bytes(), "content-length")
+            .next()
+            .unwrap()
+            .parse()
+            .unwrap();
+        let mut body = vec![0; len];
+        stream.read_exact(&mut body).await.unwrap();
+        (head, String::from_utf8(body).unwrap())
+    }
+
+    fn hello() -> Router {
+        Router::new()
+            .route("GET", "/hello$", "1.1", |_| http::http_ok("Hi".into()))
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn keeps_connections_alive() {
+        let mut client = serve_duplex(hello());
+        // both requests arrive in one read, the second is read from what was left over from the first
+        client
+            .write_all(
+                b"GET /hello HTTP/1.1\r\nHost: a\r\n\r\nGET /hello HTTP/1.1\r\nHost: a\r\n\r\n",
+            )
+            .await
+            .unwrap();
+        for _ in 0..2 {
+            let (head, body) = read_response(&mut client).await;
+            assert!(head.contains("Connection: keep-alive\r\n"));
+            assert_eq!(body, "Hi");
+        }
+        client
+            .write_all(b"GET /hello HTTP/1.1\r\nHost: a\r\nConnection: close\r\n\r\n")
+            .await
+            .unwrap();
+        let (head, _) = read_response(&mut client).await;
+        assert!(head.contains("Connection: close\r\n"));
+        assert_eq!(client.read(&mut [0; 1]).await.unwrap(), 0);
+
+        // HTTP/1.0 clients have to ask for it, and chunked bodies can't be skipped to find the next request
+        for request in [
+            "GET /hello HTTP/1.0\r\n\r\n",
+            "GET /hello HTTP/1.1\r\nHost: a\r\nTransfer-Encoding: chunked\r\n\r\n0\r\n\r\n",
+        ] {
+            let mut client = serve_duplex(hello());
+            client.write_all(request.as_bytes()).await.unwrap();
+            let (head, _) = read_response(&mut client).await;
+            assert!(head.contains("Connection: close\r\n"));
+        }
+        let mut client = serve_duplex(hello());
+        client
+            .write_all(b"GET /hello HTTP/1.0\r\nConnection: Keep-Alive\r\n\r\n")
+            .await
+            .unwrap();
+        let (head, _) = read_response(&mut client).await;
+        assert!(head.contains("Connection: keep-alive\r\n"));
+    }
+
+    #[tokio::test]
+    async fn closes_idle_connections_on_shutdown() {
+        let (mut client, server) = tokio::io::duplex(BUF_SIZE);
+        let (start_shutdown, shutdown) = crate::shutdown::channel();
+        let served = tokio::spawn(handle_connection(
+            server,
+            ConnectionInfo::default(),
+            Arc::new(hello()),
+            Timeouts::default(),
+            shutdown,
+        ));
+        client
+            .write_all(b"GET /hello HTTP/1.1\r\nHost: a\r\n\r\n")
+            .await
+            .unwrap();
+        let (head, _) = read_response(&mut client).await;
+        assert!(head.contains("Connection: keep-alive\r\n"));
+
+        start_shutdown.send(true).unwrap();
+        timeout(Duration::from_secs(5), served)
+            .await
+            .expect("Idle connection was not closed")
+            .unwrap();
+        assert_eq!(client.read(&mut [0; 1]).await.unwrap(), 0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn counts_idle_time_towards_header_read() {
+        let mut client = serve_duplex(hello());
+        let started = Instant::now();
+        // the head starts arriving just before the deadline and never finishes
+        tokio::time::sleep(Duration::from_secs(9)).await;
+        client.write_all(b"GET /hello HTTP/1.1\r\n").await.unwrap();
+
+        let (head, _) = read_response(&mut client).await;
+        assert!(head.starts_with("HTTP/1.1 408"));
+        assert_eq!(started.elapsed(), Timeouts::default().header_read);
+    }
+
+    /// Reads one HTTP/2 frame, returning its type, flags, stream and payload
+    async fn read_frame<S: AsyncRead + Unpin>(stream: &mut S) -> (u8, u8, u32, Vec<u8>) {
+        let mut header = [0; 9];
+        stream.read_exact(&mut header).await.unwrap();
+        let len = u32::from_be_bytes([0, header[0], header[1], header[2]]) as usize;
+        let mut payload = vec![0; len];
+        stream.read_exact(&mut payload).await.unwrap();
+        let id = u32::from_be_bytes([header[5], header[6], header[7], header[8]]) & 0x7fff_ffff;
+        (header[3], header[4], id, payload)
+    }
+
+    /// An empty SETTINGS frame
+    const SETTINGS: [u8; 9] = [0, 0, 0, 4, 0, 0, 0, 0, 0];
+
+    #[tokio::test]
+    async fn checks_load_balancers_that_name_no_client_against_the_acl() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let admission = Arc::new(Admission {
+            proxy_protocol: vec!["127.0.0.1/32".parse().unwrap()],
+            acl: Some(IpAcl::new().allow("203.0.113.0/24".parse().unwrap())),
+        });
+        let router = Arc::new(hello());
+        let (_shutdown, shutdown) = crate::shutdown::channel();
+        let connect = |proxy_header: &'static [u8]| {
+            let (listener, admission, router, shutdown) = (
+                &listener,
+                admission.clone(),
+                router.clone(),
+                shutdown.clone(),
+            );
+            async move {
+                let mut client = TcpStream::connect(address).await.unwrap();
+                client.write_all(proxy_header).await.unwrap();
+                client
+                    .write_all(b"GET /hello HTTP/1.1\r\nHost: a\r\n\r\n")
+                    .await
+                    .unwrap();
+                let (socket, peer) = listener.accept().await.unwrap();
+                tokio::spawn(serve(
+                    socket,
+                    peer,
+                    None,
+                    admission,
+                    router,
+                    Timeouts::default(),
+                    shutdown,
+                ));
+                client
+            }
+        };
+
+        let mut client = connect(b"PROXY TCP4 203.0.113.9 127.0.0.1 4000 80\r\n").await;
+        let (head, body) = read_response(&mut client).await;
+        assert!(head.starts_with("HTTP/1.1 200"));
+        assert_eq!(body, "Hi");
+
+        // neither a v1 UNKNOWN nor a v2 LOCAL header lets the load balancer past the ACL
+        let local = b"\r\n\r\n\0\r\nQUIT\n\x20\0\0\0";
+        for proxy_header in [b"PROXY UNKNOWN\r\n".as_slice(), local] {
+            let mut client = connect(proxy_header).await;
+            // closed without an answer, or reset as the request was never read
+            let read = client.read(&mut [0; 1]).await;
+            assert!(matches!(read, Ok(0) | Err(_)), "{read:?}");
+        }
+    }
+
+    fn serve_duplex(router: Router) -> tokio::io::DuplexStream {
+        let (client, server) = tokio::io::duplex(64 * 1024);
+        let (shutdown_tx, shutdown) = crate::shutdown::channel();
+        tokio::spawn(async move {
+            handle_connection(
+                server,
+                ConnectionInfo::default(),
+                Arc::new(router),
+                Timeouts::default(),
+                shutdown,
+            )
+            .await;
+            drop(shutdown_tx);
+        });
+        client
+    }
+
+    #[tokio::test]
+    async fn detects_preface_split_across_reads() {
+        let mut client = serve_duplex(Router::new());
+        for part in [
+            &http2::PREFACE[..3],
+            &http2::PREFACE[3..20],
+            &http2::PREFACE[20..],
+        ] {
+            client.write_all(part).await.unwrap();
+            tokio::task::yield_now().await;
+        }
+        client.write_all(&SETTINGS).await.unwrap();
+        // the server's side of the connection starts with its SETTINGS
+        assert_eq!(read_frame(&mut client).await.0, 4);
+    }
+
+    #[tokio::test]
+    async fn upgrades_to_h2c() {
+        let mut client = serve_duplex(hello());
+        client
+            .write_all(
+                b"GET /hello HTTP/1.1\r\nHost: localhost\r\nConnection: Upgrade, HTTP2-Settings\r\n\
+                Upgrade: h2c\r\nHTTP2-Settings: AAMAAABkAAQCAAAAAAIAAAAA\r\n\r\n",
+            )
+            .await
+            .unwrap();
+        let mut switching = vec![0; SWITCHING_PROTOCOLS.len()];
+        client.read_exact(&mut switching).await.unwrap();
+        assert_eq!(switching, SWITCHING_PROTOCOLS);
+
+        client.write_all(http2::PREFACE).await.unwrap();
+        client.write_all(&SETTINGS).await.unwrap();
+        let (mut headers, mut data) = (None, Vec::new());
+        while headers.is_none() || data.is_empty() {
+            match read_frame(&mut client).await {
+                (1, _, 1, block) => headers = Some(block),
+                (0, _, 1, payload) => data.extend(payload),
+                _ => {}
+            }
+        }
+        // the upgrade request is answered on stream 1, the first header being `:status: 200` from the static table
+        assert_eq!(headers.unwrap()[0], 0x88);
+        assert_eq!(data, b"Hi");
+    }
+}
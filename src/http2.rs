@@ -0,0 +1,617 @@
+use crate::{
+    connection::{
+        finish_request, header_lists, header_values, parse_error, record_request, request_span,
+        RateGuard, Timeouts,
+    },
+    prefixed::Prefixed,
+    shutdown::Shutdown,
+};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use bytes::Bytes;
+use h2::{
+    server::{self, SendResponse},
+    Reason, RecvStream, SendStream,
+};
+use http::{
+    AccessLogEntry, Authorization, ClientInfo, ConnectionInfo, CookieJar, ForwardingHeaders,
+    HTTPRequest, HTTPRequestHeader, HTTPResponses, RawHTTPResponse, Router, Session,
+};
+use http_types::{header, header::HeaderName, Request, Response};
+use std::{future::poll_fn, io, sync::Arc, time::SystemTime};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite},
+    task::JoinSet,
+    time::{timeout, Instant},
+};
+use tracing::{warn, Instrument};
+
+/// The connection preface every HTTP/2 client opens with. Cleartext clients with prior knowledge send it straight away instead of an HTTP/1.1 request line.
+pub const PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+
+/// Stands in for an `Upgrade: h2c` request on stream 1, where RFC 7540 section 3.2 puts it although the client never sends it over HTTP/2.
+/// A HEADERS frame ending the stream with `GET /` over `http`, made of static HPACK table entries so it leaves the header compression state alone. The request read over HTTP/1.1 is what gets answered
+const UPGRADE_STREAM: [u8; 12] = [0, 0, 3, 0x1, 0x5, 0, 0, 0, 1, 0x82, 0x86, 0x84];
+
+/// Largest first frame accepted from a client that upgraded. It should be a SETTINGS frame, which is a few dozen bytes
+const MAX_FIRST_FRAME: usize = 16 * 1024;
+
+/// Frame type of the SETTINGS frame, which RFC 7540 section 3.5 requires straight after the preface
+const SETTINGS_FRAME: u8 = 0x4;
+
+/// How many streams a client may have open on one connection at once
+const MAX_CONCURRENT_STREAMS: u32 = 100;
+
+/// Headers that are specific to an HTTP/1.1 connection and are not allowed in HTTP/2 responses
+const CONNECTION_HEADERS: [&str; 5] = [
+    "connection",
+    "keep-alive",
+    "proxy-connection",
+    "transfer-encoding",
+    "upgrade",
+];
+
+/// Whether an HTTP/1.1 request head asks to switch to h2c the way RFC 7540 section 3.2 lays out: `Upgrade` lists `h2c`, `Connection` lists `Upgrade` and `HTTP2-Settings`, and there is exactly one `HTTP2-Settings` holding a base64url SETTINGS payload.
+/// The settings themselves aren't applied, as the client sends them again in the SETTINGS frame that starts its side of the connection, which is read before stream 1 is opened, see [`serve_upgraded`]
+pub fn wants_upgrade(head: &[u8]) -> bool {
+    let settings: Vec<&str> = header_values(head, "http2-settings").collect();
+    header_lists(head, "upgrade", "h2c")
+        && header_lists(head, "connection", "upgrade")
+        && header_lists(head, "connection", "http2-settings")
+        && matches!(settings[..], [settings] if URL_SAFE_NO_PAD
+            .decode(settings)
+            .is_ok_and(|payload| payload.len() % 6 == 0))
+}
+
+/// Serves an HTTP/2 connection, whether negotiated through ALPN or started with the cleartext [`PREFACE`].
+/// Each stream becomes its own request to the [`Router`], so a slow route never holds up the other streams on the connection.
+/// The connection is closed with a GOAWAY once it has had no open streams for [`Timeouts::header_read`] or the server starts shutting down. Streams that have already started are finished first.
+pub async fn serve<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: S,
+    connection: ConnectionInfo,
+    router: Arc<Router>,
+    timeouts: Timeouts,
+    shutdown: Shutdown,
+) {
+    serve_streams(stream, connection, router, timeouts, shutdown, None).await
+}
+
+/// Serves a connection that switched to HTTP/2 after `101 Switching Protocols` was sent for `request`, answering `request` on stream 1.
+/// The h2 server can't be told about a stream it never received, so the client's preface and first frame, which has to be its SETTINGS, are read here and followed by [`UPGRADE_STREAM`] to open stream 1 for it. Both have to arrive within [`Timeouts::header_read`]
+pub async fn serve_upgraded<S: AsyncRead + AsyncWrite + Unpin>(
+    mut stream: S,
+    connection: ConnectionInfo,
+    router: Arc<Router>,
+    timeouts: Timeouts,
+    shutdown: Shutdown,
+    request: HTTPRequest,
+) {
+    let start = match timeout(timeouts.header_read, read_client_start(&mut stream)).await {
+        Ok(Ok(start)) => start,
+        Ok(Err(err)) => return warn!("Client did not start HTTP/2 after upgrading => {err}"),
+        Err(_) => return warn!("Client did not start HTTP/2 in time after upgrading"),
+    };
+    let stream = Prefixed::new(start, stream);
+    serve_streams(
+        stream,
+        connection,
+        router,
+        timeouts,
+        shutdown,
+        Some(request),
+    )
+    .await
+}
+
+/// Reads the client's preface and first frame, and anything else that came along with them, returning it with [`UPGRADE_STREAM`] right after the first frame
+async fn read_client_start<S: AsyncRead + Unpin>(stream: &mut S) -> io::Result<Vec<u8>> {
+    let mut start = Vec::new();
+    read_at_least(stream, &mut start, PREFACE.len() + 9).await?;
+    if !start.starts_with(PREFACE) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Expected the HTTP/2 connection preface",
+        ));
+    }
+    let header = &start[PREFACE.len()..];
+    if header[3] != SETTINGS_FRAME {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Expected a SETTINGS frame after the connection preface",
+        ));
+    }
+    let frame_len = u32::from_be_bytes([0, header[0], header[1], header[2]]) as usize;
+    if frame_len > MAX_FIRST_FRAME {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "First frame is too large",
+        ));
+    }
+    let first_frame_end = PREFACE.len() + 9 + frame_len;
+    read_at_least(stream, &mut start, first_frame_end).await?;
+    start.splice(first_frame_end..first_frame_end, UPGRADE_STREAM);
+    Ok(start)
+}
+
+/// Reads onto the end of `buf` until it holds at least `len` bytes
+async fn read_at_least<S: AsyncRead + Unpin>(
+    stream: &mut S,
+    buf: &mut Vec<u8>,
+    len: usize,
+) -> io::Result<()> {
+    let mut chunk = [0; 1024];
+    while buf.len() < len {
+        let read = stream.read(&mut chunk).await?;
+        if read == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        buf.extend_from_slice(&chunk[..read]);
+    }
+    Ok(())
+}
+
+/// Serves the connection's streams. `upgraded` is the request that switched the connection to HTTP/2, answered on stream 1
+async fn serve_streams<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: S,
+    connection: ConnectionInfo,
+    router: Arc<Router>,
+    timeouts: Timeouts,
+    mut shutdown: Shutdown,
+    mut upgraded: Option<HTTPRequest>,
+) {
+    let handshake = server::Builder::new()
+        .max_concurrent_streams(MAX_CONCURRENT_STREAMS)
+        .max_header_list_size(router.limits().max_header_bytes as u32)
+        .handshake(stream);
+    let mut h2 = match timeout(timeouts.header_read, handshake).await {
+        Ok(Ok(h2)) => h2,
+        Ok(Err(err)) => {
+            warn!("HTTP/2 handshake failed => {err}");
+            return;
+        }
+        Err(_) => {
+            warn!("HTTP/2 handshake timed out");
+            return;
+        }
+    };
+
+    let mut streams = JoinSet::new();
+    let mut closing = false;
+    loop {
+        let idle = streams.is_empty() && !closing;
+        tokio::select! {
+            biased;
+            _ = shutdown.requested(), if !closing => {
+                h2.graceful_shutdown();
+                closing = true;
+            }
+            Some(_) = streams.join_next(), if !streams.is_empty() => {}
+            // accepting also drives the connection, so it has to keep being polled while streams are open
+            next = async {
+                if idle {
+                    timeout(timeouts.header_read, h2.accept()).await.ok()
+                } else {
+                    Some(h2.accept().await)
+                }
+            } => match next {
+                Some(Some(Ok((request, respond)))) => {
+                    let upgraded = upgraded.take_if(|_| u32::from(respond.stream_id()) == 1);
+                    streams.spawn(
+                        handle_stream(
+                            request,
+                            respond,
+                            upgraded,
+                            connection.clone(),
+                            Arc::clone(&router),
+                            timeouts,
+                        )
+                        .instrument(request_span()),
+                    );
+                }
+                Some(Some(Err(err))) => {
+                    warn!("HTTP/2 connection error => {err}");
+                    break;
+                }
+                Some(None) => break,
+                // idle for too long, let the client know no more streams will be accepted
+                None => {
+                    h2.graceful_shutdown();
+                    closing = true;
+                }
+            }
+        }
+    }
+    streams.shutdown().await;
+}
+
+/// Reads one stream's request, runs it through the router and sends the response back on the same stream.
+/// `upgraded` is the request to answer instead, for stream 1 of a connection that switched to HTTP/2 with `Upgrade: h2c`
+async fn handle_stream(
+    request: Request<RecvStream>,
+    mut respond: SendResponse<Bytes>,
+    upgraded: Option<HTTPRequest>,
+    connection: ConnectionInfo,
+    router: Arc<Router>,
+    timeouts: Timeouts,
+) {
+    let (time, started) = (SystemTime::now(), Instant::now());
+    let (parts, mut body) = request.into_parts();
+    let (mut request_line, read) = match upgraded {
+        Some(HTTPRequest(request_line, body)) => (request_line, Some(body)),
+        None => (request_header(&parts, connection), None),
+    };
+    record_request(&mut request_line);
+    request_line.client = router.client(&request_line);
+    let mut entry = AccessLogEntry::new(&request_line, time);
+
+    let read = match read {
+        Some(body) => Ok(body),
+        None => read_body(&mut body, &request_line, &router, &timeouts).await,
+    };
+    let mut response = match read {
+        Ok(body) => match timeout(
+            timeouts.handler,
+            router.respond(HTTPRequest(request_line, body)),
+        )
+        .await
+        {
+            Ok(response) => response,
+            Err(_) => {
+                warn!("Handler did not respond within {:?}", timeouts.handler);
+                HTTPResponses::service_unavailable().into()
+            }
+        },
+        Err(Some(response)) => response.into(),
+        Err(None) => return,
+    };
+
+    if let Some(id) = &entry.request_id {
+        response.set_request_id(id);
+    }
+    entry.set_response(&response);
+    if timeout(timeouts.write, send_response(&mut respond, response))
+        .await
+        .is_err()
+    {
+        warn!("Timed out writing response");
+        respond.send_reset(Reason::CANCEL);
+    }
+    finish_request(&router, entry, started);
+}
+
+/// The request header of a stream, from its pseudo-headers and headers
+fn request_header(
+    parts: &http_types::request::Parts,
+    connection: ConnectionInfo,
+) -> HTTPRequestHeader {
+    let header_str =
+        |name: header::HeaderName| parts.headers.get(name).and_then(|v| v.to_str().ok());
+    let header_list = |name: header::HeaderName| {
+        let values: Vec<&str> = parts
+            .headers
+            .get_all(name)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .collect();
+        (!values.is_empty()).then(|| values.join(", "))
+    };
+    let mut request_line = HTTPRequestHeader {
+        method: parts.method.to_string(),
+        path: parts
+            .uri
+            .path_and_query()
+            .map_or("/", |path| path.as_str())
+            .to_owned(),
+        http_version: "2.0".to_owned(),
+        content_length: header_str(header::CONTENT_LENGTH).and_then(|len| len.parse().ok()),
+        content_type: header_str(header::CONTENT_TYPE).map(str::to_owned),
+        host: None,
+        authority: None,
+        cookies: CookieJar::default(),
+        authorization: header_str(header::AUTHORIZATION)
+            .map(|value| Authorization(value.to_owned())),
+        origin: header_str(header::ORIGIN).map(str::to_owned),
+        access_control_request_method: header_str(header::ACCESS_CONTROL_REQUEST_METHOD)
+            .map(str::to_owned),
+        access_control_request_headers: header_str(header::ACCESS_CONTROL_REQUEST_HEADERS)
+            .map(str::to_owned),
+        user_agent: header_str(header::USER_AGENT).map(str::to_owned),
+        referer: header_str(header::REFERER).map(str::to_owned),
+        request_id: header_str(HeaderName::from_static("x-request-id")).map(str::to_owned),
+        forwarding: ForwardingHeaders {
+            forwarded: header_list(header::FORWARDED),
+            x_forwarded_for: header_list(HeaderName::from_static("x-forwarded-for")),
+            x_forwarded_proto: header_list(HeaderName::from_static("x-forwarded-proto")),
+            x_forwarded_host: header_list(HeaderName::from_static("x-forwarded-host")),
+        },
+        client: ClientInfo::default(),
+        principal: None,
+        session: Session::default(),
+        connection,
+    };
+    // HTTP/2 clients may split cookies over several headers to compress them better
+    for cookie in parts.headers.get_all(header::COOKIE) {
+        if let Ok(cookie) = cookie.to_str() {
+            request_line.cookies.add_header(cookie);
+        }
+    }
+    if let Some(host) = parts
+        .uri
+        .authority()
+        .map(|authority| authority.as_str())
+        .or_else(|| header_str(header::HOST))
+    {
+        request_line.set_host(host);
+    }
+    request_line
+}
+
+/// Reads the stream's DATA frames into a body, handing the flow control window back to the client as each frame is read.
+/// Each frame has to arrive within [`Timeouts::body_read`], and the stream as a whole has to keep up with [`Timeouts::min_data_rate`].
+/// On failure, returns the error response to send back, or `None` if the stream was reset and nothing can be sent.
+async fn read_body(
+    body: &mut RecvStream,
+    request_line: &HTTPRequestHeader,
+    router: &Router,
+    timeouts: &Timeouts,
+) -> Result<Vec<u8>, Option<Box<HTTPResponses>>> {
+    let limits = router.limits_for(request_line);
+    let content_length = request_line.content_length.unwrap_or_default();
+    let rejected = |err: http::LimitError| {
+        warn!("Request rejected => {err:?}");
+        parse_error(router, err.kind());
+        Some(Box::<HTTPResponses>::from(err))
+    };
+    limits.check_body(content_length).map_err(rejected)?;
+
+    // This is safe as the content length was checked against the body limit above
+    let mut buf = Vec::with_capacity(content_length);
+    let mut rate = RateGuard::new(timeouts);
+    loop {
+        match timeout(timeouts.body_read, body.data()).await {
+            Ok(Some(Ok(data))) => {
+                let _ = body.flow_control().release_capacity(data.len());
+                // the content length is only a hint in HTTP/2, the frames themselves are what counts
+                limits
+                    .check_body(buf.len() + data.len())
+                    .map_err(rejected)?;
+                buf.extend_from_slice(&data);
+                if rate.record(data.len()).is_err() {
+                    warn!("Closing stream sending request body too slowly");
+                    parse_error(router, "too_slow");
+                    return Err(Some(HTTPResponses::request_timeout()));
+                }
+            }
+            Ok(Some(Err(err))) => {
+                warn!(
+                    "Error finishing body stream. Was able to read {} bytes. Error => {err}",
+                    buf.len()
+                );
+                return Err(None);
+            }
+            Ok(None) => return Ok(buf),
+            Err(_) => {
+                warn!(
+                    "Timed out finishing body stream. Was able to read {} bytes.",
+                    buf.len()
+                );
+                parse_error(router, "body_timeout");
+                return Err(Some(HTTPResponses::request_timeout()));
+            }
+        }
+    }
+}
+
+/// Sends the response headers, then the body as fast as the client's flow control window allows
+async fn send_response(respond: &mut SendResponse<Bytes>, response: RawHTTPResponse) {
+    let (head, body) = to_h2(response);
+    let mut stream = match respond.send_response(head, body.is_empty()) {
+        Ok(stream) => stream,
+        Err(err) => {
+            warn!("Error writing response => {err}");
+            return;
+        }
+    };
+    if let Err(err) = send_body(&mut stream, body).await {
+        warn!("Error writing response => {err}");
+    }
+}
+
+async fn send_body(stream: &mut SendStream<Bytes>, mut body: Bytes) -> Result<(), h2::Error> {
+    while !body.is_empty() {
+        stream.reserve_capacity(body.len());
+        let capacity = match poll_fn(|cx| stream.poll_capacity(cx)).await {
+            Some(capacity) => capacity?,
+            None => return Err(h2::Error::from(Reason::STREAM_CLOSED)),
+        };
+        let chunk = body.split_to(capacity.min(body.len()));
+        stream.send_data(chunk, body.is_empty())?;
+    }
+    Ok(())
+}
+
+/// Converts a response into HTTP/2 headers and a body. Headers that only make sense on an HTTP/1.1 connection are dropped, and any that aren't valid HTTP/2 turn the response into an `HTTP 500`.
+fn to_h2(response: RawHTTPResponse) -> (Response<()>, Bytes) {
+    let mut head = Response::builder().status(response.status_code as u16);
+    for (name, value) in &response.headers {
+        if !CONNECTION_HEADERS.contains(&name.to_ascii_lowercase().as_str()) {
+            head = head.header(name.as_str(), value.as_str());
+        }
+    }
+    if response.allows_body() {
+        head = head.header(header::CONTENT_LENGTH, response.body.len());
+    }
+    let head = head.body(());
+    match head {
+        Ok(head) => (head, Bytes::from(response.body)),
+        Err(err) => {
+            warn!("Response can not be sent over HTTP/2 => {err}");
+            to_h2(HTTPResponses::internal_server_error().into())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::shutdown;
+    use http::http_ok;
+    use http_types::HeaderValue;
+    use std::time::Duration;
+    use tokio::io::AsyncWriteExt;
+
+    #[tokio::test]
+    async fn serves_multiplexed_streams() {
+        let router = Router::new()
+            .route("POST", "/echo$", "1.1", |HTTPRequest(_, body)| {
+                http_ok(String::from_utf8(body).unwrap().into())
+            })
+            .unwrap();
+        let (client, server) = tokio::io::duplex(64 * 1024);
+        let (_shutdown, shutdown) = shutdown::channel();
+        tokio::spawn(serve(
+            server,
+            ConnectionInfo::default(),
+            Arc::new(router),
+            Timeouts::default(),
+            shutdown,
+        ));
+
+        let (client, h2) = h2::client::handshake(client).await.unwrap();
+        tokio::spawn(h2);
+        let mut client = client.ready().await.unwrap();
+        let mut responses = Vec::new();
+        for body in ["first", "second"] {
+            let request = Request::post("http://localhost/echo").body(()).unwrap();
+            let (response, mut stream) = client.send_request(request, false).unwrap();
+            stream
+                .send_data(Bytes::from_static(body.as_bytes()), true)
+                .unwrap();
+            responses.push((body, response));
+        }
+        for (expected, response) in responses {
+            let (parts, mut body) = response.await.unwrap().into_parts();
+            assert_eq!(parts.status, 200);
+            assert_eq!(
+                parts.headers.get(header::CONTENT_TYPE),
+                Some(&HeaderValue::from_static("text/plain"))
+            );
+            let mut received = Vec::new();
+            while let Some(data) = body.data().await {
+                let data = data.unwrap();
+                let _ = body.flow_control().release_capacity(data.len());
+                received.extend_from_slice(&data);
+            }
+            assert_eq!(received, expected.as_bytes());
+        }
+        // the connection stays open for further requests once the first ones finished
+        let mut client = client.ready().await.unwrap();
+        let request = Request::get("http://localhost/missing").body(()).unwrap();
+        let (missing, _) = client.send_request(request, true).unwrap();
+        assert_eq!(missing.await.unwrap().status(), 404);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn answers_slow_request_bodies_with_408() {
+        let router = Router::new()
+            .route("POST", "/echo$", "1.1", |HTTPRequest(_, body)| {
+                http_ok(String::from_utf8(body).unwrap().into())
+            })
+            .unwrap();
+        let (client, server) = tokio::io::duplex(64 * 1024);
+        let (_shutdown, shutdown) = shutdown::channel();
+        tokio::spawn(serve(
+            server,
+            ConnectionInfo::default(),
+            Arc::new(router),
+            Timeouts::default(),
+            shutdown,
+        ));
+
+        let (client, h2) = h2::client::handshake(client).await.unwrap();
+        tokio::spawn(h2);
+        let mut client = client.ready().await.unwrap();
+        let request = Request::post("http://localhost/echo").body(()).unwrap();
+        let (response, mut stream) = client.send_request(request, false).unwrap();
+        // every frame arrives well within body_read, but the stream averages far below min_data_rate
+        tokio::spawn(async move {
+            for _ in 0..10 {
+                if stream
+                    .send_data(Bytes::from_static(b"slow"), false)
+                    .is_err()
+                {
+                    break;
+                }
+                tokio::time::sleep(Duration::from_secs(2)).await;
+            }
+        });
+        assert_eq!(response.await.unwrap().status(), 408);
+    }
+
+    #[test]
+    fn detects_upgrade_requests() {
+        let head =
+            |headers: &str| format!("GET / HTTP/1.1\r\nHost: localhost\r\n{headers}\r\n\r\n");
+        assert!(wants_upgrade(
+            head("Connection: Upgrade, HTTP2-Settings\r\nUpgrade: h2c\r\nHTTP2-Settings: AAMAAABk")
+                .as_bytes()
+        ));
+        assert!(wants_upgrade(
+            head("connection: upgrade\r\nConnection: http2-settings\r\nupgrade: websocket, H2C\r\nhttp2-settings: ").as_bytes()
+        ));
+        for headers in [
+            "Upgrade: h2c\r\nHTTP2-Settings: AAMAAABk",
+            "Connection: Upgrade\r\nUpgrade: h2c\r\nHTTP2-Settings: AAMAAABk",
+            "Connection: Upgrade, HTTP2-Settings\r\nUpgrade: h2\r\nHTTP2-Settings: AAMAAABk",
+            "Connection: Upgrade, HTTP2-Settings\r\nUpgrade: h2c",
+            "Connection: Upgrade, HTTP2-Settings\r\nUpgrade: h2c\r\nHTTP2-Settings: AAMA",
+            "Connection: Upgrade, HTTP2-Settings\r\nUpgrade: h2c\r\nHTTP2-Settings: AAMAAABk\r\nHTTP2-Settings: AAMAAABk",
+        ] {
+            assert!(!wants_upgrade(head(headers).as_bytes()), "{headers}");
+        }
+    }
+
+    #[tokio::test]
+    async fn opens_stream_one_after_first_frame() {
+        let (mut client, mut server) = tokio::io::duplex(1024);
+        let settings = [0, 0, 6, 4, 0, 0, 0, 0, 0, 0, 3, 0, 0, 0, 100];
+        let window_update = [0, 0, 4, 8, 0, 0, 0, 0, 0, 0, 0, 1, 0];
+        let mut sent = PREFACE.to_vec();
+        sent.extend(settings);
+        sent.extend(window_update);
+        tokio::spawn(async move {
+            for chunk in sent.chunks(7) {
+                client.write_all(chunk).await.unwrap();
+                tokio::task::yield_now().await;
+            }
+            client
+        });
+
+        let start = read_client_start(&mut server).await.unwrap();
+        let mut expected = PREFACE.to_vec();
+        expected.extend(settings);
+        expected.extend(UPGRADE_STREAM);
+        assert!(start.starts_with(&expected));
+        // whatever came after the first frame follows the stand-in for stream 1
+        assert!(window_update.starts_with(&start[expected.len()..]));
+
+        let (mut client, mut server) = tokio::io::duplex(1024);
+        client
+            .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .await
+            .unwrap();
+        assert!(read_client_start(&mut server).await.is_err());
+        // as does a client that hangs up part way through
+        let (mut client, mut server) = tokio::io::duplex(1024);
+        client.write_all(&PREFACE[..10]).await.unwrap();
+        drop(client);
+        assert!(read_client_start(&mut server).await.is_err());
+        // the first frame after the preface has to be SETTINGS
+        let (mut client, mut server) = tokio::io::duplex(1024);
+        client.write_all(PREFACE).await.unwrap();
+        client.write_all(&window_update).await.unwrap();
+        assert_eq!(
+            read_client_start(&mut server).await.unwrap_err().kind(),
+            io::ErrorKind::InvalidData
+        );
+    }
+}
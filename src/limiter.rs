@@ -0,0 +1,240 @@
+use clap::ValueEnum;
+use http::{HTTPResponses, Response};
+use std::{
+    collections::HashMap,
+    future::Future,
+    net::IpAddr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    sync::{OwnedSemaphorePermit, Semaphore},
+    time::timeout,
+};
+
+/// Connections being sent [`ConnectionLimiter::rejection`] at once. Past this, connections over the limit are closed straight away, so a flood of them can't tie up the file descriptors and memory the limit is there to protect
+const MAX_REJECTING: usize = 32;
+
+/// How long a rejected connection is read from once the rejection is sent, and how much of it at most
+const REJECTION_LINGER: Duration = Duration::from_secs(1);
+const REJECTION_DRAIN_BYTES: usize = 16 * 1024;
+
+/// What to do with a new connection when the server is already at its connection limit
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum OverloadPolicy {
+    /// Stop accepting until a connection finishes. New connections wait in the operating system's listen backlog
+    Queue,
+    /// Accept the connection and answer it with `503 Service Unavailable` and a `Retry-After` header
+    Reject,
+}
+
+/// Number of open connections for each client IP address
+type OpenPerIp = Arc<Mutex<HashMap<IpAddr, usize>>>;
+
+/// Caps the number of connections served at once, both in total and per client IP address
+pub struct ConnectionLimiter {
+    slots: Arc<Semaphore>,
+    policy: OverloadPolicy,
+    retry_after: u64,
+    max_per_ip: Option<usize>,
+    open_per_ip: OpenPerIp,
+    rejecting: Arc<Semaphore>,
+}
+
+/// Held by a connection task for as long as it runs. Frees the connection's slots when dropped
+pub struct ConnectionPermit {
+    _slot: OwnedSemaphorePermit,
+    ip: Option<(IpAddr, OpenPerIp)>,
+}
+
+impl Drop for ConnectionPermit {
+    fn drop(&mut self) {
+        if let Some((ip, open_per_ip)) = self.ip.take() {
+            let mut open_per_ip = open_per_ip.lock().unwrap_or_else(|err| err.into_inner());
+            if let Some(open) = open_per_ip.get_mut(&ip) {
+                *open -= 1;
+                if *open == 0 {
+                    open_per_ip.remove(&ip);
+                }
+            }
+        }
+    }
+}
+
+impl ConnectionLimiter {
+    /// # Parameters
+    ///  * max_connections: Connections served at once across all clients
+    ///  * policy         : What to do with connections over `max_connections`
+    ///  * retry_after    : Seconds sent in the `Retry-After` header of rejected connections
+    ///  * max_per_ip     : Connections served at once for a single client IP address, or `None` for no per client cap
+    pub fn new(
+        max_connections: usize,
+        policy: OverloadPolicy,
+        retry_after: u64,
+        max_per_ip: Option<usize>,
+    ) -> Self {
+        Self {
+            slots: Arc::new(Semaphore::new(max_connections)),
+            policy,
+            retry_after,
+            max_per_ip,
+            open_per_ip: Arc::new(Mutex::new(HashMap::new())),
+            rejecting: Arc::new(Semaphore::new(MAX_REJECTING)),
+        }
+    }
+
+    /// Whether the accept loop should [`ConnectionLimiter::reserve`] a slot before accepting
+    pub fn queues(&self) -> bool {
+        self.policy == OverloadPolicy::Queue
+    }
+
+    /// Waits for a free slot and reserves it. Used with [`OverloadPolicy::Queue`] before accepting, so the accept loop stops accepting while the server is full.
+    pub async fn reserve(&self) -> OwnedSemaphorePermit {
+        Arc::clone(&self.slots)
+            .acquire_owned()
+            .await
+            .expect("The connection semaphore is never closed")
+    }
+
+    /// Admits a connection from `ip`, using the slot from [`ConnectionLimiter::reserve`] if there is one.
+    /// Returns `None` if the server or the client is over its limit, in which case the connection should get [`ConnectionLimiter::rejection`].
+    pub fn admit(
+        &self,
+        ip: IpAddr,
+        reserved: Option<OwnedSemaphorePermit>,
+    ) -> Option<ConnectionPermit> {
+        let slot = match reserved {
+            Some(slot) => slot,
+            None => Arc::clone(&self.slots).try_acquire_owned().ok()?,
+        };
+
+        let ip = match self.max_per_ip {
+            Some(max_per_ip) => {
+                let mut open_per_ip = self
+                    .open_per_ip
+                    .lock()
+                    .unwrap_or_else(|err| err.into_inner());
+                let open = open_per_ip.entry(ip).or_default();
+                if *open >= max_per_ip {
+                    return None;
+                }
+                *open += 1;
+                Some((ip, Arc::clone(&self.open_per_ip)))
+            }
+            None => None,
+        };
+
+        Some(ConnectionPermit { _slot: slot, ip })
+    }
+
+    /// Sends [`ConnectionLimiter::rejection`] on a connection that was not admitted, to be spawned by the caller. Returns `None` if too many rejections are being sent already, in which case the connection should just be dropped.
+    /// The write side is shut down once the response is sent, then whatever the client sent is read for a moment before closing, as closing with unread data resets the connection and the client may lose the response.
+    pub fn reject<S>(
+        &self,
+        mut socket: S,
+        write_timeout: Duration,
+    ) -> Option<impl Future<Output = ()>>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let permit = Arc::clone(&self.rejecting).try_acquire_owned().ok()?;
+        let rejection = self.rejection();
+        Some(async move {
+            let _permit = permit;
+            let written = timeout(write_timeout, async {
+                socket.write_all(&rejection).await?;
+                socket.shutdown().await
+            })
+            .await;
+            if !matches!(written, Ok(Ok(()))) {
+                return;
+            }
+            let mut buf = [0; 1024];
+            let mut drained = 0;
+            let _ = timeout(REJECTION_LINGER, async {
+                while drained < REJECTION_DRAIN_BYTES {
+                    match socket.read(&mut buf).await {
+                        Ok(0) | Err(_) => break,
+                        Ok(read) => drained += read,
+                    }
+                }
+            })
+            .await;
+        })
+    }
+
+    /// The response written to connections that were not admitted
+    pub fn rejection(&self) -> Vec<u8> {
+        HTTPResponses::Custom {
+            code: 503,
+            message: "Service Unavailable".to_owned(),
+            ctype: "text/plain".to_owned(),
+            headers: Some(HashMap::from([(
+                "Retry-After".to_owned(),
+                self.retry_after.to_string(),
+            )])),
+            body: Vec::from(
+                "The server is handling too many connections. Try again later.".as_bytes(),
+            ),
+        }
+        .to_response()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn caps_total_and_per_ip() {
+        let limiter = ConnectionLimiter::new(3, OverloadPolicy::Reject, 1, Some(2));
+        let a: IpAddr = "10.0.0.1".parse().unwrap();
+        let b: IpAddr = "10.0.0.2".parse().unwrap();
+
+        let first = limiter.admit(a, None).expect("First connection from a");
+        let _second = limiter.admit(a, None).expect("Second connection from a");
+        assert!(limiter.admit(a, None).is_none(), "a is over its cap");
+
+        let _third = limiter.admit(b, None).expect("First connection from b");
+        assert!(limiter.admit(b, None).is_none(), "Server is full");
+
+        // closing a connection frees both its total and per ip slot
+        drop(first);
+        assert!(limiter.admit(a, None).is_some());
+    }
+
+    #[tokio::test]
+    async fn caps_rejections_in_flight() {
+        let limiter = ConnectionLimiter::new(1, OverloadPolicy::Reject, 1, None);
+        let (mut client, server) = tokio::io::duplex(4096);
+        client
+            .write_all(b"GET / HTTP/1.1\r\nHost: a.test\r\n\r\n")
+            .await
+            .unwrap();
+        let rejection = limiter
+            .reject(server, Duration::from_secs(1))
+            .expect("Room for a rejection");
+
+        // the others are closed without a response while the first is being sent
+        let pending: Vec<_> = (1..MAX_REJECTING)
+            .map(|_| limiter.reject(tokio::io::duplex(64).1, Duration::from_secs(1)))
+            .collect();
+        assert!(pending.iter().all(Option::is_some));
+        assert!(limiter
+            .reject(tokio::io::duplex(64).1, Duration::from_secs(1))
+            .is_none());
+        drop(pending);
+
+        // the client gets the whole response and then the end of the stream, even though its request was never read
+        let sent = tokio::spawn(rejection);
+        let mut response = Vec::new();
+        client.read_to_end(&mut response).await.unwrap();
+        assert_eq!(response, limiter.rejection());
+        drop(client);
+        sent.await.unwrap();
+        assert!(limiter
+            .reject(tokio::io::duplex(64).1, Duration::from_secs(1))
+            .is_some());
+    }
+}
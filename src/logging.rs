@@ -0,0 +1,19 @@
+use crate::parser::LogFormat;
+use std::io::{self, IsTerminal};
+use tracing_subscriber::EnvFilter;
+
+/// Sends diagnostics to stderr, leaving stdout to the access log. `filter` is a level, e.g. "debug", or per crate directives, e.g. "info,http=trace".
+/// Each line carries the spans it was logged in, so a request's events show its connection, method, path and route.
+pub fn init(filter: &str, format: LogFormat) -> Result<(), String> {
+    let filter = EnvFilter::try_new(filter).map_err(|err| format!("Invalid log level => {err}"))?;
+    let subscriber = tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(io::stderr)
+        // colours only help someone watching, they are noise in a file or log collector
+        .with_ansi(io::stderr().is_terminal());
+    match format {
+        LogFormat::Text => subscriber.try_init(),
+        LogFormat::Json => subscriber.json().try_init(),
+    }
+    .map_err(|err| format!("Could not set up logging => {err}"))
+}
@@ -1,83 +1,242 @@
+mod accept;
+mod connection;
+mod counting;
+mod http2;
+mod limiter;
+mod logging;
 mod parser;
+mod prefixed;
+mod proxy_protocol;
 mod sample_routes;
+mod shutdown;
+mod tls;
+use accept::{log_task_result, AcceptError, MAX_BACKOFF, MIN_BACKOFF};
 use clap::Parser;
-use http::{DeconstructedHTTPRequest, HTTPRequest, Router};
-use parser::HTTPArgs;
-use std::sync::Arc;
+use connection::{connection_span, serve, Admission};
+use http::{AuthGuard, BearerAuth, IpAcl, Metrics, Router};
+use parser::LogFormat;
+use std::{process::ExitCode, sync::Arc};
 use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt, BufReader},
-    net::{TcpListener, TcpStream},
+    net::TcpListener,
+    task::JoinSet,
+    time::{sleep_until, timeout, Instant},
 };
-
-const BUF_SIZE: usize = 1024;
-const RETRIES: u8 = 5;
-async fn handle_connection(stream: TcpStream, router: Arc<Router>) {
-    let mut buf: [u8; BUF_SIZE] = [0; BUF_SIZE];
-    let mut stream = BufReader::new(stream);
-    let request_size = stream
-        .read(buf.as_mut_slice())
-        .await
-        .expect("Could not read from stream!");
-
-    let DeconstructedHTTPRequest(request_line, body_start) = buf
-        .as_slice()
-        .try_into()
-        .expect("Could not convert buffer to HTTP Request");
-
-    println!("Request Line => {request_line:?}");
-
-    // allocate a vector of bytes that has a capcity of the content length if it exists or 0
-    let mut body: Vec<u8> = Vec::with_capacity(request_line.content_length.unwrap_or_default());
-
-    // finish the stream if body length < content_length
-    // Retry 3 times if failed to get current stream
-    if let Some(content_length) = request_line.content_length {
-        let mut retries = 1;
-        body.extend_from_slice(&buf[body_start..request_size]);
-        while body.len() < content_length && retries <= RETRIES {
-            let request_size = stream.read(buf.as_mut_slice()).await.unwrap_or_else(|err| {
-                retries += 1;
-                eprintln!("Error finishing body stream. Was able to read {} bytes out of {content_length} bytes.
-                Trying again. This is attempt {retries} out of {RETRIES}.
-                Error message: {err}", body.len());             
-                0
-            });
-            body.extend_from_slice(&buf[..request_size]);
-        }
-    }
-    println!("Body Length => {}", body.len());
-    let response = router.handle_request(HTTPRequest(request_line, body)).await;
-    stream
-        .write_all(response.as_slice())
-        .await
-        .expect("Error writing response");
-}
+use tracing::{debug, error, info, warn, Instrument};
 
 #[tokio::main]
-async fn main() {
-    let HTTPArgs { ip_addr, port } = parser::HTTPArgs::parse();
+async fn main() -> ExitCode {
+    let args = parser::HTTPArgs::parse();
+    if let Err(err) = logging::init(
+        args.log_level.as_deref().unwrap_or("info"),
+        args.log_format.unwrap_or(LogFormat::Text),
+    ) {
+        eprintln!("{err}");
+        return ExitCode::FAILURE;
+    }
     let listener = TcpListener::bind({
-        let address = format!(
-            "{}:{}",
-            ip_addr.unwrap_or("127.0.0.1".to_owned()),
-            port.unwrap_or(8080)
-        );
-        println!("Starting server on {address}");
+        let address = args.address();
+        info!("Starting server on {address}");
         address
     })
     .await
     .expect("Error binding to tcp socket.");
 
-    let router: Arc<Router> = Arc::new(Router::new().with(sample_routes::http_routes()));
+    let timeouts = args.timeouts();
+    sample_routes::set_cookie_keys(match args.cookie_keys() {
+        Some(keys) => keys.expect("Error loading cookie keys"),
+        None => sample_routes::random_cookie_keys(),
+    });
+    let mut router = Router::new().with(sample_routes::http_routes());
+    if let Some(cors) = args.cors() {
+        router = router.with_cors(cors.expect("Error parsing CORS origin regex"));
+    }
+    let admin_acl = args
+        .admin_acl()
+        .map(|acl| acl.expect("Error loading the /admin ACL file"));
+    if let Some(basic) = args.basic_auth() {
+        let guard = AuthGuard::new(basic.expect("Error loading htpasswd file"));
+        let mut admin = sample_routes::admin_routes().with_auth(guard);
+        if let Some(acl) = admin_acl.clone() {
+            admin = admin.with_acl(acl);
+        }
+        router = router.with(admin);
+    }
+    if let Some(bearer) = args.bearer_auth() {
+        let guard = AuthGuard::new(bearer.expect("Error loading bearer tokens"));
+        router = router.with(sample_routes::api_routes().with_auth(guard));
+    }
+    if let Some(validator) = args.jwt_validator() {
+        let validator = validator.expect("Error loading JWKS file");
+        let admin = validator.clone().require_scope("admin");
+        router = router
+            .with(
+                sample_routes::jwt_routes()
+                    .with_auth(AuthGuard::new(BearerAuth::new("jwt", validator))),
+            )
+            .with(
+                sample_routes::jwt_admin_routes()
+                    .with_auth(AuthGuard::new(BearerAuth::new("jwt", admin))),
+            );
+    }
+    if let Some(limit) = args.rate_limit() {
+        router = router.with_rate_limit(limit);
+    }
+    if let Some(log) = args.access_log() {
+        router = router.with_access_log(log.expect("Error opening the access log file"));
+    }
+    let metrics_acl = args
+        .metrics_acl()
+        .map(|acl| acl.expect("Error loading the metrics ACL file"));
+    if let Some(path) = args.metrics_path() {
+        router = router.with_metrics(Metrics::new(), path);
+        if let Some(acl) = metrics_acl.clone() {
+            router = router.with_metrics_acl(acl);
+        }
+    }
+    let router: Arc<Router> = Arc::new(
+        router
+            .with_trusted_proxies(args.trusted_proxies())
+            .with_limits(args.limits())
+            .with_sessions(args.sessions().expect("Error setting up the session store")),
+    );
+
+    let tls = args.tls().map(|resolver| {
+        let resolver = Arc::new(resolver.expect("Error loading TLS certificates"));
+        tokio::spawn(tls::reload_on_sighup(Arc::clone(&resolver)));
+        resolver
+            .acceptor(args.tls_client_ca.as_deref())
+            .expect("Error loading TLS client CA bundle")
+    });
 
+    let admission = Arc::new(Admission {
+        proxy_protocol: args.proxy_protocol.clone(),
+        acl: args
+            .acl()
+            .map(|acl| acl.expect("Error loading the ACL file")),
+    });
+    let acls: Vec<IpAcl> = admission
+        .acl
+        .iter()
+        .chain(&admin_acl)
+        .chain(&metrics_acl)
+        .cloned()
+        .collect();
+    if !acls.is_empty() {
+        tokio::spawn(shutdown::on_hangup(move || {
+            for acl in &acls {
+                match acl.reload() {
+                    Ok(()) => info!("Reloaded ACL"),
+                    Err(err) => error!("Error reloading ACL, keeping the old rules => {err}"),
+                }
+            }
+        }));
+    }
+    let limiter = args.connection_limiter();
+    let (start_shutdown, shutdown) = shutdown::channel();
+    let mut connections = JoinSet::new();
+    let signal = shutdown::signal();
+    tokio::pin!(signal);
+
+    // with the queue policy, a slot is reserved before accepting so nothing is accepted while the server is full
+    let mut reserved = None;
+    // set while accepting is paused because the process ran out of file descriptors or memory
+    let mut paused_until: Option<Instant> = None;
+    let mut backoff = MIN_BACKOFF;
+    let mut exit_code = ExitCode::SUCCESS;
     loop {
-        let (socket, _) = listener
-            .accept()
-            .await
-            .expect("Error unwraping the listener");
-        let routeref = Arc::clone(&router);
-        tokio::spawn(async move {
-            handle_connection(socket, routeref).await;
-        });
+        tokio::select! {
+            signal = &mut signal => {
+                info!("Received {signal}, no longer accepting connections");
+                break;
+            }
+            // reap finished connections so the set doesn't grow forever
+            Some(result) = connections.join_next(), if !connections.is_empty() => log_task_result(result),
+            slot = limiter.reserve(), if limiter.queues() && reserved.is_none() => {
+                reserved = Some(slot);
+            }
+            _ = sleep_until(paused_until.unwrap_or_else(Instant::now)), if paused_until.is_some() => {
+                paused_until = None;
+            }
+            accepted = listener.accept(), if paused_until.is_none() && (!limiter.queues() || reserved.is_some()) => {
+                let (socket, peer) = match accepted {
+                    Ok(accepted) => {
+                        backoff = MIN_BACKOFF;
+                        accepted
+                    }
+                    Err(err) => match AcceptError::classify(&err) {
+                        AcceptError::Transient => {
+                            warn!("Error accepting connection, continuing => {err}");
+                            continue;
+                        }
+                        AcceptError::Exhausted => {
+                            error!("Out of resources accepting connections, pausing for {backoff:?} => {err}");
+                            paused_until = Some(Instant::now() + backoff);
+                            backoff = (backoff * 2).min(MAX_BACKOFF);
+                            continue;
+                        }
+                        AcceptError::Fatal => {
+                            error!("Listener failed, no longer accepting connections => {err}");
+                            exit_code = ExitCode::FAILURE;
+                            break;
+                        }
+                    },
+                };
+                if !admission.admits(peer.ip()) {
+                    debug!("Rejecting connection from {peer}, not allowed by the ACL");
+                    continue;
+                }
+                let Some(permit) = limiter.admit(peer.ip(), reserved.take()) else {
+                    match limiter.reject(socket, timeouts.write) {
+                        Some(rejection) => {
+                            warn!("Too many connections, rejecting {peer}");
+                            connections.spawn(rejection);
+                        }
+                        None => warn!("Too many connections, closing {peer} without a response"),
+                    }
+                    continue;
+                };
+                let routeref = Arc::clone(&router);
+                let shutdown = shutdown.clone();
+                let tls = tls.clone();
+                let admission = Arc::clone(&admission);
+                connections.spawn(
+                    async move {
+                        serve(socket, peer, tls, admission, routeref, timeouts, shutdown).await;
+                        drop(permit);
+                    }
+                    .instrument(connection_span(peer)),
+                );
+            }
+        }
+    }
+
+    // stop accepting, close idle connections and give the rest until the drain deadline to finish
+    drop(listener);
+    let _ = start_shutdown.send(true);
+    let drain_timeout = args.drain_timeout();
+    info!(
+        "Waiting up to {drain_timeout:?} for {} connection(s) to finish",
+        connections.len()
+    );
+    let drained = timeout(drain_timeout, async {
+        while let Some(result) = connections.join_next().await {
+            log_task_result(result);
+        }
+    })
+    .await;
+
+    match drained {
+        Ok(()) => {
+            info!("All connections finished, shutting down");
+            exit_code
+        }
+        Err(_) => {
+            warn!(
+                "Drain deadline passed, aborting {} connection(s)",
+                connections.len()
+            );
+            connections.shutdown().await;
+            ExitCode::FAILURE
+        }
     }
 }
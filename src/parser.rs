@@ -1,4 +1,48 @@
-use clap::Parser;
+use crate::{
+    connection::Timeouts,
+    limiter::{ConnectionLimiter, OverloadPolicy},
+    tls::{CertPaths, CertResolver, SniCert},
+};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use clap::{Parser, ValueEnum};
+use http::{
+    AccessLog, AccessLogFormat, AuthError, BasicAuth, BearerAuth, Cidr, CookieKeys, Cors,
+    FileStore, IpAcl, Jwks, JwtValidator, Limits, MemoryStore, Principal, RateLimit, Sessions,
+    TrustedProxies,
+};
+use ring::{hmac, rand::SystemRandom};
+use std::{fs, io, path::PathBuf, sync::Arc, time::Duration};
+
+/// How the server wide rate limit counts requests
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum RateLimitAlgorithm {
+    /// Allows bursts of up to the whole limit, refilled evenly over the window
+    TokenBucket,
+    /// Allows the limit within any window, with no bursts over it
+    SlidingWindow,
+}
+
+/// How diagnostics are written to stderr
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum LogFormat {
+    /// Human readable lines
+    Text,
+    /// One JSON object per line, with the spans each was logged in
+    Json,
+}
+
+/// How the access log is written, if at all
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum AccessLogArg {
+    /// Common Log Format
+    Common,
+    /// Combined Log Format, which adds the referer and user agent
+    Combined,
+    /// One JSON object per line
+    Json,
+    /// No access log
+    Off,
+}
 
 #[derive(Parser, Debug)]
 pub struct HTTPArgs {
@@ -9,4 +53,451 @@ pub struct HTTPArgs {
     /// Port Number. Enter in the format of "1234". Default is port 8080.
     #[arg(long, short)]
     pub port: Option<u16>,
+
+    /// Longest request line accepted in bytes. Longer request lines get a 414. Default is 8192.
+    #[arg(long)]
+    pub max_request_line: Option<usize>,
+
+    /// Largest header block accepted in bytes. Headers are read until the blank line ending them or until this cap is reached, after which they get a 431. Default is 16384.
+    #[arg(long)]
+    pub max_header_bytes: Option<usize>,
+
+    /// Maximum number of headers accepted. More headers get a 431. Default is 100.
+    #[arg(long)]
+    pub max_header_count: Option<usize>,
+
+    /// Largest body accepted in bytes, unless a route overrides it. Larger bodies get a 413. Default is 10485760 (10 MiB).
+    #[arg(long)]
+    pub max_body: Option<usize>,
+
+    /// Seconds allowed to receive the request line and headers. Clients that stall part way through get a 408. Default is 10.
+    #[arg(long)]
+    pub header_timeout: Option<u64>,
+
+    /// Seconds allowed to wait for each read of the request body. Clients that stall get a 408. Default is 30.
+    #[arg(long)]
+    pub body_timeout: Option<u64>,
+
+    /// Seconds allowed for a route to produce a response. Slower routes get a 503. Default is 30.
+    #[arg(long)]
+    pub handler_timeout: Option<u64>,
+
+    /// Seconds allowed to write the response back to the client. Default is 10.
+    #[arg(long)]
+    pub write_timeout: Option<u64>,
+
+    /// Slowest average rate in bytes per second a client may send a request at before its connection is closed. 0 disables the check. Default is 256.
+    #[arg(long)]
+    pub min_data_rate: Option<usize>,
+
+    /// Seconds a connection may stay below the minimum data rate before it is closed. Default is 5.
+    #[arg(long)]
+    pub min_rate_grace: Option<u64>,
+
+    /// Seconds to let in-flight requests finish after SIGINT or SIGTERM before they are aborted. Default is 30.
+    #[arg(long)]
+    pub drain_timeout: Option<u64>,
+
+    /// Maximum number of connections served at once. Default is 1024.
+    #[arg(long)]
+    pub max_connections: Option<usize>,
+
+    /// What to do with new connections once max-connections is reached. "queue" stops accepting until a connection finishes, "reject" answers with a 503. Default is queue.
+    #[arg(long, value_enum)]
+    pub overload_policy: Option<OverloadPolicy>,
+
+    /// Seconds sent in the Retry-After header of rejected connections. Default is 1.
+    #[arg(long)]
+    pub retry_after: Option<u64>,
+
+    /// Maximum number of connections served at once for a single client IP address. Connections over this get a 503. Default is no limit.
+    #[arg(long)]
+    pub max_connections_per_ip: Option<usize>,
+
+    /// Address or CIDR block of a proxy in front of the server, e.g. "10.0.0.0/8". May be repeated. The client address, scheme and host are taken from the Forwarded or X-Forwarded-* headers of requests from these proxies. Default is to trust no proxies.
+    #[arg(long)]
+    pub trusted_proxy: Vec<Cidr>,
+
+    /// Address or CIDR block of a load balancer that sends a PROXY protocol (v1 or v2) header ahead of each connection, e.g. "10.0.0.0/8". May be repeated. Connections from these must send one, and connections from anywhere else that send one are closed. Default is to not accept the PROXY protocol.
+    #[arg(long)]
+    pub proxy_protocol: Vec<Cidr>,
+
+    /// File of "allow CIDR" and "deny CIDR" rules, one per line, for which addresses may connect. The first matching rule decides, and "all" matches every address. Reloaded on SIGHUP. Default is to let everyone connect.
+    #[arg(long)]
+    pub acl: Option<PathBuf>,
+
+    /// File of rules in the same format as --acl, for which addresses may use /admin. Reloaded on SIGHUP. Default is any address.
+    #[arg(long, requires = "htpasswd")]
+    pub admin_acl: Option<PathBuf>,
+
+    /// Requests each client IP address may make per --rate-limit-window. Clients over it get a 429. Default is no limit.
+    #[arg(long)]
+    pub rate_limit: Option<u32>,
+
+    /// Seconds the rate limit is counted over. Default is 60.
+    #[arg(long, requires = "rate_limit")]
+    pub rate_limit_window: Option<u64>,
+
+    /// How the rate limit counts requests. Default is token-bucket.
+    #[arg(long, value_enum, requires = "rate_limit")]
+    pub rate_limit_algorithm: Option<RateLimitAlgorithm>,
+
+    /// Diagnostics to log to stderr, as a level ("error", "warn", "info", "debug" or "trace") or per crate directives, e.g. "info,http=debug". Default is info.
+    #[arg(long)]
+    pub log_level: Option<String>,
+
+    /// Format of the diagnostics logged to stderr. Default is text.
+    #[arg(long, value_enum)]
+    pub log_format: Option<LogFormat>,
+
+    /// Format of the access log, with a line for every request answered. Default is combined.
+    #[arg(long, value_enum)]
+    pub access_log: Option<AccessLogArg>,
+
+    /// File to append the access log to. Default is stdout.
+    #[arg(long)]
+    pub access_log_file: Option<PathBuf>,
+
+    /// Megabytes the access log file may grow to before it is rotated to FILE.1, FILE.1 to FILE.2 and so on, from 1 to 1048576 (1 TiB). Default is 100.
+    #[arg(long, requires = "access_log_file", value_parser = clap::value_parser!(u64).range(1..=1024 * 1024))]
+    pub access_log_max_size: Option<u64>,
+
+    /// Rotated access log files to keep. Default is 5.
+    #[arg(long, requires = "access_log_file")]
+    pub access_log_keep: Option<usize>,
+
+    /// Path to collect and serve Prometheus metrics at, to GET requests on any host, such as /metrics. Default is no metrics.
+    #[arg(long)]
+    pub metrics_path: Option<String>,
+
+    /// File of rules in the same format as --acl, for which addresses may read the metrics. Reloaded on SIGHUP. Default is any address.
+    #[arg(long, requires = "metrics_path")]
+    pub metrics_acl: Option<PathBuf>,
+
+    /// PEM file holding the certificate chain to serve HTTPS with. Requires --tls-key. Without it the server speaks plain HTTP.
+    #[arg(long, requires = "tls_key")]
+    pub tls_cert: Option<PathBuf>,
+
+    /// PEM file holding the private key for --tls-cert.
+    #[arg(long, requires = "tls_cert")]
+    pub tls_key: Option<PathBuf>,
+
+    /// Extra certificate for one hostname, chosen by SNI. Enter in the format of "HOST=CERT,KEY", where HOST may be "*.example.com". May be repeated. Certificates are reloaded on SIGHUP.
+    #[arg(long, requires = "tls_cert")]
+    pub tls_sni: Vec<SniCert>,
+
+    /// PEM bundle of CAs to verify TLS client certificates against. Clients may then authenticate with a certificate, and routes decide whether one is required.
+    #[arg(long, requires = "tls_cert")]
+    pub tls_client_ca: Option<PathBuf>,
+
+    /// File with the secrets used to sign and encrypt cookies, one base64 encoded secret of at least 32 bytes per line. The first secret issues new cookies, the rest are previous secrets still accepted while rotating keys. Default is a random secret, so cookies don't survive a restart.
+    #[arg(long)]
+    pub cookie_keys: Option<PathBuf>,
+
+    /// Directory to keep sessions in, one file per session, so they survive restarts. Default is to keep them in memory.
+    #[arg(long)]
+    pub session_dir: Option<PathBuf>,
+
+    /// Seconds a session lasts without any requests. Default is 1800 (30 minutes).
+    #[arg(long)]
+    pub session_idle_timeout: Option<u64>,
+
+    /// Seconds a session lasts after it started, however active it is. Default is 86400 (24 hours).
+    #[arg(long)]
+    pub session_lifetime: Option<u64>,
+
+    /// htpasswd file with bcrypt or argon2 hashes of the users allowed into /admin. Without it /admin is not served.
+    #[arg(long)]
+    pub htpasswd: Option<PathBuf>,
+
+    /// File with the tokens allowed to call /api, one "TOKEN NAME" pair per line. Without it /api is not served.
+    #[arg(long)]
+    pub bearer_tokens: Option<PathBuf>,
+
+    /// JWKS file with the keys JSON Web Tokens for /jwt are signed with. HS256, RS256 and ES256 keys are supported. Without it /jwt is not served.
+    #[arg(long)]
+    pub jwks: Option<PathBuf>,
+
+    /// Issuer JSON Web Tokens must have in their "iss" claim. Default is any issuer.
+    #[arg(long, requires = "jwks")]
+    pub jwt_issuer: Option<String>,
+
+    /// Audience JSON Web Tokens must have in their "aud" claim. Default is any audience.
+    #[arg(long, requires = "jwks")]
+    pub jwt_audience: Option<String>,
+
+    /// Seconds of clock skew allowed when checking when JSON Web Tokens expire or become valid. Default is 60.
+    #[arg(long, requires = "jwks")]
+    pub jwt_leeway: Option<u64>,
+
+    /// Origin browser apps may call the sample routes from, e.g. "https://app.example.test", or "*" for any origin. May be repeated. Without it or --cors-origin-regex, cross-origin calls are not allowed.
+    #[arg(long)]
+    pub cors_origin: Vec<String>,
+
+    /// Regular expression matching whole origins browser apps may call the sample routes from, e.g. "https://.*\.example\.test". May be repeated.
+    #[arg(long)]
+    pub cors_origin_regex: Vec<String>,
+
+    /// Request header cross-origin calls may send, on top of the ones browsers always allow, e.g. "Content-Type". May be repeated.
+    #[arg(long)]
+    pub cors_allow_header: Vec<String>,
+
+    /// Response header cross-origin callers may read, on top of the ones browsers always expose. May be repeated.
+    #[arg(long)]
+    pub cors_expose_header: Vec<String>,
+
+    /// Let cross-origin calls send cookies and credentials.
+    #[arg(long)]
+    pub cors_credentials: bool,
+
+    /// Seconds browsers may cache the answer to a CORS preflight. Default is left to the browser.
+    #[arg(long)]
+    pub cors_max_age: Option<u64>,
+}
+
+impl HTTPArgs {
+    /// The address to bind the listener to
+    pub fn address(&self) -> String {
+        format!(
+            "{}:{}",
+            self.ip_addr.as_deref().unwrap_or("127.0.0.1"),
+            self.port.unwrap_or(8080)
+        )
+    }
+
+    /// How long to wait for in-flight requests when shutting down
+    pub fn drain_timeout(&self) -> Duration {
+        Duration::from_secs(self.drain_timeout.unwrap_or(30))
+    }
+
+    /// The addresses allowed to connect, from [`HTTPArgs::acl`], if given
+    pub fn acl(&self) -> Option<io::Result<IpAcl>> {
+        Some(IpAcl::load(self.acl.as_ref()?))
+    }
+
+    /// The addresses allowed to use /admin, from [`HTTPArgs::admin_acl`], if given
+    pub fn admin_acl(&self) -> Option<io::Result<IpAcl>> {
+        Some(IpAcl::load(self.admin_acl.as_ref()?))
+    }
+
+    /// The addresses allowed to read the metrics, from [`HTTPArgs::metrics_acl`], if given
+    pub fn metrics_acl(&self) -> Option<io::Result<IpAcl>> {
+        Some(IpAcl::load(self.metrics_acl.as_ref()?))
+    }
+
+    /// Basic authentication against [`HTTPArgs::htpasswd`], if given
+    pub fn basic_auth(&self) -> Option<io::Result<BasicAuth>> {
+        Some(BasicAuth::load(self.htpasswd.as_ref()?, "admin"))
+    }
+
+    /// Bearer authentication against the tokens in [`HTTPArgs::bearer_tokens`], if given. Empty lines and lines starting with `#` are skipped.
+    /// Only HMAC-SHA256 tags of the tokens are kept, under a key made at startup, and a presented token is checked against every one of them in constant time, so response times don't give away how much of a token was right.
+    pub fn bearer_auth(&self) -> Option<io::Result<BearerAuth>> {
+        let path = self.bearer_tokens.as_ref()?;
+        Some(fs::read_to_string(path).and_then(|file| {
+            let key = hmac::Key::generate(hmac::HMAC_SHA256, &SystemRandom::new())
+                .map_err(|_| io::Error::other("Couldn't generate a key for the bearer tokens"))?;
+            let tokens = file
+                .lines()
+                .map(str::trim)
+                .filter(|line| !line.is_empty() && !line.starts_with('#'))
+                .map(|line| {
+                    line.split_once(char::is_whitespace)
+                        .map(|(token, name)| {
+                            (hmac::sign(&key, token.as_bytes()), name.trim().to_owned())
+                        })
+                        .ok_or_else(|| {
+                            io::Error::new(io::ErrorKind::InvalidData, "Expected \"TOKEN NAME\"")
+                        })
+                })
+                .collect::<io::Result<Vec<_>>>()?;
+            Ok(BearerAuth::new("api", move |token: &str| {
+                // every token is checked, rather than stopping at the first match
+                tokens
+                    .iter()
+                    .fold(None, |found, (tag, name)| {
+                        match hmac::verify(&key, token.as_bytes(), tag.as_ref()) {
+                            Ok(()) => Some(name),
+                            Err(_) => found,
+                        }
+                    })
+                    .map(|name| Principal::new(name))
+                    .ok_or_else(|| AuthError::Invalid("Unknown token".to_owned()))
+            }))
+        }))
+    }
+
+    /// Validation of JSON Web Tokens against the keys in [`HTTPArgs::jwks`], if given
+    pub fn jwt_validator(&self) -> Option<io::Result<JwtValidator>> {
+        let path = self.jwks.as_ref()?;
+        Some(Jwks::load(path).map(|jwks| {
+            let mut validator = JwtValidator::new(Arc::new(jwks))
+                .leeway(Duration::from_secs(self.jwt_leeway.unwrap_or(60)));
+            if let Some(issuer) = &self.jwt_issuer {
+                validator = validator.issuer(issuer);
+            }
+            if let Some(audience) = &self.jwt_audience {
+                validator = validator.audience(audience);
+            }
+            validator
+        }))
+    }
+
+    /// The CORS policy for the sample routes, if any origins are allowed
+    pub fn cors(&self) -> Option<io::Result<Cors>> {
+        if self.cors_origin.is_empty() && self.cors_origin_regex.is_empty() {
+            return None;
+        }
+        let mut cors = Cors::new()
+            .allow_headers(&as_strs(&self.cors_allow_header))
+            .expose_headers(&as_strs(&self.cors_expose_header))
+            .allow_credentials(self.cors_credentials);
+        for origin in &self.cors_origin {
+            cors = match origin.as_str() {
+                "*" => cors.allow_any_origin(),
+                origin => cors.allow_origin(origin),
+            };
+        }
+        if let Some(max_age) = self.cors_max_age {
+            cors = cors.max_age(Duration::from_secs(max_age));
+        }
+        Some(
+            self.cors_origin_regex
+                .iter()
+                .try_fold(cors, |cors, pattern| cors.allow_origin_regex(pattern))
+                .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err)),
+        )
+    }
+
+    /// The proxies whose forwarding headers are believed
+    pub fn trusted_proxies(&self) -> TrustedProxies {
+        TrustedProxies::new(self.trusted_proxy.clone())
+    }
+
+    /// The access log, unless it is turned off
+    pub fn access_log(&self) -> Option<io::Result<AccessLog>> {
+        let format = match self.access_log.unwrap_or(AccessLogArg::Combined) {
+            AccessLogArg::Common => AccessLogFormat::Common,
+            AccessLogArg::Combined => AccessLogFormat::Combined,
+            AccessLogArg::Json => AccessLogFormat::Json,
+            AccessLogArg::Off => return None,
+        };
+        Some(match &self.access_log_file {
+            Some(path) => AccessLog::file(
+                path,
+                format,
+                self.access_log_max_size
+                    .unwrap_or(100)
+                    .saturating_mul(1024 * 1024),
+                self.access_log_keep.unwrap_or(5),
+            ),
+            None => Ok(AccessLog::stdout(format)),
+        })
+    }
+
+    /// The path metrics are served at, if they are turned on
+    pub fn metrics_path(&self) -> Option<&str> {
+        self.metrics_path.as_deref()
+    }
+
+    /// The server wide rate limit per client IP address, if given
+    pub fn rate_limit(&self) -> Option<RateLimit> {
+        let quota = self.rate_limit?;
+        let window = Duration::from_secs(self.rate_limit_window.unwrap_or(60));
+        Some(match self.rate_limit_algorithm {
+            Some(RateLimitAlgorithm::SlidingWindow) => RateLimit::sliding_window(quota, window),
+            Some(RateLimitAlgorithm::TokenBucket) | None => RateLimit::token_bucket(quota, window),
+        })
+    }
+
+    /// Session settings, with defaults for anything not given on the command line. Session cookies are marked secure when serving HTTPS.
+    pub fn sessions(&self) -> io::Result<Sessions> {
+        let idle_timeout = Duration::from_secs(self.session_idle_timeout.unwrap_or(30 * 60));
+        let lifetime = Duration::from_secs(self.session_lifetime.unwrap_or(24 * 60 * 60));
+        // nothing is kept past the session's lifetime, as it can't be used after that anyway
+        let sessions = match &self.session_dir {
+            Some(dir) => Sessions::new(FileStore::new(dir, lifetime)?),
+            None => Sessions::new(MemoryStore::new(lifetime)),
+        };
+        Ok(sessions
+            .idle_timeout(idle_timeout)
+            .absolute_timeout(lifetime)
+            .secure(self.tls_cert.is_some()))
+    }
+
+    /// Connection limits, with defaults for anything not given on the command line
+    pub fn connection_limiter(&self) -> ConnectionLimiter {
+        ConnectionLimiter::new(
+            self.max_connections.unwrap_or(1024),
+            self.overload_policy.unwrap_or(OverloadPolicy::Queue),
+            self.retry_after.unwrap_or(1),
+            self.max_connections_per_ip,
+        )
+    }
+
+    /// Loads the TLS certificates if HTTPS was asked for
+    pub fn tls(&self) -> Option<io::Result<CertResolver>> {
+        let default = CertPaths {
+            cert: self.tls_cert.clone()?,
+            key: self.tls_key.clone()?,
+        };
+        Some(CertResolver::load(default, self.tls_sni.clone()))
+    }
+
+    /// Loads the cookie keys from [`HTTPArgs::cookie_keys`], if given. Empty lines and lines starting with `#` are skipped.
+    pub fn cookie_keys(&self) -> Option<io::Result<CookieKeys>> {
+        let path = self.cookie_keys.as_ref()?;
+        let invalid = |err| io::Error::new(io::ErrorKind::InvalidData, err);
+        Some(fs::read_to_string(path).and_then(|file| {
+            let secrets = file
+                .lines()
+                .map(str::trim)
+                .filter(|line| !line.is_empty() && !line.starts_with('#'))
+                .map(|line| {
+                    STANDARD
+                        .decode(line)
+                        .map_err(|err| invalid(format!("Invalid base64 secret => {err}")))
+                })
+                .collect::<io::Result<Vec<_>>>()?;
+            let (current, previous) = secrets
+                .split_first()
+                .ok_or_else(|| invalid("No secrets found".to_owned()))?;
+            previous
+                .iter()
+                .fold(CookieKeys::new(current), |keys, secret| {
+                    keys.and_then(|keys| keys.with_previous(secret))
+                })
+                .map_err(|err| invalid(format!("Unusable secret => {err:?}")))
+        }))
+    }
+
+    /// Request size limits, with defaults for anything not given on the command line
+    pub fn limits(&self) -> Limits {
+        let default = Limits::default();
+        Limits {
+            max_request_line: self.max_request_line.unwrap_or(default.max_request_line),
+            max_header_bytes: self.max_header_bytes.unwrap_or(default.max_header_bytes),
+            max_header_count: self.max_header_count.unwrap_or(default.max_header_count),
+            max_body: self.max_body.unwrap_or(default.max_body),
+        }
+    }
+
+    /// Connection timeouts, with defaults for anything not given on the command line
+    pub fn timeouts(&self) -> Timeouts {
+        let default = Timeouts::default();
+        let seconds =
+            |secs: Option<u64>, default: Duration| secs.map_or(default, Duration::from_secs);
+        Timeouts {
+            header_read: seconds(self.header_timeout, default.header_read),
+            body_read: seconds(self.body_timeout, default.body_read),
+            handler: seconds(self.handler_timeout, default.handler),
+            write: seconds(self.write_timeout, default.write),
+            min_data_rate: self.min_data_rate.unwrap_or(default.min_data_rate),
+            min_rate_grace: seconds(self.min_rate_grace, default.min_rate_grace),
+        }
+    }
+}
+
+fn as_strs(values: &[String]) -> Vec<&str> {
+    values.iter().map(String::as_str).collect()
 }
@@ -0,0 +1,99 @@
+use std::{
+    io,
+    pin::Pin,
+    task::{Context, Poll},
+};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+/// A stream that hands back bytes already read from it before reading any more, so the start of a connection can be looked at to decide how to serve it
+#[derive(Debug)]
+pub struct Prefixed<S> {
+    prefix: Vec<u8>,
+    /// How much of the prefix has been read back
+    read: usize,
+    inner: S,
+}
+
+impl<S> Prefixed<S> {
+    pub fn new(prefix: Vec<u8>, inner: S) -> Self {
+        Self {
+            prefix,
+            read: 0,
+            inner,
+        }
+    }
+
+    /// Puts `bytes` back in front of whatever hasn't been read yet, for bytes read past the end of one request that belong to the next
+    pub fn unread(&mut self, bytes: &[u8]) {
+        self.prefix.drain(..self.read);
+        self.read = 0;
+        self.prefix.splice(..0, bytes.iter().copied());
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Prefixed<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        if this.read < this.prefix.len() {
+            let len = buf.remaining().min(this.prefix.len() - this.read);
+            buf.put_slice(&this.prefix[this.read..this.read + len]);
+            this.read += len;
+            return Poll::Ready(Ok(()));
+        }
+        Pin::new(&mut this.inner).poll_read(cx, buf)
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Prefixed<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[tokio::test]
+    async fn reads_prefix_first() {
+        let (mut client, server) = tokio::io::duplex(64);
+        let mut server = Prefixed::new(b"GET / ".to_vec(), server);
+        client.write_all(b"HTTP/1.1\r\n").await.unwrap();
+        drop(client);
+
+        let mut read = String::new();
+        server.read_to_string(&mut read).await.unwrap();
+        assert_eq!(read, "GET / HTTP/1.1\r\n");
+    }
+
+    #[tokio::test]
+    async fn reads_unread_bytes_again() {
+        let (client, server) = tokio::io::duplex(64);
+        let mut server = Prefixed::new(b"abcdef".to_vec(), server);
+        drop(client);
+
+        let mut start = [0; 4];
+        server.read_exact(&mut start).await.unwrap();
+        server.unread(b"cd");
+        let mut read = String::new();
+        server.read_to_string(&mut read).await.unwrap();
+        assert_eq!(read, "cdef");
+    }
+}
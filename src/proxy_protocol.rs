@@ -0,0 +1,242 @@
+use http::{Cidr, ProxyAddresses};
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    time::Duration,
+};
+use tokio::{io::AsyncReadExt, net::TcpStream, time::sleep};
+
+/// What a version 1 header starts with
+const V1_SIGNATURE: &[u8] = b"PROXY ";
+/// What a version 2 header starts with
+const V2_SIGNATURE: &[u8] = b"\r\n\r\n\0\r\nQUIT\n";
+/// Longest version 1 header allowed by the spec, including the `\r\n`
+const V1_MAX_LEN: usize = 107;
+/// Fixed part of a version 2 header, before the addresses
+const V2_HEADER_LEN: usize = 16;
+/// How long to wait between looks at a connection that has only sent part of a signature
+const PEEK_INTERVAL: Duration = Duration::from_millis(5);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Version {
+    V1,
+    V2,
+}
+
+/// Reads the HAProxy PROXY protocol header (https://www.haproxy.org/download/2.9/doc/proxy-protocol.txt) off a connection from one of `allowed` sources, leaving the stream at the first byte after it, ready for TLS or HTTP.
+/// Returns the addresses the proxy was connected with, or `None` for connections the proxy made itself, e.g. health checks.
+/// Fails if a source in `allowed` doesn't send a header, or one that isn't, does, as only the load balancer may say where a connection came from.
+/// Should be given a deadline by the caller, as it waits for the client to send the header.
+pub async fn read_header(
+    socket: &mut TcpStream,
+    peer: SocketAddr,
+    allowed: &[Cidr],
+) -> Result<Option<ProxyAddresses>, String> {
+    let version = detect(socket).await.map_err(|err| err.to_string())?;
+    let is_allowed = allowed.iter().any(|cidr| cidr.contains(peer.ip()));
+    let version = match (version, is_allowed) {
+        (Some(version), true) => version,
+        (None, true) => return Err("Missing PROXY protocol header".to_owned()),
+        (Some(_), false) => {
+            return Err("PROXY protocol header from a source that isn't allowed".to_owned())
+        }
+        (None, false) => return Ok(None),
+    };
+
+    match version {
+        Version::V1 => {
+            let mut line = [0; V1_MAX_LEN];
+            let len = loop {
+                let seen = socket
+                    .peek(&mut line)
+                    .await
+                    .map_err(|err| err.to_string())?;
+                if let Some(end) = line[..seen].windows(2).position(|pair| pair == b"\r\n") {
+                    break end + 2;
+                }
+                if seen == V1_MAX_LEN {
+                    return Err("PROXY protocol v1 header too long".to_owned());
+                }
+                sleep(PEEK_INTERVAL).await;
+            };
+            socket
+                .read_exact(&mut line[..len])
+                .await
+                .map_err(|err| err.to_string())?;
+            parse_v1(&line[..len])
+        }
+        Version::V2 => {
+            let mut header = [0; V2_HEADER_LEN];
+            socket
+                .read_exact(&mut header)
+                .await
+                .map_err(|err| err.to_string())?;
+            let mut addresses = vec![0; usize::from(u16::from_be_bytes([header[14], header[15]]))];
+            socket
+                .read_exact(&mut addresses)
+                .await
+                .map_err(|err| err.to_string())?;
+            parse_v2(&header, &addresses)
+        }
+    }
+}
+
+/// Looks at the start of the connection, without consuming it, for a PROXY protocol signature
+async fn detect(socket: &TcpStream) -> std::io::Result<Option<Version>> {
+    let mut start = [0; V2_SIGNATURE.len()];
+    loop {
+        let len = socket.peek(&mut start).await?;
+        let seen = &start[..len];
+        if seen.starts_with(V1_SIGNATURE) {
+            return Ok(Some(Version::V1));
+        }
+        if seen.starts_with(V2_SIGNATURE) {
+            return Ok(Some(Version::V2));
+        }
+        let could_be =
+            |signature: &[u8]| signature.starts_with(&seen[..seen.len().min(signature.len())]);
+        // a closed connection, or one that has already shown it isn't sending a signature
+        if seen.is_empty() || !(could_be(V1_SIGNATURE) || could_be(V2_SIGNATURE)) {
+            return Ok(None);
+        }
+        sleep(PEEK_INTERVAL).await;
+    }
+}
+
+/// Parses a version 1 header, e.g. `PROXY TCP4 192.0.2.1 198.51.100.1 56324 443\r\n`
+fn parse_v1(line: &[u8]) -> Result<Option<ProxyAddresses>, String> {
+    let line = std::str::from_utf8(line)
+        .ok()
+        .and_then(|line| line.strip_suffix("\r\n"))
+        .ok_or("Malformed PROXY protocol v1 header")?;
+    let fields: Vec<&str> = line.split(' ').collect();
+    let address = |ip: &str, port: &str| -> Result<SocketAddr, String> {
+        let ip: IpAddr = ip
+            .parse()
+            .map_err(|err| format!("Invalid PROXY address {ip:?} => {err}"))?;
+        let port: u16 = port
+            .parse()
+            .map_err(|err| format!("Invalid PROXY port {port:?} => {err}"))?;
+        Ok(SocketAddr::new(ip, port))
+    };
+    match fields.as_slice() {
+        ["PROXY", "TCP4" | "TCP6", source, destination, source_port, destination_port] => {
+            Ok(Some(ProxyAddresses {
+                source: address(source, source_port)?,
+                destination: address(destination, destination_port)?,
+            }))
+        }
+        // the proxy couldn't tell, so the connection is treated as its own
+        ["PROXY", "UNKNOWN", ..] => Ok(None),
+        _ => Err(format!("Malformed PROXY protocol v1 header {line:?}")),
+    }
+}
+
+/// Parses a version 2 header, given its fixed 16 bytes and the address block that follows
+fn parse_v2(
+    header: &[u8; V2_HEADER_LEN],
+    addresses: &[u8],
+) -> Result<Option<ProxyAddresses>, String> {
+    let (version, command) = (header[12] >> 4, header[12] & 0x0f);
+    if version != 2 {
+        return Err(format!("Unsupported PROXY protocol version {version}"));
+    }
+    match command {
+        // LOCAL, the proxy's own connection
+        0 => return Ok(None),
+        1 => {}
+        command => return Err(format!("Unsupported PROXY protocol command {command}")),
+    }
+    let port = |at: usize| u16::from_be_bytes([addresses[at], addresses[at + 1]]);
+    // the high nibble is the address family, the low one the transport, which doesn't matter here
+    match header[13] >> 4 {
+        1 if addresses.len() >= 12 => {
+            let ip =
+                |at: usize| Ipv4Addr::from(<[u8; 4]>::try_from(&addresses[at..at + 4]).unwrap());
+            Ok(Some(ProxyAddresses {
+                source: SocketAddr::new(ip(0).into(), port(8)),
+                destination: SocketAddr::new(ip(4).into(), port(10)),
+            }))
+        }
+        2 if addresses.len() >= 36 => {
+            let ip =
+                |at: usize| Ipv6Addr::from(<[u8; 16]>::try_from(&addresses[at..at + 16]).unwrap());
+            Ok(Some(ProxyAddresses {
+                source: SocketAddr::new(ip(0).into(), port(32)),
+                destination: SocketAddr::new(ip(16).into(), port(34)),
+            }))
+        }
+        // unspecified or unix sockets, there is no address to go by
+        0 | 3 => Ok(None),
+        _ => Err("Malformed PROXY protocol v2 addresses".to_owned()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::{io::AsyncWriteExt, net::TcpListener};
+
+    fn addresses(source: &str, destination: &str) -> Option<ProxyAddresses> {
+        Some(ProxyAddresses {
+            source: source.parse().unwrap(),
+            destination: destination.parse().unwrap(),
+        })
+    }
+
+    #[test]
+    fn parses_headers() {
+        assert_eq!(
+            parse_v1(b"PROXY TCP4 192.0.2.1 198.51.100.1 56324 443\r\n"),
+            Ok(addresses("192.0.2.1:56324", "198.51.100.1:443"))
+        );
+        assert_eq!(parse_v1(b"PROXY UNKNOWN\r\n"), Ok(None));
+        assert!(parse_v1(b"PROXY TCP4 192.0.2.1 nope 1 2\r\n").is_err());
+
+        let mut header = [0; V2_HEADER_LEN];
+        header[..12].copy_from_slice(V2_SIGNATURE);
+        header[12] = 0x21;
+        header[13] = 0x21;
+        let mut ips = [0; 36];
+        ips[15] = 1;
+        ips[31] = 2;
+        ips[32..].copy_from_slice(&[0xdb, 0x04, 0x01, 0xbb]);
+        assert_eq!(
+            parse_v2(&header, &ips),
+            Ok(addresses("[::1]:56068", "[::2]:443"))
+        );
+        header[12] = 0x20;
+        assert_eq!(parse_v2(&header, &ips), Ok(None));
+    }
+
+    #[tokio::test]
+    async fn only_takes_headers_from_allowed_sources() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let listener = &listener;
+        let connect = |data: &'static [u8]| async move {
+            let mut client = TcpStream::connect(address).await.unwrap();
+            client.write_all(data).await.unwrap();
+            let (server, peer) = listener.accept().await.unwrap();
+            (client, server, peer)
+        };
+        let allowed = ["127.0.0.0/8".parse().unwrap()];
+
+        let (_client, mut server, peer) =
+            connect(b"PROXY TCP4 203.0.113.9 127.0.0.1 4000 80\r\nGET / HTTP/1.1\r\n").await;
+        assert_eq!(
+            read_header(&mut server, peer, &allowed).await,
+            Ok(addresses("203.0.113.9:4000", "127.0.0.1:80"))
+        );
+        // the request is left for HTTP to read
+        let mut rest = [0; 3];
+        server.read_exact(&mut rest).await.unwrap();
+        assert_eq!(&rest, b"GET");
+
+        let (_client, mut server, peer) =
+            connect(b"PROXY TCP4 203.0.113.9 127.0.0.1 4000 80\r\n").await;
+        assert!(read_header(&mut server, peer, &[]).await.is_err());
+        let (_client, mut server, peer) = connect(b"GET / HTTP/1.1\r\n").await;
+        assert_eq!(read_header(&mut server, peer, &[]).await, Ok(None));
+        assert!(read_header(&mut server, peer, &allowed).await.is_err());
+    }
+}
@@ -1,14 +1,105 @@
 use http::{
-    http_err, http_ok, HTTPRequest,
+    http_err, http_ok, ClientCertPolicy, Cookie, CookieKeys, HTTPRequest,
     HTTPResponses::{self, *},
-    HTTPResult, Router,
+    HTTPResult, RateLimit, RouteOptions, Router, SameSite, MIN_SECRET_LEN,
 };
+use ring::rand::{SecureRandom, SystemRandom};
+use std::{sync::OnceLock, time::Duration};
+use tracing::{debug, info, warn};
+
+/// Routes for administrators, to be guarded with Basic authentication
+pub fn admin_routes() -> Router {
+    Router::new()
+        .route("GET", "/admin$", "1.1", whoami_principal)
+        .unwrap()
+}
+
+/// Routes for other services, to be guarded with Bearer authentication
+pub fn api_routes() -> Router {
+    Router::new()
+        .route("GET", "/api/status$", "1.1", whoami_principal)
+        .unwrap()
+}
+
+/// Routes for holders of a JSON Web Token, to be guarded with Bearer authentication
+pub fn jwt_routes() -> Router {
+    Router::new()
+        .route("GET", "/jwt/claims$", "1.1", jwt_claims)
+        .unwrap()
+}
+
+/// Routes for holders of a JSON Web Token with the admin scope
+pub fn jwt_admin_routes() -> Router {
+    Router::new()
+        .route("GET", "/jwt/admin/claims$", "1.1", jwt_claims)
+        .unwrap()
+}
+
+// Routes are plain functions, so the keys for their cookies live in a static set once at startup
+static COOKIE_KEYS: OnceLock<CookieKeys> = OnceLock::new();
+
+pub fn set_cookie_keys(keys: CookieKeys) {
+    if COOKIE_KEYS.set(keys).is_err() {
+        warn!("Cookie keys were already set, keeping the first ones");
+    }
+}
+
+/// Keys from a fresh random secret, for when none were configured
+pub fn random_cookie_keys() -> CookieKeys {
+    let mut secret = [0; MIN_SECRET_LEN];
+    SystemRandom::new()
+        .fill(&mut secret)
+        .expect("The system's random number generator failed");
+    CookieKeys::new(&secret).expect("The secret is long enough")
+}
 pub fn http_routes() -> Router {
     Router::new()
         .route("GET|POST", "/$", "1.1", hello_world)
-        .and_then(|route| route.route("POST", "/image$", "1.1", get_image))
+        .and_then(|route| {
+            // images are allowed to be larger than the default body limit
+            route.route_with(
+                "POST",
+                "/image$",
+                "1.1",
+                RouteOptions {
+                    max_body: Some(50 * 1024 * 1024),
+                    ..Default::default()
+                },
+                get_image,
+            )
+        })
         .and_then(|route| route.route("POST", "/user_json$", "1.1", print_json))
         .and_then(|route| route.route("GET|POST", "/custom$", "1.1", custom_route))
+        .and_then(|route| {
+            // only clients that authenticated with a TLS client certificate may ask who they are
+            route.route_with(
+                "GET",
+                "/whoami$",
+                "1.1",
+                RouteOptions {
+                    client_cert: ClientCertPolicy::Required,
+                    ..Default::default()
+                },
+                whoami,
+            )
+        })
+        .and_then(|route| route.route("GET", "/client$", "1.1", client))
+        .and_then(|route| route.route("GET", "/visits$", "1.1", visits))
+        .and_then(|route| {
+            // slow down password guessing, whatever the server wide rate limit is
+            route.route_with(
+                "POST",
+                "/login$",
+                "1.1",
+                RouteOptions {
+                    rate_limit: Some(RateLimit::sliding_window(5, Duration::from_secs(60))),
+                    ..Default::default()
+                },
+                login,
+            )
+        })
+        .and_then(|route| route.route("GET", "/me$", "1.1", me))
+        .and_then(|route| route.route("POST", "/logout$", "1.1", logout))
         .unwrap()
 }
 
@@ -20,7 +111,7 @@ fn hello_world(_: HTTPRequest) -> HTTPResult {
 // Function takes HTTP request as a parameter, but then destructures it into the variable header since we only care about the header.
 // The body is unneeded and marked with a wildcard. This means that ownership won't transfer over, for what help that may be.
 fn custom_route(HTTPRequest(headers, _): HTTPRequest) -> HTTPResult {
-    println!("Headers => {headers:?}");
+    debug!("Headers => {headers:?}");
     http_ok(Custom {
         code: 201,
         message: "Created".to_owned(),
@@ -30,16 +121,124 @@ fn custom_route(HTTPRequest(headers, _): HTTPRequest) -> HTTPResult {
     })
 }
 
-fn get_image(HTTPRequest(_, body): HTTPRequest) -> HTTPResult {
-    println!("Body Length: {}", body.len());
-    http_ok(Redirect("/".to_owned()))
+fn whoami(HTTPRequest(headers, _): HTTPRequest) -> HTTPResult {
+    // the route requires a client certificate, so the router has already rejected requests without one
+    let cert = headers
+        .connection
+        .client_cert
+        .ok_or_else(HTTPResponses::internal_server_error)?;
+    http_ok(PlainText(format!(
+        "Subject: {}\nSANs: {}\nFingerprint: {}",
+        cert.subject,
+        cert.sans.join(", "),
+        cert.fingerprint
+    )))
+}
+
+// Counts the visits of each client in a signed cookie, so the count can be read but not changed by the client, and remembers the last path in an encrypted one
+fn visits(HTTPRequest(headers, _): HTTPRequest) -> HTTPResult {
+    let keys = COOKIE_KEYS
+        .get()
+        .ok_or_else(HTTPResponses::internal_server_error)?;
+    let visits = keys
+        .get_signed(&headers.cookies, "visits")
+        .and_then(|visits| visits.parse::<u64>().ok())
+        .unwrap_or_default()
+        + 1;
+    let last_path = keys.get_private(&headers.cookies, "last_path");
+    let cookie = |name, value: &str| {
+        Cookie::new(name, value)
+            .path("/")
+            .http_only(true)
+            .same_site(SameSite::Lax)
+    };
+    http_ok(
+        PlainText(format!(
+            "You have visited {visits} time(s). Last path: {}",
+            last_path.as_deref().unwrap_or("none")
+        ))
+        .with_cookie(keys.sign(cookie("visits", &visits.to_string())))
+        .with_cookie(keys.encrypt(cookie("last_path", &headers.path))),
+    )
+}
+
+// Logs in as the user named in the body. A real login would check a password first
+fn login(HTTPRequest(headers, body): HTTPRequest) -> HTTPResult {
+    let user = String::from_utf8(body).map_err(|_| HTTPResponses::bad_request())?;
+    if user.trim().is_empty() {
+        return Err(HTTPResponses::bad_request());
+    }
+    headers.session.insert("user", user.trim());
+    // the client's privileges change, so any session ID it had before must stop working
+    headers.session.rotate_id();
+    http_ok(PlainText(format!("Logged in as {}", user.trim())))
+}
+
+fn me(HTTPRequest(headers, _): HTTPRequest) -> HTTPResult {
+    match headers.session.get::<String>("user") {
+        Some(user) => http_ok(PlainText(format!("You are {user}"))),
+        None => http_ok("You are not logged in".into()),
+    }
+}
+
+fn logout(HTTPRequest(headers, _): HTTPRequest) -> HTTPResult {
+    headers.session.destroy();
+    http_ok("Logged out".into())
+}
+
+fn whoami_principal(HTTPRequest(headers, _): HTTPRequest) -> HTTPResult {
+    // the route is guarded, so the router has already rejected requests without a principal
+    let principal = headers
+        .principal
+        .ok_or_else(HTTPResponses::internal_server_error)?;
+    http_ok(PlainText(format!("Authenticated as {}", principal.name)))
+}
+
+fn jwt_claims(HTTPRequest(headers, _): HTTPRequest) -> HTTPResult {
+    let claims = headers
+        .principal
+        .and_then(|principal| principal.claims)
+        .ok_or_else(HTTPResponses::internal_server_error)?;
+    http_ok(PlainText(format!(
+        "Authenticated as {} by {} with scopes [{}]",
+        claims.sub.as_deref().unwrap_or("nobody"),
+        claims.iss.as_deref().unwrap_or("no issuer"),
+        claims.scopes.join(", ")
+    )))
+}
+
+fn get_image(HTTPRequest(headers, body): HTTPRequest) -> HTTPResult {
+    debug!("Body Length: {}", body.len());
+    // redirect to the URL the client used to reach us, even through a proxy
+    http_ok(Redirect(
+        headers.client.url("/").unwrap_or_else(|| "/".to_owned()),
+    ))
+}
+
+fn client(HTTPRequest(headers, _): HTTPRequest) -> HTTPResult {
+    let client = headers.client;
+    let mut text = format!(
+        "Client {} asked for {} over {}",
+        client
+            .ip
+            .map_or_else(|| "unknown".to_owned(), |ip| ip.to_string()),
+        client.host.as_deref().unwrap_or("no host"),
+        client.scheme
+    );
+    if let Some(proxy) = headers.connection.proxy {
+        text += &format!(
+            ", connecting from {} to {} through a load balancer",
+            proxy.source, proxy.destination
+        );
+    }
+    http_ok(PlainText(text))
 }
 
 fn print_json(HTTPRequest(_, body): HTTPRequest) -> HTTPResult {
-    println!(
+    info!(
         "Json Receieved: {}",
         String::from_utf8(body).map_err(|err| {
-            eprintln!("Could not format the body as JSON => {err}");
+            warn!("Could not format the body as JSON => {err}");
             HTTPResponses::internal_server_error()
         })?
     );
@@ -0,0 +1,60 @@
+use tokio::sync::watch;
+
+/// Creates the sending half, used once by `main` to start shutting down, and a [`Shutdown`] handle that can be cloned into every connection
+pub fn channel() -> (watch::Sender<bool>, Shutdown) {
+    let (sender, receiver) = watch::channel(false);
+    (sender, Shutdown(receiver))
+}
+
+/// A handle connections use to find out the server is shutting down
+#[derive(Debug, Clone)]
+pub struct Shutdown(watch::Receiver<bool>);
+
+impl Shutdown {
+    /// Completes once shutdown has been requested, or straight away if it already has been
+    pub async fn requested(&mut self) {
+        // An error means the sender is gone, which only happens once main is exiting anyway
+        let _ = self.0.wait_for(|&shutting_down| shutting_down).await;
+    }
+
+    /// Whether shutdown has been requested yet
+    pub fn is_requested(&self) -> bool {
+        *self.0.borrow()
+    }
+}
+
+/// Completes when the process receives SIGINT (Ctrl+C) or SIGTERM, returning the name of the signal
+pub async fn signal() -> &'static str {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        let mut terminate =
+            signal(SignalKind::terminate()).expect("Error installing the SIGTERM handler");
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => "SIGINT",
+            _ = terminate.recv() => "SIGTERM",
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = tokio::signal::ctrl_c().await;
+        "Ctrl+C"
+    }
+}
+
+/// Calls `reload` every time the process receives SIGHUP, which asks a daemon to read its configuration again. Never completes
+pub async fn on_hangup(mut reload: impl FnMut()) {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        let mut hangup = signal(SignalKind::hangup()).expect("Error installing the SIGHUP handler");
+        while hangup.recv().await.is_some() {
+            reload();
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = &mut reload;
+        std::future::pending::<()>().await;
+    }
+}
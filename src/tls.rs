@@ -0,0 +1,474 @@
+use crate::shutdown;
+use http::ClientCertificate;
+use ring::digest::{digest, SHA256};
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{self, BufReader},
+    net::IpAddr,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::{Arc, RwLock},
+};
+use tokio_rustls::{
+    rustls::{
+        server::{AllowAnyAnonymousOrAuthenticatedClient, ClientHello, ResolvesServerCert},
+        sign::{any_supported_type, CertifiedKey},
+        Certificate, PrivateKey, RootCertStore, ServerConfig,
+    },
+    TlsAcceptor,
+};
+use tracing::{error, info};
+use x509_parser::{certificate::X509Certificate, extensions::GeneralName, prelude::FromDer};
+
+/// Where to load a certificate chain and its private key from. Both files are PEM encoded.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CertPaths {
+    pub cert: PathBuf,
+    pub key: PathBuf,
+}
+
+/// A certificate to serve for one hostname, given on the command line as `HOST=CERT,KEY`. `HOST` may start with `*.` to match any single subdomain.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SniCert {
+    pub host: String,
+    pub paths: CertPaths,
+}
+
+impl FromStr for SniCert {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (host, paths) = s
+            .split_once('=')
+            .ok_or(format!("Expected HOST=CERT,KEY but got {s}"))?;
+        let (cert, key) = paths
+            .split_once(',')
+            .ok_or(format!("Expected HOST=CERT,KEY but got {s}"))?;
+        Ok(SniCert {
+            host: host.to_ascii_lowercase(),
+            paths: CertPaths {
+                cert: cert.into(),
+                key: key.into(),
+            },
+        })
+    }
+}
+
+/// Every certificate currently being served
+struct Certificates {
+    default: Arc<CertifiedKey>,
+    by_host: HashMap<String, Arc<CertifiedKey>>,
+}
+
+/// Picks the certificate for each handshake from the SNI hostname the client sent, falling back to the default certificate.
+/// The certificates sit behind a lock so they can be reloaded from disk without restarting the server, see [`CertResolver::reload`].
+pub struct CertResolver {
+    default: CertPaths,
+    sni: Vec<SniCert>,
+    certificates: RwLock<Certificates>,
+}
+
+fn invalid_data(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// Reads a PEM certificate chain and the first private key (PKCS#8, PKCS#1 or SEC1) from their files
+fn load_certified_key(paths: &CertPaths) -> io::Result<Arc<CertifiedKey>> {
+    let open = |path: &Path| {
+        File::open(path)
+            .map(BufReader::new)
+            .map_err(|err| io::Error::new(err.kind(), format!("{} => {err}", path.display())))
+    };
+
+    let chain: Vec<Certificate> = rustls_pemfile::certs(&mut open(&paths.cert)?)?
+        .into_iter()
+        .map(Certificate)
+        .collect();
+    if chain.is_empty() {
+        return Err(invalid_data(format!(
+            "No certificates found in {}",
+            paths.cert.display()
+        )));
+    }
+
+    let key = rustls_pemfile::read_all(&mut open(&paths.key)?)?
+        .into_iter()
+        .find_map(|item| match item {
+            rustls_pemfile::Item::PKCS8Key(key)
+            | rustls_pemfile::Item::RSAKey(key)
+            | rustls_pemfile::Item::ECKey(key) => Some(PrivateKey(key)),
+            _ => None,
+        })
+        .ok_or(invalid_data(format!(
+            "No private key found in {}",
+            paths.key.display()
+        )))?;
+    let key = any_supported_type(&key).map_err(|err| {
+        invalid_data(format!(
+            "Unsupported private key in {} => {err}",
+            paths.key.display()
+        ))
+    })?;
+
+    Ok(Arc::new(CertifiedKey::new(chain, key)))
+}
+
+/// Reads the PEM bundle of CAs that client certificates are verified against
+fn load_client_roots(path: &Path) -> io::Result<RootCertStore> {
+    let file = File::open(path)
+        .map_err(|err| io::Error::new(err.kind(), format!("{} => {err}", path.display())))?;
+    let mut roots = RootCertStore::empty();
+    let (added, _) =
+        roots.add_parsable_certificates(&rustls_pemfile::certs(&mut BufReader::new(file))?);
+    if added == 0 {
+        return Err(invalid_data(format!(
+            "No CA certificates found in {}",
+            path.display()
+        )));
+    }
+    Ok(roots)
+}
+
+/// Pulls the identity handlers see out of a client certificate that rustls has already verified
+pub fn client_identity(cert: &Certificate) -> ClientCertificate {
+    let fingerprint = digest(&SHA256, &cert.0)
+        .as_ref()
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect();
+
+    let Ok((_, parsed)) = X509Certificate::from_der(&cert.0) else {
+        return ClientCertificate {
+            subject: String::new(),
+            sans: Vec::new(),
+            fingerprint,
+        };
+    };
+    let sans = parsed
+        .subject_alternative_name()
+        .ok()
+        .flatten()
+        .map(|extension| {
+            extension
+                .value
+                .general_names
+                .iter()
+                .filter_map(|name| match name {
+                    GeneralName::DNSName(dns) => Some(format!("DNS:{dns}")),
+                    GeneralName::URI(uri) => Some(format!("URI:{uri}")),
+                    GeneralName::RFC822Name(email) => Some(format!("email:{email}")),
+                    GeneralName::IPAddress(&[a, b, c, d]) => {
+                        Some(format!("IP:{}", IpAddr::from([a, b, c, d])))
+                    }
+                    GeneralName::IPAddress(ip) => <[u8; 16]>::try_from(*ip)
+                        .ok()
+                        .map(|ip| format!("IP:{}", IpAddr::from(ip))),
+                    _ => None,
+                })
+                .collect()
+        })
+        .unwrap_or_default();
+
+    ClientCertificate {
+        subject: parsed.subject().to_string(),
+        sans,
+        fingerprint,
+    }
+}
+
+impl CertResolver {
+    /// Loads the default certificate and every SNI certificate. Fails if any of them can't be loaded.
+    pub fn load(default: CertPaths, sni: Vec<SniCert>) -> io::Result<Self> {
+        let certificates = RwLock::new(Self::load_certificates(&default, &sni)?);
+        Ok(Self {
+            default,
+            sni,
+            certificates,
+        })
+    }
+
+    fn load_certificates(default: &CertPaths, sni: &[SniCert]) -> io::Result<Certificates> {
+        Ok(Certificates {
+            default: load_certified_key(default)?,
+            by_host: sni
+                .iter()
+                .map(|SniCert { host, paths }| Ok((host.clone(), load_certified_key(paths)?)))
+                .collect::<io::Result<_>>()?,
+        })
+    }
+
+    /// Loads every certificate from disk again and swaps them in for new handshakes. If any certificate fails to load, the current ones are kept.
+    pub fn reload(&self) -> io::Result<()> {
+        let certificates = Self::load_certificates(&self.default, &self.sni)?;
+        *self
+            .certificates
+            .write()
+            .unwrap_or_else(|err| err.into_inner()) = certificates;
+        Ok(())
+    }
+
+    /// Builds the acceptor used to wrap accepted connections. HTTP/2 and HTTP/1.1 are both offered through ALPN.
+    /// With `client_ca`, clients may authenticate with a certificate signed by one of the CAs in that PEM bundle. Clients without a certificate are still accepted, it is up to each route's [`http::ClientCertPolicy`] whether they are served.
+    pub fn acceptor(self: Arc<Self>, client_ca: Option<&Path>) -> io::Result<TlsAcceptor> {
+        let builder = ServerConfig::builder().with_safe_defaults();
+        let builder = match client_ca {
+            Some(client_ca) => builder.with_client_cert_verifier(
+                AllowAnyAnonymousOrAuthenticatedClient::new(load_client_roots(client_ca)?).boxed(),
+            ),
+            None => builder.with_no_client_auth(),
+        };
+        let mut config = builder.with_cert_resolver(self);
+        // offer HTTP/2 first, clients that don't speak it fall back to HTTP/1.1
+        config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+        Ok(TlsAcceptor::from(Arc::new(config)))
+    }
+}
+
+impl ResolvesServerCert for CertResolver {
+    fn resolve(&self, client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        let certificates = self
+            .certificates
+            .read()
+            .unwrap_or_else(|err| err.into_inner());
+        let Some(host) = client_hello.server_name() else {
+            return Some(Arc::clone(&certificates.default));
+        };
+        let host = host.to_ascii_lowercase();
+        let wildcard = host
+            .split_once('.')
+            .map(|(_, parent)| format!("*.{parent}"));
+
+        certificates
+            .by_host
+            .get(&host)
+            .or_else(|| wildcard.and_then(|wildcard| certificates.by_host.get(&wildcard)))
+            .or(Some(&certificates.default))
+            .cloned()
+    }
+}
+
+/// Reloads the certificates every time the process receives SIGHUP. Runs until the process exits.
+pub async fn reload_on_sighup(resolver: Arc<CertResolver>) {
+    shutdown::on_hangup(|| match resolver.reload() {
+        Ok(()) => info!("Reloaded TLS certificates"),
+        Err(err) => error!("Error reloading TLS certificates, keeping the old ones => {err}"),
+    })
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio_rustls::{
+        rustls::{ClientConfig, RootCertStore, ServerName},
+        TlsConnector,
+    };
+
+    /// Writes a self signed certificate for `hosts` into `dir` and returns its paths along with the DER encoded certificate
+    fn self_signed(dir: &Path, name: &str, hosts: &[&str]) -> (CertPaths, Vec<u8>) {
+        let generated = rcgen::generate_simple_self_signed(
+            hosts
+                .iter()
+                .map(|host| host.to_string())
+                .collect::<Vec<_>>(),
+        )
+        .unwrap();
+        let paths = CertPaths {
+            cert: dir.join(format!("{name}.pem")),
+            key: dir.join(format!("{name}.key")),
+        };
+        std::fs::write(&paths.cert, generated.serialize_pem().unwrap()).unwrap();
+        std::fs::write(&paths.key, generated.serialize_private_key_pem()).unwrap();
+        (paths, generated.serialize_der().unwrap())
+    }
+
+    /// Connects to `acceptor` over an in memory pipe, trusting only `trusted`, and echoes one message through the connection
+    async fn handshake(acceptor: TlsAcceptor, host: &str, trusted: &[u8]) -> io::Result<()> {
+        let mut roots = RootCertStore::empty();
+        roots.add(&Certificate(trusted.to_vec())).unwrap();
+        let connector = TlsConnector::from(Arc::new(
+            ClientConfig::builder()
+                .with_safe_defaults()
+                .with_root_certificates(roots)
+                .with_no_client_auth(),
+        ));
+
+        let (client, server) = tokio::io::duplex(4096);
+        let server = tokio::spawn(async move {
+            let mut stream = acceptor.accept(server).await?;
+            let mut buf = [0; 5];
+            stream.read_exact(&mut buf).await?;
+            stream.write_all(&buf).await?;
+            stream.shutdown().await
+        });
+
+        let mut stream = connector
+            .connect(ServerName::try_from(host).unwrap(), client)
+            .await?;
+        stream.write_all(b"hello").await?;
+        let mut buf = [0; 5];
+        stream.read_exact(&mut buf).await?;
+        assert_eq!(&buf, b"hello");
+        server.await.unwrap()
+    }
+
+    /// Generates a client certificate for `CN=billing, O=Example`. It is signed when serialized, by a CA or by itself
+    fn client_cert() -> rcgen::Certificate {
+        let mut params = rcgen::CertificateParams::new(vec!["billing.internal".to_owned()]);
+        params
+            .subject_alt_names
+            .push(rcgen::SanType::URI("spiffe://example/billing".to_owned()));
+        params.distinguished_name = rcgen::DistinguishedName::new();
+        params
+            .distinguished_name
+            .push(rcgen::DnType::CommonName, "billing");
+        params
+            .distinguished_name
+            .push(rcgen::DnType::OrganizationName, "Example");
+        params.extended_key_usages = vec![rcgen::ExtendedKeyUsagePurpose::ClientAuth];
+        rcgen::Certificate::from_params(params).unwrap()
+    }
+
+    /// Connects to `acceptor` presenting `cert`, if any, and returns the identity the server pulled out of the verified client certificate
+    async fn client_handshake(
+        acceptor: TlsAcceptor,
+        trusted: &[u8],
+        cert: Option<(Vec<u8>, Vec<u8>)>,
+    ) -> io::Result<Option<ClientCertificate>> {
+        let mut roots = RootCertStore::empty();
+        roots.add(&Certificate(trusted.to_vec())).unwrap();
+        let builder = ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(roots);
+        let config = match cert {
+            Some((cert, key)) => builder
+                .with_client_auth_cert(vec![Certificate(cert)], PrivateKey(key))
+                .unwrap(),
+            None => builder.with_no_client_auth(),
+        };
+        let connector = TlsConnector::from(Arc::new(config));
+
+        let (client, server) = tokio::io::duplex(4096);
+        let server = tokio::spawn(async move {
+            let mut stream = acceptor.accept(server).await?;
+            let identity = stream
+                .get_ref()
+                .1
+                .peer_certificates()
+                .and_then(|chain| chain.first())
+                .map(client_identity);
+            stream.write_all(b"hello").await?;
+            stream.shutdown().await?;
+            io::Result::Ok(identity)
+        });
+
+        // with TLS 1.3 the client only learns its certificate was rejected once it reads
+        let mut stream = connector
+            .connect(ServerName::try_from("localhost").unwrap(), client)
+            .await?;
+        let mut buf = [0; 5];
+        let read = stream.read_exact(&mut buf).await;
+        let identity = server.await.unwrap()?;
+        read?;
+        Ok(identity)
+    }
+
+    #[tokio::test]
+    async fn verifies_client_certificates() {
+        let dir = std::env::temp_dir().join(format!("web_app_mtls_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let (server, server_der) = self_signed(&dir, "server", &["localhost"]);
+        let mut ca_params = rcgen::CertificateParams::new(Vec::new());
+        ca_params.is_ca = rcgen::IsCa::Ca(rcgen::BasicConstraints::Unconstrained);
+        let ca = rcgen::Certificate::from_params(ca_params).unwrap();
+        let ca_path = dir.join("ca.pem");
+        std::fs::write(&ca_path, ca.serialize_pem().unwrap()).unwrap();
+        let acceptor = Arc::new(CertResolver::load(server, Vec::new()).unwrap())
+            .acceptor(Some(&ca_path))
+            .unwrap();
+
+        let signed = client_cert();
+        let signed_der = signed.serialize_der_with_signer(&ca).unwrap();
+        let identity = client_handshake(
+            acceptor.clone(),
+            &server_der,
+            Some((signed_der.clone(), signed.serialize_private_key_der())),
+        )
+        .await
+        .unwrap()
+        .unwrap();
+        assert_eq!(identity.subject, "CN=billing, O=Example");
+        assert_eq!(
+            identity.sans,
+            ["DNS:billing.internal", "URI:spiffe://example/billing"]
+        );
+        let fingerprint: String = digest(&SHA256, &signed_der)
+            .as_ref()
+            .iter()
+            .map(|byte| format!("{byte:02x}"))
+            .collect();
+        assert_eq!(identity.fingerprint, fingerprint);
+
+        // a certificate no trusted CA signed fails the handshake
+        let untrusted = client_cert();
+        assert!(client_handshake(
+            acceptor.clone(),
+            &server_der,
+            Some((
+                untrusted.serialize_der().unwrap(),
+                untrusted.serialize_private_key_der()
+            )),
+        )
+        .await
+        .is_err());
+
+        // clients without a certificate are let through, without an identity
+        assert_eq!(
+            client_handshake(acceptor, &server_der, None).await.unwrap(),
+            None
+        );
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn selects_certificate_by_sni_and_reloads() {
+        let dir = std::env::temp_dir().join(format!("web_app_tls_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let (default, default_der) = self_signed(&dir, "default", &["localhost"]);
+        let (api, api_der) = self_signed(&dir, "api", &["api.example.test"]);
+
+        let resolver = Arc::new(
+            CertResolver::load(
+                default.clone(),
+                vec![SniCert {
+                    host: "*.example.test".to_owned(),
+                    paths: api.clone(),
+                }],
+            )
+            .unwrap(),
+        );
+        let acceptor = Arc::clone(&resolver).acceptor(None).unwrap();
+
+        handshake(acceptor.clone(), "localhost", &default_der)
+            .await
+            .unwrap();
+        handshake(acceptor.clone(), "api.example.test", &api_der)
+            .await
+            .unwrap();
+        // a client only trusting the default certificate must not be served the api one
+        assert!(
+            handshake(acceptor.clone(), "api.example.test", &default_der)
+                .await
+                .is_err()
+        );
+
+        // replace the default certificate on disk and reload
+        let (_, new_der) = self_signed(&dir, "default", &["localhost"]);
+        resolver.reload().unwrap();
+        handshake(acceptor, "localhost", &new_der).await.unwrap();
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}